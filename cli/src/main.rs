@@ -1,3 +1,382 @@
+// The `wormhole` command: file transfer that interoperates with the Python
+// client.
+//
+//     wormhole send FILE
+//     wormhole receive [CODE]
+//
+// The sender's "transit" hints and its offer go through the mailbox server,
+// and the receiver answers with its own hints and a "file_ack". Then the
+// file itself goes over Transit, as a series of records, and the receiver
+// finishes by sending back one more record with the SHA-256 of what it got.
+
+extern crate hex;
+extern crate magic_wormhole_io_blocking;
+extern crate magic_wormhole_transit;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+#[cfg(test)]
+extern crate magic_wormhole_server;
+#[cfg(test)]
+extern crate magic_wormhole_transit_relay;
+
+mod receive;
+mod send;
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use magic_wormhole_io_blocking::Wormhole;
+use magic_wormhole_transit::{transit_key_purpose, Role, Transit};
+use serde_json::Value;
+
+const APPID: &'static str = "lothar.com/wormhole/text-or-file-xfer";
+const MAILBOX_SERVER: &'static str = "ws://relay.magic-wormhole.io:4000/v1";
+const TRANSIT_RELAY: &'static str = "transit.magic-wormhole.io:4001";
+
+const USAGE: &'static str = "usage: wormhole [--server URL] \
+                             [--relay HOST:PORT] send FILE\n       \
+                             wormhole [--server URL] [--relay HOST:PORT] \
+                             receive [--accept-file] [CODE]";
+
+#[derive(Debug)]
+pub enum Error {
+    Wormhole(magic_wormhole_io_blocking::Error),
+    Transit(magic_wormhole_transit::Error),
+    Io(io::Error),
+    // the other side sent something we didn't understand
+    Protocol(String),
+    // one side or the other didn't want the transfer
+    Rejected(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Wormhole(ref e) => write!(f, "{}", e),
+            Error::Transit(ref e) => write!(f, "transit: {}", e),
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            Error::Rejected(ref e) => write!(f, "transfer rejected: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Wormhole(ref e) => e.description(),
+            Error::Transit(ref e) => e.description(),
+            Error::Io(ref e) => e.description(),
+            Error::Protocol(_) => "protocol error",
+            Error::Rejected(_) => "transfer rejected",
+        }
+    }
+}
+
+impl From<magic_wormhole_io_blocking::Error> for Error {
+    fn from(e: magic_wormhole_io_blocking::Error) -> Error {
+        Error::Wormhole(e)
+    }
+}
+
+impl From<magic_wormhole_transit::Error> for Error {
+    fn from(e: magic_wormhole_transit::Error) -> Error {
+        Error::Transit(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub struct Options {
+    mailbox_server: String,
+    transit_relay: (String, u16),
+    // how long to wait for the other side (i.e. for a person)
+    patience: Duration,
+    // how long to wait for a server, or for Transit to connect
+    timeout: Duration,
+}
+
+// The next message from the other side, which must be a JSON object. Either
+// side can give up by sending {"error": ".."} instead.
+fn receive_json(w: &Wormhole, options: &Options) -> Result<Value, Error> {
+    let message = w.receive(options.patience)?;
+    let value: Value = serde_json::from_slice(&message)
+        .map_err(|e| Error::Protocol(e.to_string()))?;
+    if !value.is_object() {
+        return Err(Error::Protocol(format!("unexpected message {}", value)));
+    }
+    match value.get("error") {
+        Some(&Value::String(ref e)) => Err(Error::Rejected(e.clone())),
+        Some(e) => Err(Error::Rejected(e.to_string())),
+        None => Ok(value),
+    }
+}
+
+fn send_json(w: &Wormhole, value: Value) {
+    w.send(value.to_string().into_bytes());
+}
+
+// keyed by the wormhole, so this waits for the key to be verified
+fn new_transit(
+    w: &Wormhole,
+    role: Role,
+    options: &Options,
+) -> Result<Transit, Error> {
+    let purpose = transit_key_purpose(APPID);
+    let key = w.derive_key(&purpose, 32, options.patience)?;
+    let mut transit = Transit::new(role, &key)?;
+    let (ref hostname, port) = options.transit_relay;
+    transit.add_relay(hostname, port);
+    Ok(transit)
+}
+
+// Closes the wormhole (unless it's what failed). Failing to close properly
+// after the transfer is done isn't worth reporting as a failure.
+fn finish<T>(
+    w: Wormhole,
+    result: Result<T, Error>,
+    options: &Options,
+) -> Result<T, Error> {
+    match result {
+        Err(Error::Wormhole(_)) => {}
+        _ => {
+            if let Err(e) = w.close(options.timeout) {
+                println!("unable to close the wormhole: {}", e);
+            }
+        }
+    }
+    result
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+fn parse_relay(relay: &str) -> Option<(String, u16)> {
+    let colon = relay.rfind(':')?;
+    let port = relay[colon + 1..].parse().ok()?;
+    Some((relay[..colon].to_string(), port))
+}
+
+fn ask(question: &str) -> bool {
+    print!("{} (y/N) ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    let stdin = io::stdin();
+    if stdin.lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim().to_lowercase();
+    answer == "y" || answer == "yes"
+}
+
+fn run_send(options: &Options, path: &Path) -> Result<(), Error> {
+    let w = Wormhole::connect(APPID, &options.mailbox_server)?;
+    w.allocate_code(2);
+    let code = w.get_code(options.timeout)?;
+    println!("Wormhole code is: {}", code);
+    println!("On the other computer, please run: wormhole receive");
+    let result = send::send_file(&w, options, path);
+    finish(w, result, options)
+}
+
+fn run_receive(
+    options: &Options,
+    code: Option<String>,
+    accept_file: bool,
+) -> Result<(), Error> {
+    let code = match code {
+        Some(code) => code,
+        None => {
+            print!("Enter receive wormhole code: ");
+            let _ = io::stdout().flush();
+            let mut code = String::new();
+            io::stdin().read_line(&mut code)?;
+            code.trim().to_string()
+        }
+    };
+    let w = Wormhole::connect(APPID, &options.mailbox_server)?;
+    w.set_code(&code);
+    let accept = |filename: &str, filesize: u64| {
+        let question =
+            format!("Receive file {} ({} bytes)?", filename, filesize);
+        accept_file || ask(&question)
+    };
+    let result = receive::receive_file(&w, options, accept, Path::new("."));
+    finish(w, result, options).map(|path| {
+        println!("Received file written to {}", path.display());
+    })
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut mailbox_server = MAILBOX_SERVER.to_string();
+    let mut transit_relay = TRANSIT_RELAY.to_string();
+    let mut accept_file = false;
+    let mut words = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => match args.next() {
+                Some(value) => mailbox_server = value,
+                None => usage(),
+            },
+            "--relay" => match args.next() {
+                Some(value) => transit_relay = value,
+                None => usage(),
+            },
+            "--accept-file" => accept_file = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => words.push(arg),
+        }
+    }
+    let options = Options {
+        mailbox_server: mailbox_server,
+        transit_relay: match parse_relay(&transit_relay) {
+            Some(relay) => relay,
+            None => usage(),
+        },
+        patience: Duration::from_secs(3600),
+        timeout: Duration::from_secs(60),
+    };
+    let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+    let result = match words.as_slice() {
+        ["send", file] => run_send(&options, Path::new(file)),
+        ["receive"] => run_receive(&options, None, accept_file),
+        ["receive", code] => {
+            run_receive(&options, Some(code.to_string()), accept_file)
+        }
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use magic_wormhole_io_blocking::Wormhole;
+    use magic_wormhole_server::ServerBuilder;
+    use magic_wormhole_transit_relay::RelayBuilder;
+    use super::*;
+
+    // a fresh, empty directory for each test
+    fn directory(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("wormhole-cli-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Sends `contents` as "data.bin" from one directory to another, through
+    // a mailbox server and a Transit relay on localhost.
+    fn transfer(
+        name: &str,
+        contents: &[u8],
+        accept: bool,
+    ) -> (Result<(), Error>, Result<PathBuf, Error>, PathBuf) {
+        let server = ServerBuilder::new("127.0.0.1:0").build().unwrap();
+        let url = server.url().unwrap();
+        let stopper = server.stopper();
+        let serving = thread::spawn(move || server.run().unwrap());
+        let relay = Arc::new(RelayBuilder::new("127.0.0.1:0").build().unwrap());
+        let port = relay.local_addr().unwrap().port();
+        let relaying = {
+            let relay = Arc::clone(&relay);
+            thread::spawn(move || relay.run())
+        };
+        let options = || Options {
+            mailbox_server: url.clone(),
+            transit_relay: ("127.0.0.1".to_string(), port),
+            patience: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+        };
+
+        let from = directory(&format!("{}-from", name));
+        let to = directory(&format!("{}-to", name));
+        let path = from.join("data.bin");
+        File::create(&path).unwrap().write_all(contents).unwrap();
+
+        let sender = Wormhole::connect(APPID, &url).unwrap();
+        sender.allocate_code(2);
+        let code = sender.get_code(Duration::from_secs(10)).unwrap();
+        let receiving = {
+            let (options, to) = (options(), to.clone());
+            thread::spawn(move || {
+                let w = Wormhole::connect(APPID, &options.mailbox_server)
+                    .unwrap();
+                w.set_code(&code);
+                let accept = |_: &str, _: u64| accept;
+                let result = receive::receive_file(&w, &options, accept, &to);
+                finish(w, result, &options)
+            })
+        };
+        let options = options();
+        let sent = send::send_file(&sender, &options, &path);
+        let sent = finish(sender, sent, &options);
+        let received = receiving.join().unwrap();
+
+        relay.stop().unwrap();
+        relaying.join().unwrap();
+        stopper.shutdown().unwrap();
+        serving.join().unwrap();
+        (sent, received, to)
+    }
+
+    #[test]
+    fn send_and_receive() {
+        // several records' worth
+        let contents: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+        let (sent, received, to) = transfer("accept", &contents, true);
+        sent.unwrap();
+        let path = received.unwrap();
+        assert_eq!(path, to.join("data.bin"));
+        let mut got = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut got).unwrap();
+        assert_eq!(got, contents);
+        // and the temporary file is gone
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn reject() {
+        let (sent, received, to) = transfer("reject", b"secret", false);
+        match sent {
+            Err(Error::Rejected(_)) => {}
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        match received {
+            Err(Error::Rejected(_)) => {}
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn relay_address() {
+        assert_eq!(
+            parse_relay("transit.magic-wormhole.io:4001"),
+            Some(("transit.magic-wormhole.io".to_string(), 4001))
+        );
+        assert_eq!(parse_relay("transit.magic-wormhole.io"), None);
+        assert_eq!(parse_relay("localhost:port"), None);
+    }
 }
@@ -0,0 +1,201 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use hex;
+use magic_wormhole_io_blocking::Wormhole;
+use magic_wormhole_transit::{Connection, Role};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use super::{new_transit, receive_json, send_json, Error, Options};
+
+// Asks `accept` (with the filename and size) whether we want the file.
+// Returns where it was written: `directory`, under the name the sender gave
+// (minus any directories).
+pub fn receive_file<F>(
+    w: &Wormhole,
+    options: &Options,
+    accept: F,
+    directory: &Path,
+) -> Result<PathBuf, Error>
+where
+    F: Fn(&str, u64) -> bool,
+{
+    let mut transit = new_transit(w, Role::Receiver, options)?;
+
+    // they send their hints, then their offer
+    let mut hinted = false;
+    let mut offer = None;
+    while !hinted || offer.is_none() {
+        let message = receive_json(w, options)?;
+        if message.get("transit").is_some() {
+            transit.add_their_hints(message.to_string().as_bytes())?;
+            hinted = true;
+        } else if let Some(o) = message.get("offer") {
+            offer = Some(o.clone());
+        } else {
+            println!("ignoring unexpected message {}", message);
+        }
+    }
+    let (filename, filesize) = match parse_offer(&offer.unwrap()) {
+        Ok(file) => file,
+        Err(e) => {
+            send_json(w, json!({"error": "unable to parse the offer"}));
+            return Err(e);
+        }
+    };
+    let path = directory.join(&filename);
+    if path.exists() {
+        send_json(w, json!({"error": "the file already exists"}));
+        let e = format!("refusing to overwrite {}", path.display());
+        return Err(Error::Rejected(e));
+    }
+    if !accept(&filename, filesize) {
+        send_json(w, json!({"error": "transfer rejected"}));
+        return Err(Error::Rejected("declined".to_string()));
+    }
+
+    // Nothing appears under the real name until we have all of it. If the
+    // partial file is already there (e.g. another transfer of the same
+    // file), it isn't ours to replace.
+    let partial = directory.join(format!("{}.part", filename));
+    let file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&partial)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            send_json(w, json!({"error": "unable to write the file"}));
+            return Err(e.into());
+        }
+    };
+
+    w.send(transit.our_hints());
+    send_json(w, json!({"answer": {"file_ack": "ok"}}));
+    let received = transit
+        .connect(options.timeout)
+        .map_err(Error::from)
+        .and_then(|mut connection| {
+            let sha256 = write_file(&mut connection, file, filesize)?;
+            Ok((connection, sha256))
+        });
+    let (mut connection, sha256) = match received {
+        Ok(received) => received,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    match rename_new(&partial, &path) {
+        Ok(()) => {}
+        // it turned up while we were receiving: we keep what we received,
+        // but don't tell them we have it
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let e = format!(
+                "refusing to overwrite {} (we received {})",
+                path.display(),
+                partial.display()
+            );
+            return Err(Error::Rejected(e));
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let ack = json!({"ack": "ok", "sha256": sha256});
+    connection.send_record(ack.to_string().as_bytes())?;
+    Ok(path)
+}
+
+// {"file": {"filename": NAME, "filesize": SIZE}}
+fn parse_offer(offer: &Value) -> Result<(String, u64), Error> {
+    let file = match offer.get("file") {
+        Some(file) => file,
+        None => {
+            let e = format!("we can only receive files, not {}", offer);
+            return Err(Error::Protocol(e));
+        }
+    };
+    let filename = file.get("filename").and_then(|name| name.as_str());
+    let filesize = file.get("filesize").and_then(|size| size.as_u64());
+    // the sender doesn't get to pick the directory
+    let filename = filename
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str());
+    match (filename, filesize) {
+        (Some(filename), Some(filesize)) => {
+            Ok((filename.to_string(), filesize))
+        }
+        _ => Err(Error::Protocol(format!("bad file offer {}", file))),
+    }
+}
+
+// returns the (hex) SHA-256 of what we wrote
+fn write_file(
+    connection: &mut Connection,
+    mut file: File,
+    filesize: u64,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::default();
+    let mut received = 0;
+    while received < filesize {
+        let record = connection.receive_record()?;
+        received += record.len() as u64;
+        if received > filesize {
+            return Err(Error::Protocol("they sent too much".to_string()));
+        }
+        hasher.input(&record);
+        file.write_all(&record)?;
+    }
+    file.sync_all()?;
+    Ok(hex::encode(hasher.result()))
+}
+
+// Like fs::rename, except that it fails (with AlreadyExists) rather than
+// replace anything at `to`, even something that turned up after we last
+// looked.
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    fs::hard_link(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process;
+    use super::{parse_offer, rename_new};
+
+    #[test]
+    fn rename_without_replacing() {
+        let dir = env::temp_dir()
+            .join(format!("wormhole-receive-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let (partial, path) = (dir.join("a.txt.part"), dir.join("a.txt"));
+        fs::write(&partial, b"new").unwrap();
+        fs::write(&path, b"old").unwrap();
+        let e = rename_new(&partial, &path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"old".to_vec());
+        assert_eq!(fs::read(&partial).unwrap(), b"new".to_vec());
+
+        fs::remove_file(&path).unwrap();
+        rename_new(&partial, &path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new".to_vec());
+        assert!(!partial.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offers() {
+        let offer = json!({"file": {"filename": "a.txt", "filesize": 5}});
+        assert_eq!(parse_offer(&offer).unwrap(), ("a.txt".to_string(), 5));
+        let offer =
+            json!({"file": {"filename": "../../.bashrc", "filesize": 5}});
+        assert_eq!(parse_offer(&offer).unwrap(), (".bashrc".to_string(), 5));
+        assert!(parse_offer(&json!({"message": "hi"})).is_err());
+        assert!(parse_offer(&json!({"file": {"filename": "a"}})).is_err());
+        assert!(parse_offer(&json!({"file": {"filename": "..",
+                                             "filesize": 5}})).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use hex;
+use magic_wormhole_io_blocking::Wormhole;
+use magic_wormhole_transit::Role;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use super::{new_transit, receive_json, send_json, Error, Options};
+
+// the same size as the Python client's records
+const RECORD_SIZE: usize = 65536;
+
+pub fn send_file(
+    w: &Wormhole,
+    options: &Options,
+    path: &Path,
+) -> Result<(), Error> {
+    let mut file = File::open(path)?;
+    let filesize = file.metadata()?.len();
+    let filename = match path.file_name().and_then(|name| name.to_str()) {
+        Some(filename) => filename.to_string(),
+        None => {
+            let e = format!("{} is not a file", path.display());
+            return Err(Error::Io(io::Error::new(io::ErrorKind::Other, e)));
+        }
+    };
+
+    let mut transit = new_transit(w, Role::Sender, options)?;
+    w.send(transit.our_hints());
+    send_json(
+        w,
+        json!({"offer": {"file": {"filename": filename,
+                                  "filesize": filesize}}}),
+    );
+
+    // they send their hints, then their answer
+    let mut answered = false;
+    let mut hinted = false;
+    while !(answered && hinted) {
+        let message = receive_json(w, options)?;
+        if message.get("transit").is_some() {
+            transit.add_their_hints(message.to_string().as_bytes())?;
+            hinted = true;
+        } else if let Some(answer) = message.get("answer") {
+            if answer.get("file_ack") != Some(&json!("ok")) {
+                let e = format!("unexpected answer {}", answer);
+                return Err(Error::Protocol(e));
+            }
+            answered = true;
+        } else {
+            println!("ignoring unexpected message {}", message);
+        }
+    }
+
+    println!("Sending {} ({} bytes)", filename, filesize);
+    let mut connection = transit.connect(options.timeout)?;
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0; RECORD_SIZE];
+    let mut sent = 0;
+    while sent < filesize {
+        // no more than we offered, even if the file has grown since
+        let want = (filesize - sent).min(RECORD_SIZE as u64) as usize;
+        let n = file.read(&mut buf[..want])?;
+        if n == 0 {
+            let e = format!("{} got shorter while we sent it", filename);
+            let e = io::Error::new(io::ErrorKind::UnexpectedEof, e);
+            return Err(Error::Io(e));
+        }
+        hasher.input(&buf[..n]);
+        connection.send_record(&buf[..n])?;
+        sent += n as u64;
+    }
+
+    // {"ack": "ok", "sha256": HEX}
+    let ack = connection.receive_record()?;
+    let ack: Value = serde_json::from_slice(&ack)
+        .map_err(|e| Error::Protocol(e.to_string()))?;
+    if ack.get("ack") != Some(&json!("ok")) {
+        return Err(Error::Protocol(format!("unexpected ack {}", ack)));
+    }
+    let sha256 = hex::encode(hasher.result());
+    if ack.get("sha256") != Some(&json!(sha256)) {
+        return Err(Error::Protocol("they got a different file".to_string()));
+    }
+    println!("File sent, and acknowledged");
+    Ok(())
+}
@@ -4,7 +4,8 @@ extern crate serde_json;
 extern crate url;
 extern crate ws;
 use magic_wormhole_core::{APIAction, APIEvent, Action, IOAction, IOEvent,
-                          TimerHandle, WSHandle, WormholeCore};
+                          TimerHandle, WSHandle, WormholeCore,
+                          WormholeCoreBuilder};
 use std::cell::RefCell;
 use std::rc::Rc;
 use url::Url;
@@ -30,7 +31,7 @@ fn main() {
     // make a single connection. Eventually, it will manage reconnects too,
     // and we must be prepared to make multiple connections when it asks.
 
-    let mut wc = WormholeCore::new(APPID, MAILBOX_SERVER);
+    let mut wc = WormholeCoreBuilder::new(APPID, MAILBOX_SERVER).build();
     let wsh;
     let ws_url;
     let mut actions = wc.start();
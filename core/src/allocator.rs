@@ -1,22 +1,229 @@
-use events::Events;
+use std::sync::Arc;
+use serde::{Serialize, Serializer};
+use events::{Events, Wordlist};
+use wordlist::{deserialize_wordlist, serialize_wordlist};
+use api::WormholeError;
 // we process these
 use events::AllocatorEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
+use events::RendezvousEvent::TxAllocate as RC_TxAllocate;
+use events::CodeEvent::Allocated as C_Allocated;
 
-pub struct Allocator {}
+// all -A states are not-connected, while -B states are yes-connected
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum State {
+    // S0: haven't been asked to allocate yet
+    S0A_idle,
+    S0B_idle_connected,
+    // S1: asked to allocate, waiting for the server to give us a nameplate
+    S1A_allocating(
+        u8, // length
+        #[serde(serialize_with = "serialize_wordlist",
+                deserialize_with = "deserialize_wordlist")]
+        Arc<Wordlist>,
+    ),
+    S1B_allocating_connected(
+        u8,
+        #[serde(serialize_with = "serialize_wordlist",
+                deserialize_with = "deserialize_wordlist")]
+        Arc<Wordlist>,
+    ),
+    // S2: got a nameplate, code has been built
+    S2_done,
+}
+
+// B states serialize as A, so we wake up disconnected
+fn serialize_state<S>(state: &State, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use self::State::*;
+    let disconnected = match *state {
+        S0B_idle_connected => S0A_idle,
+        S1B_allocating_connected(length, ref wordlist) => {
+            S1A_allocating(length, wordlist.clone())
+        }
+        ref s => return s.serialize(serializer),
+    };
+    disconnected.serialize(serializer)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Allocator {
+    #[serde(serialize_with = "serialize_state")]
+    state: State,
+}
 
 impl Allocator {
     pub fn new() -> Allocator {
-        Allocator {}
+        Allocator {
+            state: State::S0A_idle,
+        }
     }
 
     pub fn process(&mut self, event: AllocatorEvent) -> Events {
+        use self::State::*;
+        let (newstate, actions) = match self.state {
+            S0A_idle => self.do_S0A(event),
+            S0B_idle_connected => self.do_S0B(event),
+            S1A_allocating(length, ref wordlist) => {
+                self.do_S1A(length, &wordlist, event)
+            }
+            S1B_allocating_connected(length, ref wordlist) => {
+                self.do_S1B(length, &wordlist, event)
+            }
+            S2_done => self.do_S2(event),
+        };
+        match newstate {
+            Some(s) => {
+                self.state = s;
+            }
+            None => {}
+        }
+        actions
+    }
+
+    fn do_S0A(&self, event: AllocatorEvent) -> (Option<State>, Events) {
+        use events::AllocatorEvent::*;
+        match event {
+            Connected => (Some(State::S0B_idle_connected), events![]),
+            Lost => self.unexpected("Lost"),
+            Allocate(length, wordlist) => {
+                (Some(State::S1A_allocating(length, wordlist)), events![])
+            }
+            RxAllocated(_nameplate) => self.unexpected("RxAllocated"),
+        }
+    }
+
+    fn do_S0B(&self, event: AllocatorEvent) -> (Option<State>, Events) {
         use events::AllocatorEvent::*;
         match event {
-            Connected => events![],
-            Lost => events![],
-            Allocate(length, wordlist) => events![],
-            RxAllocated => events![],
+            Connected => self.unexpected("Connected"),
+            Lost => (Some(State::S0A_idle), events![]),
+            Allocate(length, wordlist) => (
+                Some(State::S1B_allocating_connected(length, wordlist)),
+                events![RC_TxAllocate],
+            ),
+            RxAllocated(_nameplate) => self.unexpected("RxAllocated"),
+        }
+    }
+
+    fn do_S1A(
+        &self,
+        length: u8,
+        wordlist: &Arc<Wordlist>,
+        event: AllocatorEvent,
+    ) -> (Option<State>, Events) {
+        use events::AllocatorEvent::*;
+        match event {
+            // we never heard back from the server before the connection was
+            // lost, so ask again on the new connection
+            Connected => (
+                Some(State::S1B_allocating_connected(
+                    length,
+                    wordlist.clone(),
+                )),
+                events![RC_TxAllocate],
+            ),
+            Lost => self.unexpected("Lost"),
+            Allocate(_length, _wordlist) => self.unexpected("Allocate"),
+            RxAllocated(_nameplate) => self.unexpected("RxAllocated"),
+        }
+    }
+
+    fn do_S1B(
+        &self,
+        length: u8,
+        wordlist: &Arc<Wordlist>,
+        event: AllocatorEvent,
+    ) -> (Option<State>, Events) {
+        use events::AllocatorEvent::*;
+        match event {
+            Connected => self.unexpected("Connected"),
+            Lost => (
+                Some(State::S1A_allocating(length, wordlist.clone())),
+                events![],
+            ),
+            Allocate(_length, _wordlist) => self.unexpected("Allocate"),
+            RxAllocated(nameplate) => {
+                let words = wordlist.choose_words(length);
+                let code = format!("{}-{}", nameplate, words);
+                (Some(State::S2_done), events![C_Allocated(nameplate, code)])
+            }
+        }
+    }
+
+    fn do_S2(&self, event: AllocatorEvent) -> (Option<State>, Events) {
+        use events::AllocatorEvent::*;
+        match event {
+            Connected => (None, events![]),
+            Lost => (None, events![]),
+            Allocate(_length, _wordlist) => self.unexpected("Allocate"),
+            RxAllocated(_nameplate) => self.unexpected("RxAllocated"),
+        }
+    }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("allocator: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use events::AllocatorEvent::*;
+    use events::CodeEvent;
+    use events::Event;
+    use events::RendezvousEvent;
+    use wordlist::AlternatingWordlist;
+
+    fn wordlist() -> Arc<Wordlist> {
+        Arc::new(AlternatingWordlist::new(vec![
+            vec!["purple".to_string()],
+            vec!["sausages".to_string()],
+        ]))
+    }
+
+    #[test]
+    fn allocate_while_connected() {
+        let mut a = Allocator::new();
+        assert_eq!(a.process(Connected), events![]);
+        assert_eq!(
+            a.process(Allocate(2, wordlist())),
+            events![RendezvousEvent::TxAllocate]
+        );
+        assert_eq!(
+            a.process(RxAllocated("4".to_string())),
+            events![CodeEvent::Allocated(
+                "4".to_string(),
+                "4-purple-sausages".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn allocate_before_connected() {
+        let mut a = Allocator::new();
+        assert_eq!(a.process(Allocate(2, wordlist())), events![]);
+        assert_eq!(a.process(Connected), events![RendezvousEvent::TxAllocate]);
+    }
+
+    #[test]
+    fn reallocate_after_reconnect() {
+        let mut a = Allocator::new();
+        a.process(Connected);
+        a.process(Allocate(3, wordlist()));
+        assert_eq!(a.process(Lost), events![]);
+        assert_eq!(a.process(Connected), events![RendezvousEvent::TxAllocate]);
+        let actions = a.process(RxAllocated("7".to_string()));
+        match actions.events[0] {
+            Event::Code(CodeEvent::Allocated(ref nameplate, ref code)) => {
+                assert_eq!(nameplate, "7");
+                assert_eq!(code, "7-purple-sausages-purple");
+            }
+            _ => panic!(),
         }
     }
 }
@@ -1,34 +1,179 @@
-use std::collections::HashMap;
+use std::error::Error;
+use serde_json::Value;
+use std::fmt;
+use std::time::{Duration, Instant};
+use wordlist::Wordlist;
+
+// Where the core gets the current time (to measure round trips) and random
+// numbers between 0 and 1 (to spread out reconnects). Both are supplied by
+// the IO layer through WormholeCoreBuilder, e.g. so tests can fix them.
+pub type Clock = Box<Fn() -> Instant + Send>;
+pub type Random = Box<FnMut() -> f32 + Send>;
 
 pub enum APIEvent {
     // from application to IO glue to WormholeCore
-    AllocateCode,
-    InputCode,
+    // num_words (None means WormholeCoreBuilder::code_length), wordlist
+    // (None means the default PGP wordlist)
+    AllocateCode(Option<u8>, Option<Box<Wordlist>>),
+    // the wordlist to complete words from (None means the default one)
+    InputCode(Option<Box<Wordlist>>),
+    InputHelperRefreshNameplates,
+    InputHelperChooseNameplate(String),
+    InputHelperChooseWords(String),
     SetCode(String),
     Close,
     Send(Vec<u8>),
 }
 
+// returned by the (synchronous) input-helper completion methods
 #[derive(Debug, PartialEq, Copy, Clone)]
+pub enum InputHelperError {
+    Inactive, // APIEvent::InputCode was not used
+    MustChooseNameplateFirst,
+    AlreadyChoseNameplate,
+    AlreadyChoseWords,
+}
+
+// Anything that goes wrong with the protocol is delivered to the application
+// as APIAction::GotError, after which the session is closed with
+// Mood::Error. The strings are for humans, not for matching on.
+#[derive(Debug, PartialEq, Clone)]
+pub enum WormholeError {
+    // AllocateCode, InputCode or SetCode was used after a code had already
+    // been chosen
+    AlreadyStartedCode,
+    // AllocateCode was asked for a code without any words
+    CodeTooShort,
+    // one of our state machines got an event it doesn't expect in its
+    // current state
+    BadTransition(String),
+    // the server refused to talk to us at all (in its welcome message)
+    WelcomeError(String),
+    // the server rejected something we did
+    ServerError(String),
+    // we gave up on reaching the server after this many attempts in a row.
+    // Since there's nobody left to tell, the session is closed immediately.
+    ConnectionFailed(u32),
+    // the server (or our peer) sent us something we couldn't parse
+    MalformedMessage(String),
+    // WormholeCore::derive_key was called before APIAction::GotVerifier
+    KeyNotVerified,
+    // WormholeCore::restore couldn't make sense of the saved state
+    MalformedSavedState(String),
+    // WormholeCore::serialize was called between sending our PAKE message
+    // and receiving our peer's, when the core can't be saved
+    KeyExchangeInProgress,
+    // a message from our peer failed to decrypt. Either one side typed the
+    // code wrong, or someone (e.g. the server) has tampered with the
+    // messages. The session is closed with Mood::Scary.
+    WrongCode,
+}
+
+impl fmt::Display for WormholeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WormholeError::AlreadyStartedCode => {
+                write!(f, "a code has already been started")
+            }
+            WormholeError::CodeTooShort => {
+                write!(f, "a code needs at least one word")
+            }
+            WormholeError::BadTransition(ref s) => {
+                write!(f, "unexpected event: {}", s)
+            }
+            WormholeError::WelcomeError(ref s) => {
+                write!(f, "server refused us: {}", s)
+            }
+            WormholeError::ServerError(ref s) => {
+                write!(f, "server error: {}", s)
+            }
+            WormholeError::ConnectionFailed(attempts) => write!(
+                f,
+                "unable to reach the server after {} attempts",
+                attempts
+            ),
+            WormholeError::MalformedMessage(ref s) => {
+                write!(f, "malformed message: {}", s)
+            }
+            WormholeError::KeyNotVerified => {
+                write!(f, "the key has not been verified yet")
+            }
+            WormholeError::MalformedSavedState(ref s) => {
+                write!(f, "malformed saved state: {}", s)
+            }
+            WormholeError::KeyExchangeInProgress => {
+                write!(f, "can't save during the key exchange")
+            }
+            WormholeError::WrongCode => write!(
+                f,
+                "the code was wrong, or the messages were tampered with"
+            ),
+        }
+    }
+}
+
+impl Error for WormholeError {
+    fn description(&self) -> &str {
+        match *self {
+            WormholeError::AlreadyStartedCode => "code already started",
+            WormholeError::CodeTooShort => "code too short",
+            WormholeError::BadTransition(_) => "unexpected event",
+            WormholeError::WelcomeError(_) => "server refused us",
+            WormholeError::ServerError(_) => "server error",
+            WormholeError::ConnectionFailed(_) => "unable to reach the server",
+            WormholeError::MalformedMessage(_) => "malformed message",
+            WormholeError::KeyNotVerified => "key not verified",
+            WormholeError::MalformedSavedState(_) => "malformed saved state",
+            WormholeError::KeyExchangeInProgress => "key exchange in progress",
+            WormholeError::WrongCode => "wrong code",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Mood {
     Happy,
     Lonely,
     Error,
+    Scary, // wrong code (or tampering)
+}
+
+impl Mood {
+    // the "mood" field of the "close" message we send to the server
+    pub fn to_protocol_string(&self) -> String {
+        match *self {
+            Mood::Happy => "happy",
+            Mood::Lonely => "lonely",
+            Mood::Error => "errory",
+            Mood::Scary => "scary",
+        }.to_string()
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum APIAction {
     // from WormholeCore out through IO glue to application
-    GotWelcome(HashMap<String, String>), // actually anything JSON-able: Value
+    // the server's "welcome" dict, e.g. {"motd": "down for maintenance"},
+    // which may also include "current_cli_version". This is delivered again
+    // after each reconnect.
+    GotWelcome(Value),
     GotCode(String), // must be easy to canonically encode into UTF-8 bytes
     GotUnverifiedKey(Vec<u8>),
     GotVerifier(Vec<u8>),
-    GotVersions(HashMap<String, String>), // actually anything JSON-able
+    // the peer's app_versions (see WormholeCoreBuilder::app_versions)
+    GotVersions(Value),
     GotMessage(Vec<u8>),
+    // diagnostics: how long the relay server took to answer a request of
+    // the given type (e.g. "ping")
+    GotRoundTrip(String, Duration),
+    // diagnostics: our peer sent a message with a phase we don't know (e.g.
+    // from a newer client), which we've ignored. Worth logging.
+    GotUnknownPhase(String),
+    GotError(WormholeError), // followed by GotClosed(Mood::Error)
     GotClosed(Mood),
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TimerHandle {
     id: u32,
 }
@@ -38,7 +183,7 @@ impl TimerHandle {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct WSHandle {
     id: u32,
 }
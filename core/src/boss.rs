@@ -1,5 +1,11 @@
-use events::{Event, Events, Wordlist};
-use api::Mood;
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde_json;
+use serde_json::Value;
+use events::{Event, Events};
+use key;
+use wordlist::{default_wordlist, Wordlist};
+use api::{Mood, WormholeError};
 // we process these
 use events::BossEvent;
 use api::APIEvent;
@@ -7,11 +13,15 @@ use api::APIEvent;
 use api::APIAction;
 use events::CodeEvent::{AllocateCode as C_AllocateCode,
                         InputCode as C_InputCode, SetCode as C_SetCode};
-use events::RendezvousEvent::Stop as RC_Stop;
+use events::InputEvent::{ChooseNameplate as I_ChooseNameplate,
+                         ChooseWords as I_ChooseWords,
+                         RefreshNameplates as I_RefreshNameplates};
+use events::BossEvent::Error as B_Error;
 use events::SendEvent::Send as S_Send;
 use events::TerminatorEvent::Close as T_Close;
+use events::TerminatorEvent::Stopped as T_Stopped;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     Empty(u32),
     Coding(u32),
@@ -21,26 +31,63 @@ enum State {
     Closed,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Boss {
     state: State,
     mood: Mood,
+    // numbered messages can arrive out of order (e.g. after a reconnect),
+    // so we hold on to any that arrive early
+    next_rx_phase: u32,
+    rx_phases: HashMap<u32, Vec<u8>>,
+    // the shared key, once our peer has proven they know it
+    verified_key: Option<Vec<u8>>,
+    unverified_key: Option<Vec<u8>>,
+    // how many words AllocateCode uses when it isn't told
+    code_length: u8,
 }
 
 impl Boss {
-    pub fn new() -> Boss {
+    pub fn new(code_length: u8) -> Boss {
         Boss {
             state: State::Empty(0),
             mood: Mood::Lonely,
+            next_rx_phase: 0,
+            rx_phases: HashMap::new(),
+            verified_key: None,
+            unverified_key: None,
+            code_length: code_length,
+        }
+    }
+
+    // called synchronously by WormholeCore::derive_key
+    pub fn derive_key(
+        &self,
+        purpose: &str,
+        length: usize,
+    ) -> Result<Vec<u8>, WormholeError> {
+        match self.verified_key {
+            Some(ref k) => {
+                Ok(key::Key::derive_key(k, purpose.as_bytes(), length))
+            }
+            None => Err(WormholeError::KeyNotVerified),
         }
     }
 
     pub fn process_api(&mut self, event: APIEvent) -> Events {
         use api::APIEvent::*;
         match event {
-            AllocateCode => self.allocate_code(), // TODO: len, wordlist
-            InputCode => self.input_code(),       // TODO: return Helper
+            AllocateCode(num_words, wordlist) => {
+                self.allocate_code(num_words, wordlist)
+            }
+            InputCode(wordlist) => self.input_code(wordlist),
+            // the input helper talks to the Input machine directly
+            InputHelperRefreshNameplates => events![I_RefreshNameplates],
+            InputHelperChooseNameplate(nameplate) => {
+                events![I_ChooseNameplate(nameplate)]
+            }
+            InputHelperChooseWords(words) => events![I_ChooseWords(words)],
             SetCode(code) => self.set_code(&code),
-            Close => events![RC_Stop], // eventually signals GotClosed
+            Close => self.close(), // eventually signals GotClosed
             Send(plaintext) => self.send(plaintext),
         }
     }
@@ -49,24 +96,57 @@ impl Boss {
         use events::BossEvent::*;
         match event {
             GotCode(code) => self.got_code(&code),
-            GotKey(key) => events![APIAction::GotUnverifiedKey(key)],
+            GotKey(key) => {
+                self.unverified_key = Some(key.clone());
+                events![APIAction::GotUnverifiedKey(key)]
+            }
             Happy => self.happy(),
-            GotVerifier(verifier) => events![APIAction::GotVerifier(verifier)],
+            GotVerifier(verifier) => {
+                // Receive only sends this after our peer's first message
+                // decrypted correctly, so the key is now verified
+                self.verified_key = self.unverified_key.take();
+                events![APIAction::GotVerifier(verifier)]
+            }
             GotMessage(phase, plaintext) => self.got_message(&phase, plaintext),
             Closed => self.closed(),
-            Error | RxError | RxWelcome | Scared => events![],
+            RxWelcome(welcome) => self.rx_welcome(welcome),
+            RxError(err) => {
+                self.error(WormholeError::ServerError(err), Mood::Error)
+            }
+            Error(WormholeError::ConnectionFailed(attempts)) => {
+                self.connection_failed(attempts)
+            }
+            Error(err) => self.error(err, Mood::Error),
+            Scared => self.error(WormholeError::WrongCode, Mood::Scary),
         }
     }
 
-    fn allocate_code(&mut self) -> Events {
+    fn rx_welcome(&mut self, welcome: Value) -> Events {
+        // the server can refuse us with an "error" key (e.g. because our
+        // client is too old), in which case the rest isn't interesting
+        if let Some(err) = welcome.get("error") {
+            let err = err.as_str().map_or(err.to_string(), |s| s.to_string());
+            return events![B_Error(WormholeError::WelcomeError(err))];
+        }
+        events![APIAction::GotWelcome(welcome)]
+    }
+
+    fn allocate_code(
+        &mut self,
+        num_words: Option<u8>,
+        wordlist: Option<Box<Wordlist>>,
+    ) -> Events {
         use self::State::*;
+        let num_words = num_words.unwrap_or(self.code_length);
         let (actions, newstate) = match self.state {
+            Empty(_) if num_words == 0 => {
+                return events![B_Error(WormholeError::CodeTooShort)]
+            }
             Empty(i) => {
-                let length = 2; // TODO: configurable by AllocateCode
-                let wordlist = Wordlist {}; // TODO: populate words
-                (events![C_AllocateCode(length, wordlist)], Coding(i))
+                let wordlist = chosen_wordlist(wordlist);
+                (events![C_AllocateCode(num_words, wordlist)], Coding(i))
             }
-            _ => panic!(), // TODO: signal AlreadyStartedCodeError
+            _ => return events![B_Error(WormholeError::AlreadyStartedCode)],
         };
         self.state = newstate;
         actions
@@ -81,19 +161,22 @@ impl Boss {
             // moment, and by not special-casing set_code we get to use the
             // same flow for allocate_code and input_code
             Empty(i) => (events![C_SetCode(code.to_string())], Coding(i)),
-            _ => panic!(), // TODO: signal AlreadyStartedCodeError
+            _ => return events![B_Error(WormholeError::AlreadyStartedCode)],
         };
         self.state = newstate;
         actions
     }
 
-    fn input_code(&mut self) -> Events {
-        // TODO: validate code, maybe signal KeyFormatError
-        // TODO: return Helper somehow
+    fn input_code(&mut self, wordlist: Option<Box<Wordlist>>) -> Events {
+        // the application drives the input helper with the InputHelper*
+        // APIEvents, and asks WormholeCore for completions
         use self::State::*;
         let (actions, newstate) = match self.state {
-            Empty(i) => (events![C_InputCode], Coding(i)),
-            _ => panic!(), // TODO: signal AlreadyStartedCodeError
+            Empty(i) => {
+                let wordlist = chosen_wordlist(wordlist);
+                (events![C_InputCode(wordlist)], Coding(i))
+            }
+            _ => return events![B_Error(WormholeError::AlreadyStartedCode)],
         };
         self.state = newstate;
         actions
@@ -105,7 +188,7 @@ impl Boss {
             Coding(i) => {
                 (events![APIAction::GotCode(code.to_string())], Lonely(i))
             }
-            _ => panic!(), // TODO: signal AlreadyStartedCodeError
+            _ => return self.unexpected("GotCode"),
         };
         self.state = newstate;
         actions
@@ -116,7 +199,7 @@ impl Boss {
         let (actions, newstate) = match self.state {
             Lonely(i) => (events![], Happy(i)),
             Closing => (events![], Closing),
-            _ => panic!(),
+            _ => return self.unexpected("Happy"),
         };
         self.state = newstate;
         actions
@@ -133,14 +216,14 @@ impl Boss {
             Lonely(i) => (events![], Lonely(i)),
             Happy(i) => {
                 if phase == "version" {
-                    // TODO deliver the "app_versions" key to API
-                    (events![], Happy(i))
-                } else if phase == "\\d+" {
-                    // TODO: match on regexp
-                    (events![APIAction::GotMessage(plaintext)], Happy(i))
+                    (self.got_versions(&plaintext), Happy(i))
+                } else if let Some(num) = parse_phase(phase) {
+                    (self.got_numbered_message(num, plaintext), Happy(i))
                 } else {
-                    // TODO: log and ignore, for future expansion
-                    (events![], Happy(i))
+                    // ignore unknown phases, for future expansion, but let
+                    // the application log them
+                    let phase = phase.to_string();
+                    (events![APIAction::GotUnknownPhase(phase)], Happy(i))
                 }
             }
         };
@@ -148,6 +231,39 @@ impl Boss {
         actions
     }
 
+    fn got_versions(&self, plaintext: &[u8]) -> Events {
+        // older peers might not send app_versions at all, which means the
+        // same thing as sending an empty dict
+        match serde_json::from_slice::<Value>(plaintext) {
+            Ok(ref versions) if versions.is_object() => {
+                let app_versions = versions
+                    .get("app_versions")
+                    .cloned()
+                    .unwrap_or(json!({}));
+                events![APIAction::GotVersions(app_versions)]
+            }
+            _ => {
+                let e = String::from_utf8_lossy(plaintext).to_string();
+                events![B_Error(WormholeError::MalformedMessage(e))]
+            }
+        }
+    }
+
+    fn got_numbered_message(&mut self, num: u32, plaintext: Vec<u8>) -> Events {
+        // anything below next_rx_phase has already been delivered, so this
+        // must be a duplicate
+        if num >= self.next_rx_phase {
+            self.rx_phases.insert(num, plaintext);
+        }
+        let mut actions = events![];
+        while let Some(plaintext) = self.rx_phases.remove(&self.next_rx_phase)
+        {
+            actions.push(APIAction::GotMessage(plaintext));
+            self.next_rx_phase += 1;
+        }
+        actions
+    }
+
     fn send(&mut self, plaintext: Vec<u8>) -> Events {
         use self::State::*;
         let (actions, newstate) = match self.state {
@@ -200,28 +316,261 @@ impl Boss {
     fn closed(&mut self) -> Events {
         use self::State::*;
         let (actions, newstate) = match self.state {
-            Closing => (events![APIAction::GotClosed(self.mood)], Closing),
-            _ => panic!(),
+            Closing => (events![APIAction::GotClosed(self.mood)], Closed),
+            _ => return self.unexpected("Closed"),
+        };
+        self.state = newstate;
+        actions
+    }
+
+    fn error(&mut self, err: WormholeError, mood: Mood) -> Events {
+        use self::State::*;
+        let (actions, newstate) = match self.state {
+            Empty(_) | Coding(_) | Lonely(_) | Happy(_) => {
+                self.mood = mood;
+                (events![APIAction::GotError(err), T_Close(mood)], Closing)
+            }
+            // we're already on our way out, so just let the application know
+            Closing => (events![APIAction::GotError(err)], Closing),
+            Closed => (events![APIAction::GotError(err)], Closed),
         };
         self.state = newstate;
         actions
     }
+
+    fn connection_failed(&mut self, attempts: u32) -> Events {
+        // We close like for any other error, but the Rendezvous has given
+        // up, so the server will never acknowledge our release and close.
+        // Telling the Terminator that the connection has stopped (after it
+        // has heard about the close) lets it finish without them.
+        let closed = self.state == State::Closed;
+        let mut actions =
+            self.error(WormholeError::ConnectionFailed(attempts), Mood::Error);
+        if !closed {
+            actions.push(T_Stopped);
+        }
+        actions
+    }
+
+    fn unexpected(&self, event: &str) -> Events {
+        let e = format!("boss: {} in state {:?}", event, self.state);
+        events![B_Error(WormholeError::BadTransition(e))]
+    }
+}
+
+fn chosen_wordlist(wordlist: Option<Box<Wordlist>>) -> Arc<Wordlist> {
+    match wordlist {
+        Some(w) => Arc::from(w),
+        None => Arc::new(default_wordlist()),
+    }
+}
+
+// application messages use phases "0", "1", "2", ..
+fn parse_phase(phase: &str) -> Option<u32> {
+    if !phase.is_empty() && phase.chars().all(|c| c.is_digit(10)) {
+        phase.parse().ok()
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use api::APIEvent;
-    use events::RendezvousEvent;
+    use events::{CodeEvent, TerminatorEvent};
 
     #[test]
     fn create() {
-        let _b = Boss::new();
+        let _b = Boss::new(2);
     }
 
+    #[test]
     fn process_api() {
-        let mut b = Boss::new();
+        let mut b = Boss::new(2);
         let actions = b.process_api(APIEvent::Close);
-        assert_eq!(actions, events![RendezvousEvent::Stop]);
+        assert_eq!(actions, events![TerminatorEvent::Close(Mood::Lonely)]);
+        let actions = b.process(BossEvent::Closed);
+        assert_eq!(actions, events![APIAction::GotClosed(Mood::Lonely)]);
+    }
+
+    #[test]
+    fn welcome() {
+        let mut b = Boss::new(2);
+        let welcome = json!({"motd": "down for maintenance"});
+        assert_eq!(
+            b.process(BossEvent::RxWelcome(welcome.clone())),
+            events![APIAction::GotWelcome(welcome)]
+        );
+        let err = WormholeError::WelcomeError("go away".to_string());
+        assert_eq!(
+            b.process(BossEvent::RxWelcome(json!({"error": "go away"}))),
+            events![BossEvent::Error(err.clone())]
+        );
+        assert_eq!(
+            b.process(BossEvent::Error(err.clone())),
+            events![
+                APIAction::GotError(err),
+                TerminatorEvent::Close(Mood::Error)
+            ]
+        );
+    }
+
+    #[test]
+    fn versions() {
+        let mut b = Boss::new(2);
+        b.process_api(APIEvent::SetCode("4-purple-sausages".to_string()));
+        b.process(BossEvent::GotCode("4-purple-sausages".to_string()));
+        b.process(BossEvent::Happy);
+        let versions = br#"{"app_versions": {"transfer": "v2"}}"#.to_vec();
+        assert_eq!(
+            b.process(BossEvent::GotMessage("version".to_string(), versions)),
+            events![APIAction::GotVersions(json!({"transfer": "v2"}))]
+        );
+        let versions = b"{}".to_vec();
+        assert_eq!(
+            b.process(BossEvent::GotMessage("version".to_string(), versions)),
+            events![APIAction::GotVersions(json!({}))]
+        );
+    }
+
+    #[test]
+    fn messages_in_order() {
+        let mut b = Boss::new(2);
+        b.process_api(APIEvent::SetCode("4-purple-sausages".to_string()));
+        b.process(BossEvent::GotCode("4-purple-sausages".to_string()));
+        b.process(BossEvent::Happy);
+        let msg = |phase: &str, body: &[u8]| {
+            BossEvent::GotMessage(phase.to_string(), body.to_vec())
+        };
+        assert_eq!(b.process(msg("1", b"second")), events![]);
+        assert_eq!(
+            b.process(msg("unknown", b"ignored")),
+            events![APIAction::GotUnknownPhase("unknown".to_string())]
+        );
+        assert_eq!(
+            b.process(msg("+3", b"ignored")),
+            events![APIAction::GotUnknownPhase("+3".to_string())]
+        );
+        assert_eq!(
+            b.process(msg("0", b"first")),
+            events![
+                APIAction::GotMessage(b"first".to_vec()),
+                APIAction::GotMessage(b"second".to_vec())
+            ]
+        );
+        assert_eq!(b.process(msg("0", b"duplicate")), events![]);
+        assert_eq!(
+            b.process(msg("2", b"third")),
+            events![APIAction::GotMessage(b"third".to_vec())]
+        );
+    }
+
+    #[test]
+    fn derive_key() {
+        let mut b = Boss::new(2);
+        let purpose = "appid/transit-key";
+        assert_eq!(
+            b.derive_key(purpose, 32),
+            Err(WormholeError::KeyNotVerified)
+        );
+        b.process(BossEvent::GotKey(b"key".to_vec()));
+        assert_eq!(
+            b.derive_key(purpose, 32),
+            Err(WormholeError::KeyNotVerified)
+        );
+        b.process(BossEvent::GotVerifier(b"verifier".to_vec()));
+        let k = b.derive_key(purpose, 32).unwrap();
+        assert_eq!(k.len(), 32);
+        assert_eq!(k, key::Key::derive_key(b"key", purpose.as_bytes(), 32));
+        assert_ne!(k, b.derive_key("appid/other", 32).unwrap());
+    }
+
+    #[test]
+    fn scared() {
+        let mut b = Boss::new(2);
+        b.process_api(APIEvent::SetCode("4-purple-sausages".to_string()));
+        b.process(BossEvent::GotCode("4-purple-sausages".to_string()));
+        assert_eq!(
+            b.process(BossEvent::Scared),
+            events![
+                APIAction::GotError(WormholeError::WrongCode),
+                TerminatorEvent::Close(Mood::Scary)
+            ]
+        );
+        let actions = b.process(BossEvent::Closed);
+        assert_eq!(actions, events![APIAction::GotClosed(Mood::Scary)]);
+    }
+
+    #[test]
+    fn connection_failed() {
+        let mut b = Boss::new(2);
+        let err = WormholeError::ConnectionFailed(3);
+        assert_eq!(
+            b.process(BossEvent::Error(err.clone())),
+            events![
+                APIAction::GotError(err),
+                TerminatorEvent::Close(Mood::Error),
+                TerminatorEvent::Stopped
+            ]
+        );
+        let actions = b.process(BossEvent::Closed);
+        assert_eq!(actions, events![APIAction::GotClosed(Mood::Error)]);
+    }
+
+    #[test]
+    fn server_error() {
+        let mut b = Boss::new(2);
+        let err = WormholeError::ServerError("crowded".to_string());
+        assert_eq!(
+            b.process(BossEvent::RxError("crowded".to_string())),
+            events![
+                APIAction::GotError(err),
+                TerminatorEvent::Close(Mood::Error)
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_default_length() {
+        let mut b = Boss::new(3);
+        let actions = b.process_api(APIEvent::AllocateCode(None, None));
+        // wordlists only compare equal to themselves, so just check the length
+        assert_eq!(actions.events.len(), 1);
+        match actions.events[0] {
+            Event::Code(CodeEvent::AllocateCode(num_words, _)) => {
+                assert_eq!(num_words, 3)
+            }
+            ref e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn allocate_no_words() {
+        let mut b = Boss::new(2);
+        assert_eq!(
+            b.process_api(APIEvent::AllocateCode(Some(0), None)),
+            events![BossEvent::Error(WormholeError::CodeTooShort)]
+        );
+    }
+
+    #[test]
+    fn already_started_code() {
+        let mut b = Boss::new(2);
+        b.process_api(APIEvent::SetCode("4-purple-sausages".to_string()));
+        let err = WormholeError::AlreadyStartedCode;
+        assert_eq!(
+            b.process_api(APIEvent::InputCode(None)),
+            events![BossEvent::Error(err.clone())]
+        );
+        assert_eq!(
+            b.process(BossEvent::Error(err.clone())),
+            events![
+                APIAction::GotError(err),
+                TerminatorEvent::Close(Mood::Error)
+            ]
+        );
+        let actions = b.process(BossEvent::Closed);
+        assert_eq!(actions, events![APIAction::GotClosed(Mood::Error)]);
     }
 }
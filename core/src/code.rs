@@ -1,14 +1,18 @@
-use events::Events;
+use std::sync::Arc;
+use events::{Events, Wordlist};
+use wordlist::{default_wordlist, deserialize_wordlist, serialize_wordlist};
+use api::WormholeError;
 // we process these
 use events::CodeEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
 use events::NameplateEvent::SetNameplate as N_SetNameplate;
 use events::BossEvent::GotCode as B_GotCode;
 use events::KeyEvent::GotCode as K_GotCode;
 use events::AllocatorEvent::Allocate as A_Allocate;
 use events::InputEvent::Start as I_Start;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     Idle,
     InputtingNameplate,
@@ -17,13 +21,21 @@ enum State {
     Known,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Code {
     state: State,
+    // what the words after the nameplate are chosen (or completed) from
+    #[serde(serialize_with = "serialize_wordlist",
+            deserialize_with = "deserialize_wordlist")]
+    wordlist: Arc<Wordlist>,
 }
 
 impl Code {
     pub fn new() -> Code {
-        Code { state: State::Idle }
+        Code {
+            state: State::Idle,
+            wordlist: Arc::new(default_wordlist()),
+        }
     }
 
     pub fn process(&mut self, event: CodeEvent) -> Events {
@@ -47,11 +59,14 @@ impl Code {
     fn in_idle(&mut self, event: CodeEvent) -> (Option<State>, Events) {
         use events::CodeEvent::*;
         match event {
-            AllocateCode(length, wordlist) => (
-                Some(State::Allocating),
-                events![A_Allocate(length, wordlist)],
-            ),
-            InputCode => (Some(State::InputtingNameplate), events![I_Start]), // TODO: return Input object
+            AllocateCode(length, wordlist) => {
+                self.wordlist = Arc::clone(&wordlist);
+                (Some(State::Allocating), events![A_Allocate(length, wordlist)])
+            }
+            InputCode(wordlist) => {
+                self.wordlist = wordlist;
+                (Some(State::InputtingNameplate), events![I_Start])
+            }
             SetCode(code) => {
                 // TODO: try!(validate_code(code))
                 let nc: Vec<&str> = code.splitn(2, "-").collect();
@@ -59,15 +74,18 @@ impl Code {
                 (
                     Some(State::Known),
                     events![
-                        N_SetNameplate(nameplate.to_string()),
+                        N_SetNameplate(
+                            nameplate.to_string(),
+                            Arc::clone(&self.wordlist),
+                        ),
                         B_GotCode(code.to_string()),
                         K_GotCode(code.to_string())
                     ],
                 )
             }
-            Allocated(nameplate, code) => panic!(),
-            GotNameplate(nameplate) => panic!(),
-            FinishedInput(_code) => panic!(),
+            Allocated(_, _) => self.unexpected("Allocated"),
+            GotNameplate(_) => self.unexpected("GotNameplate"),
+            FinishedInput(_code) => self.unexpected("FinishedInput"),
         }
     }
 
@@ -77,15 +95,15 @@ impl Code {
     ) -> (Option<State>, Events) {
         use events::CodeEvent::*;
         match event {
-            AllocateCode(_length, _wordlist) => panic!(),
-            InputCode => panic!(),
-            SetCode(code) => panic!(),
-            Allocated(nameplate, code) => panic!(),
+            AllocateCode(_length, _wordlist) => self.unexpected("AllocateCode"),
+            InputCode(_) => self.unexpected("InputCode"),
+            SetCode(_) => self.unexpected("SetCode"),
+            Allocated(_, _) => self.unexpected("Allocated"),
             GotNameplate(nameplate) => (
                 Some(State::InputtingWords),
-                events![N_SetNameplate(nameplate)],
+                events![N_SetNameplate(nameplate, Arc::clone(&self.wordlist))],
             ),
-            FinishedInput(_code) => panic!(),
+            FinishedInput(_code) => self.unexpected("FinishedInput"),
         }
     }
 
@@ -95,11 +113,11 @@ impl Code {
     ) -> (Option<State>, Events) {
         use events::CodeEvent::*;
         match event {
-            AllocateCode(_length, _wordlist) => panic!(),
-            InputCode => panic!(),
-            SetCode(code) => panic!(),
-            Allocated(nameplate, code) => panic!(),
-            GotNameplate(nameplate) => panic!(),
+            AllocateCode(_length, _wordlist) => self.unexpected("AllocateCode"),
+            InputCode(_) => self.unexpected("InputCode"),
+            SetCode(_) => self.unexpected("SetCode"),
+            Allocated(_, _) => self.unexpected("Allocated"),
+            GotNameplate(_) => self.unexpected("GotNameplate"),
             FinishedInput(code) => (
                 Some(State::Known),
                 events![
@@ -113,34 +131,42 @@ impl Code {
     fn in_allocating(&mut self, event: CodeEvent) -> (Option<State>, Events) {
         use events::CodeEvent::*;
         match event {
-            AllocateCode(_length, _wordlist) => panic!(),
-            InputCode => panic!(),
-            SetCode(code) => panic!(),
+            AllocateCode(_length, _wordlist) => self.unexpected("AllocateCode"),
+            InputCode(_) => self.unexpected("InputCode"),
+            SetCode(_) => self.unexpected("SetCode"),
             Allocated(nameplate, code) => {
                 // TODO: assert code.startswith(nameplate+"-")
                 (
                     Some(State::Known),
                     events![
-                        N_SetNameplate(nameplate.to_string()),
+                        N_SetNameplate(
+                            nameplate.to_string(),
+                            Arc::clone(&self.wordlist),
+                        ),
                         B_GotCode(code.to_string()),
                         K_GotCode(code.to_string())
                     ],
                 )
             }
-            GotNameplate(nameplate) => panic!(),
-            FinishedInput(_code) => panic!(),
+            GotNameplate(_) => self.unexpected("GotNameplate"),
+            FinishedInput(_code) => self.unexpected("FinishedInput"),
         }
     }
 
     fn in_known(&mut self, event: CodeEvent) -> (Option<State>, Events) {
         use events::CodeEvent::*;
         match event {
-            AllocateCode(_length, _wordlist) => panic!(),
-            InputCode => panic!(),
-            SetCode(code) => panic!(),
-            Allocated(nameplate, code) => panic!(),
-            GotNameplate(nameplate) => panic!(),
-            FinishedInput(_code) => panic!(),
+            AllocateCode(_length, _wordlist) => self.unexpected("AllocateCode"),
+            InputCode(_) => self.unexpected("InputCode"),
+            SetCode(_) => self.unexpected("SetCode"),
+            Allocated(_, _) => self.unexpected("Allocated"),
+            GotNameplate(_) => self.unexpected("GotNameplate"),
+            FinishedInput(_code) => self.unexpected("FinishedInput"),
         }
     }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("code: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
 }
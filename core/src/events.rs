@@ -1,30 +1,30 @@
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::str;
+use std::sync::Arc;
+use serde_json::Value;
 // Events come into the core, Actions go out of it (to the IO glue layer)
-use api::{APIAction, APIEvent, IOAction, IOEvent, Mood, TimerHandle, WSHandle};
+use api::{APIAction, APIEvent, IOAction, IOEvent, Mood, TimerHandle, WSHandle,
+          WormholeError};
 
-#[derive(Debug, PartialEq)]
-pub struct Wordlist {
-    // TODO lists(Vec<Vec<String>>),
-}
+pub use wordlist::Wordlist;
 
 // machines (or IO, or the API) emit these events, and each is routed to a
 // specific machine (or IO or the API)
 
 #[derive(Debug, PartialEq)]
 pub enum AllocatorEvent {
-    Allocate(u8, Wordlist),
+    Allocate(u8, Arc<Wordlist>), // length, wordlist
     Connected,
     Lost,
-    RxAllocated,
+    RxAllocated(String), // nameplate
 }
 
 #[derive(Debug, PartialEq)]
 pub enum BossEvent {
-    RxWelcome,
-    RxError,
-    Error,
+    RxWelcome(Value), // the "welcome" dict
+    RxError(String),  // the server's "error" reply
+    Error(WormholeError),
     Closed,
     GotCode(String),
     GotKey(Vec<u8>), // TODO: fixed length?
@@ -36,8 +36,8 @@ pub enum BossEvent {
 
 #[derive(Debug, PartialEq)]
 pub enum CodeEvent {
-    AllocateCode(u8, Wordlist), // length, wordlist
-    InputCode,
+    AllocateCode(u8, Arc<Wordlist>), // length, wordlist
+    InputCode(Arc<Wordlist>),
     SetCode(String),
     Allocated(String, String),
     GotNameplate(String),
@@ -47,8 +47,11 @@ pub enum CodeEvent {
 #[derive(Debug, PartialEq)]
 pub enum InputEvent {
     Start,
-    GotNameplates,
-    GotWordlist,
+    GotNameplates(Vec<String>),
+    GotWordlist(Arc<Wordlist>),
+    RefreshNameplates,
+    ChooseNameplate(String),
+    ChooseWords(String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -62,7 +65,7 @@ pub enum KeyEvent {
 pub enum ListerEvent {
     Connected,
     Lost,
-    RxNameplates,
+    RxNameplates(Vec<String>),
     Refresh,
 }
 
@@ -85,7 +88,7 @@ pub enum NameplateEvent {
     Lost,
     RxClaimed(String),
     RxReleased,
-    SetNameplate(String),
+    SetNameplate(String, Arc<Wordlist>), // and the words that follow it
     Release,
     Close,
 }
@@ -109,7 +112,7 @@ pub enum RendezvousEvent {
     TxBind(String, String), // appid, side
     TxOpen(String),         // mailbox
     TxAdd(String, Vec<u8>), // phase, body
-    TxClose,
+    TxClose(String, String), // mailbox, mood
     Stop,
     TxClaim(String),
     TxRelease(String),
@@ -1,21 +1,279 @@
-use events::Events;
+use std::sync::Arc;
+use api::InputHelperError;
+use events::{Events, Wordlist};
+use wordlist::{deserialize_wordlist, serialize_wordlist};
+use api::WormholeError;
 // we process these
 use events::InputEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
+use events::ListerEvent::Refresh as L_Refresh;
+use events::CodeEvent::{FinishedInput as C_FinishedInput,
+                        GotNameplate as C_GotNameplate};
 
-pub struct Input {}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum State {
+    S0_idle,
+    S1_typing_nameplate(Vec<String>), // nameplates we've heard about
+    S2_typing_code_no_wordlist(String), // nameplate
+    S3_typing_code_yes_wordlist(
+        String, // nameplate
+        #[serde(serialize_with = "serialize_wordlist",
+                deserialize_with = "deserialize_wordlist")]
+        Arc<Wordlist>,
+    ),
+    S4_done,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Input {
+    state: State,
+    num_words: u8, // how many words the completions should expect
+}
 
 impl Input {
-    pub fn new() -> Input {
-        Input {}
+    pub fn new(num_words: u8) -> Input {
+        Input {
+            state: State::S0_idle,
+            num_words: num_words,
+        }
     }
 
     pub fn process(&mut self, event: InputEvent) -> Events {
+        use self::State::*;
+        let (newstate, actions) = match self.state {
+            S0_idle => self.do_S0(event),
+            S1_typing_nameplate(_) => self.do_S1(event),
+            S2_typing_code_no_wordlist(ref nameplate) => {
+                self.do_S2(&nameplate, event)
+            }
+            S3_typing_code_yes_wordlist(ref nameplate, _) => {
+                self.do_S3(&nameplate, event)
+            }
+            S4_done => self.do_S4(event),
+        };
+        match newstate {
+            Some(s) => {
+                self.state = s;
+            }
+            None => {}
+        }
+        actions
+    }
+
+    // These are called synchronously by WormholeCore, so the application
+    // can ask for completions while the user is typing. The completions
+    // include the hyphen that follows, if more input is expected.
+
+    pub fn get_nameplate_completions(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, InputHelperError> {
+        use self::State::*;
+        match self.state {
+            S0_idle => Err(InputHelperError::Inactive),
+            S1_typing_nameplate(ref nameplates) => {
+                let mut completions: Vec<String> = nameplates
+                    .iter()
+                    .filter(|n| n.starts_with(prefix))
+                    .map(|n| format!("{}-", n))
+                    .collect();
+                completions.sort();
+                Ok(completions)
+            }
+            S2_typing_code_no_wordlist(_)
+            | S3_typing_code_yes_wordlist(_, _)
+            | S4_done => Err(InputHelperError::AlreadyChoseNameplate),
+        }
+    }
+
+    pub fn get_word_completions(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, InputHelperError> {
+        use self::State::*;
+        match self.state {
+            S0_idle => Err(InputHelperError::Inactive),
+            S1_typing_nameplate(_) => {
+                Err(InputHelperError::MustChooseNameplateFirst)
+            }
+            // we haven't claimed the nameplate yet, so we don't know which
+            // wordlist to use
+            S2_typing_code_no_wordlist(_) => Ok(Vec::new()),
+            S3_typing_code_yes_wordlist(_, ref wordlist) => {
+                Ok(wordlist.get_completions(prefix, self.num_words))
+            }
+            S4_done => Err(InputHelperError::AlreadyChoseWords),
+        }
+    }
+
+    fn do_S0(&self, event: InputEvent) -> (Option<State>, Events) {
+        use events::InputEvent::*;
+        match event {
+            Start => (
+                Some(State::S1_typing_nameplate(Vec::new())),
+                events![L_Refresh],
+            ),
+            // the Nameplate machine tells us about the wordlist even when
+            // the code was allocated or set directly
+            GotNameplates(_) | GotWordlist(_) => (None, events![]),
+            RefreshNameplates => self.unexpected("RefreshNameplates"),
+            ChooseNameplate(_nameplate) => self.unexpected("ChooseNameplate"),
+            ChooseWords(_words) => self.unexpected("ChooseWords"),
+        }
+    }
+
+    fn do_S1(&self, event: InputEvent) -> (Option<State>, Events) {
+        use events::InputEvent::*;
+        match event {
+            Start => self.unexpected("Start"),
+            GotNameplates(nameplates) => {
+                (Some(State::S1_typing_nameplate(nameplates)), events![])
+            }
+            GotWordlist(_wordlist) => self.unexpected("GotWordlist"),
+            RefreshNameplates => (None, events![L_Refresh]),
+            ChooseNameplate(nameplate) => (
+                Some(State::S2_typing_code_no_wordlist(nameplate.clone())),
+                events![C_GotNameplate(nameplate)],
+            ),
+            ChooseWords(_words) => self.unexpected("ChooseWords"),
+        }
+    }
+
+    fn do_S2(
+        &self,
+        nameplate: &str,
+        event: InputEvent,
+    ) -> (Option<State>, Events) {
         use events::InputEvent::*;
         match event {
-            Start => events![],
-            GotNameplates => events![],
-            GotWordlist => events![],
+            Start => self.unexpected("Start"),
+            GotNameplates(_) => (None, events![]),
+            GotWordlist(wordlist) => (
+                Some(State::S3_typing_code_yes_wordlist(
+                    nameplate.to_string(),
+                    wordlist,
+                )),
+                events![],
+            ),
+            RefreshNameplates => self.unexpected("RefreshNameplates"),
+            ChooseNameplate(_nameplate) => self.unexpected("ChooseNameplate"),
+            ChooseWords(words) => {
+                let code = format!("{}-{}", nameplate, words);
+                (Some(State::S4_done), events![C_FinishedInput(code)])
+            }
         }
     }
+
+    fn do_S3(
+        &self,
+        nameplate: &str,
+        event: InputEvent,
+    ) -> (Option<State>, Events) {
+        use events::InputEvent::*;
+        match event {
+            Start => self.unexpected("Start"),
+            GotNameplates(_) => (None, events![]),
+            GotWordlist(_) => (None, events![]),
+            RefreshNameplates => self.unexpected("RefreshNameplates"),
+            ChooseNameplate(_nameplate) => self.unexpected("ChooseNameplate"),
+            ChooseWords(words) => {
+                let code = format!("{}-{}", nameplate, words);
+                (Some(State::S4_done), events![C_FinishedInput(code)])
+            }
+        }
+    }
+
+    fn do_S4(&self, event: InputEvent) -> (Option<State>, Events) {
+        use events::InputEvent::*;
+        match event {
+            Start => self.unexpected("Start"),
+            GotNameplates(_) => (None, events![]),
+            GotWordlist(_) => (None, events![]),
+            RefreshNameplates => self.unexpected("RefreshNameplates"),
+            ChooseNameplate(_nameplate) => self.unexpected("ChooseNameplate"),
+            ChooseWords(_words) => self.unexpected("ChooseWords"),
+        }
+    }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("input: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::InputHelperError;
+    use events::InputEvent::*;
+    use events::{CodeEvent, ListerEvent, Wordlist};
+    use std::sync::Arc;
+    use wordlist::AlternatingWordlist;
+
+    fn wordlist() -> Arc<Wordlist> {
+        let even = vec!["purple".to_string(), "puffin".to_string()];
+        let odd = vec!["sausages".to_string(), "snapshot".to_string()];
+        Arc::new(AlternatingWordlist::new(vec![even, odd]))
+    }
+
+    #[test]
+    fn inactive() {
+        let i = Input::new(2);
+        assert_eq!(
+            i.get_nameplate_completions(""),
+            Err(InputHelperError::Inactive)
+        );
+        assert_eq!(i.get_word_completions(""), Err(InputHelperError::Inactive));
+    }
+
+    #[test]
+    fn complete_nameplate_then_words() {
+        let mut i = Input::new(2);
+        assert_eq!(i.process(Start), events![ListerEvent::Refresh]);
+        assert_eq!(
+            i.get_word_completions(""),
+            Err(InputHelperError::MustChooseNameplateFirst)
+        );
+        let nameplates = vec!["12".to_string(), "4".to_string(), "1".into()];
+        assert_eq!(i.process(GotNameplates(nameplates)), events![]);
+        assert_eq!(
+            i.get_nameplate_completions("1"),
+            Ok(vec!["1-".to_string(), "12-".to_string()])
+        );
+        assert_eq!(
+            i.process(RefreshNameplates),
+            events![ListerEvent::Refresh]
+        );
+
+        assert_eq!(
+            i.process(ChooseNameplate("4".to_string())),
+            events![CodeEvent::GotNameplate("4".to_string())]
+        );
+        assert_eq!(
+            i.get_nameplate_completions(""),
+            Err(InputHelperError::AlreadyChoseNameplate)
+        );
+        // no wordlist until the nameplate is claimed
+        assert_eq!(i.get_word_completions("pu"), Ok(vec![]));
+
+        assert_eq!(i.process(GotWordlist(wordlist())), events![]);
+        assert_eq!(
+            i.get_word_completions("pu"),
+            Ok(vec!["puffin-".to_string(), "purple-".to_string()])
+        );
+        assert_eq!(
+            i.get_word_completions("purple-sa"),
+            Ok(vec!["purple-sausages".to_string()])
+        );
+
+        assert_eq!(
+            i.process(ChooseWords("purple-sausages".to_string())),
+            events![CodeEvent::FinishedInput("4-purple-sausages".to_string())]
+        );
+        assert_eq!(
+            i.get_word_completions(""),
+            Err(InputHelperError::AlreadyChoseWords)
+        );
+    }
 }
@@ -11,17 +11,20 @@ use hkdf::Hkdf;
 
 use util;
 use events::Events;
+use api::WormholeError;
 // we process these
 use events::KeyEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
+use events::BossEvent::Scared as B_Scared;
 use events::MailboxEvent::AddMessage as M_AddMessage;
 use events::BossEvent::GotKey as B_GotKey;
 use events::ReceiveEvent::GotKey as R_GotKey;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 enum State {
     S00,
-    S10(String),          // code
+    S10(String),          // code (and we've sent our pake)
     S01(Vec<u8>),         // pake
     S11(String, Vec<u8>), // code, pake
 }
@@ -33,10 +36,16 @@ enum SKState {
     S3_Scared,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Key {
     appid: String,
     state: State,
     side: String,
+    app_versions: serde_json::Value,
+    // the other half of the pake we sent, kept until our peer's arrives.
+    // This can't be saved: see exchanging().
+    #[serde(skip)]
+    pake: Option<SPAKE2<Ed25519Group>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,27 +53,34 @@ struct PhaseMessage {
     pake_v1: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionsMessage {
+    app_versions: serde_json::Value,
+}
+
 impl Key {
-    pub fn new(appid: &str, side: &str) -> Key {
+    pub fn new(
+        appid: &str,
+        side: &str,
+        app_versions: serde_json::Value,
+    ) -> Key {
         Key {
             appid: appid.to_string(),
             state: State::S00,
             side: side.to_string(),
+            app_versions: app_versions,
+            pake: None,
         }
     }
 
     pub fn process(&mut self, event: KeyEvent) -> Events {
         use self::State::*;
 
-        println!(
-            "key: current state = {:?}, got event = {:?}",
-            self.state, event
-        );
-        let (newstate, actions) = match self.state {
+        let (newstate, actions) = match self.state.clone() {
             S00 => self.do_S00(event),
-            S01(ref body) => self.do_S01(body.to_vec(), event),
-            S10(ref code) => self.do_S10(&code, event),
-            S11(ref code, ref body) => self.do_S11(&code, body.to_vec(), event),
+            S01(body) => self.do_S01(body, event),
+            S10(code) => self.do_S10(&code, event),
+            S11(code, body) => self.do_S11(&code, body, event),
         };
 
         match newstate {
@@ -76,6 +92,16 @@ impl Key {
         actions
     }
 
+    // True between sending our pake and receiving our peer's. The secret
+    // half of our pake can't be saved, and a fresh one wouldn't match the
+    // one our peer may already have, so WormholeCore won't save us then.
+    pub fn exchanging(&self) -> bool {
+        match self.state {
+            State::S10(_) => true,
+            _ => false,
+        }
+    }
+
     fn extract_pake_msg(&self, body: Vec<u8>) -> Option<String> {
         let pake_msg = serde_json::from_slice(&body)
             .and_then(|res: PhaseMessage| Ok(res.pake_v1))
@@ -99,10 +125,11 @@ impl Key {
     fn compute_key(&self, key: &[u8]) -> Events {
         let phase = "version";
         let data_key = Self::derive_phase_key(&self.side, &key, phase);
-        let versions = r#"{"app_versions": {}}"#;
-        let plaintext = versions.to_string();
-        let (nonce, encrypted) =
-            Self::encrypt_data(data_key, &plaintext.as_bytes());
+        let versions = VersionsMessage {
+            app_versions: self.app_versions.clone(),
+        };
+        let plaintext = serde_json::to_vec(&versions).unwrap();
+        let (nonce, encrypted) = Self::encrypt_data(data_key, &plaintext);
         events![
             B_GotKey(key.to_vec()),
             M_AddMessage(phase.to_string(), encrypted),
@@ -123,13 +150,15 @@ impl Key {
     // TODO: return an Result with a proper error type
     // secretbox::open() returns Result<Vec<u8>, ()> which is not helpful.
     pub fn decrypt_data(key: Vec<u8>, encrypted: &[u8]) -> Option<Vec<u8>> {
-        let (nonce, ciphertext) =
-            encrypted.split_at(sodiumoxide::crypto::secretbox::NONCEBYTES);
-        assert_eq!(nonce.len(), sodiumoxide::crypto::secretbox::NONCEBYTES);
+        // this came from our peer, and might be too short to hold a nonce
+        if encrypted.len() < secretbox::NONCEBYTES {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(secretbox::NONCEBYTES);
         secretbox::open(
             &ciphertext,
-            &secretbox::Nonce::from_slice(nonce).unwrap(),
-            &secretbox::Key::from_slice(&key).unwrap(),
+            &secretbox::Nonce::from_slice(nonce)?,
+            &secretbox::Key::from_slice(&key)?,
         ).ok()
     }
 
@@ -161,33 +190,48 @@ impl Key {
         Self::derive_key(key, &purpose_vec, length)
     }
 
-    fn do_S00(&self, event: KeyEvent) -> (Option<State>, Events) {
+    fn do_S00(&mut self, event: KeyEvent) -> (Option<State>, Events) {
         use events::KeyEvent::*;
 
         match event {
             GotCode(code) => {
-                // defer building and sending pake.
-                (Some(State::S10(code.clone())), events![])
+                // send our pake right away: our peer may be waiting for it
+                let (es, sp) = self.build_pake(&code);
+                self.pake = Some(sp);
+                (Some(State::S10(code)), es)
             }
             GotPake(body) => {
                 // early, we haven't got the code yet.
                 (Some(State::S01(body)), events![])
             }
-            GotMessage => panic!(),
+            GotMessage => self.unexpected("GotMessage"),
         }
     }
 
     fn send_pake_compute_key(&self, code: &str, body: Vec<u8>) -> Events {
-        let (mut buildpake_events, sp) = self.build_pake(&code);
-        let msg2 = self.extract_pake_msg(body).unwrap();
-        let key = sp.finish(&hex::decode(msg2).unwrap()).unwrap();
-        let mut key_events = self.compute_key(&key);
-
-        let mut es = buildpake_events;
-        es.append(&mut key_events);
+        let (mut es, sp) = self.build_pake(&code);
+        es.append(&mut self.finish_pake(sp, body));
         es
     }
 
+    fn finish_pake(&self, sp: SPAKE2<Ed25519Group>, body: Vec<u8>) -> Events {
+        let msg2 = self.extract_pake_msg(body.clone())
+            .and_then(|msg2| hex::decode(msg2).ok());
+        let msg2 = match msg2 {
+            Some(msg2) => msg2,
+            None => {
+                let body = String::from_utf8_lossy(&body);
+                let e = format!("pake: {}", body);
+                return events![B_Error(WormholeError::MalformedMessage(e))];
+            }
+        };
+        match sp.finish(&msg2) {
+            Ok(key) => self.compute_key(&key),
+            // well-formed, but not something an honest peer would send
+            Err(_) => events![B_Scared],
+        }
+    }
+
     fn do_S01(
         &self,
         body: Vec<u8>,
@@ -200,21 +244,30 @@ impl Key {
                 let es = self.send_pake_compute_key(&code, body.clone());
                 (Some(State::S11(code, body)), es)
             }
-            GotPake(_) => panic!(),
-            GotMessage => panic!(),
+            GotPake(_) => self.unexpected("GotPake"),
+            GotMessage => self.unexpected("GotMessage"),
         }
     }
 
-    fn do_S10(&self, code: &str, event: KeyEvent) -> (Option<State>, Events) {
+    fn do_S10(
+        &mut self,
+        code: &str,
+        event: KeyEvent,
+    ) -> (Option<State>, Events) {
         use events::KeyEvent::*;
 
         match event {
-            GotCode(_) => panic!(), // we already have the code
-            GotPake(body) => {
-                let es = self.send_pake_compute_key(&code, body.clone());
-                (Some(State::S11(code.to_string(), body)), es)
-            }
-            GotMessage => panic!(),
+            // we already have the code
+            GotCode(_) => self.unexpected("GotCode"),
+            GotPake(body) => match self.pake.take() {
+                Some(sp) => {
+                    let es = self.finish_pake(sp, body.clone());
+                    (Some(State::S11(code.to_string(), body)), es)
+                }
+                // WormholeCore never saves (or restores) us in S10
+                None => self.unexpected("GotPake"),
+            },
+            GotMessage => self.unexpected("GotMessage"),
         }
     }
 
@@ -228,23 +281,30 @@ impl Key {
         use events::KeyEvent::*;
 
         match event {
-            GotCode(_) => panic!(),
-            GotPake(_) => panic!(),
-            GotMessage => panic!(),
+            GotCode(_) => self.unexpected("GotCode"),
+            GotPake(_) => self.unexpected("GotPake"),
+            GotMessage => self.unexpected("GotMessage"),
         }
     }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        // our states hold secrets (the code or the key), so leave them out
+        let e = format!("key: unexpected {}", event);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use events::{BossEvent, Event};
 
     #[test]
     fn test_extract_pake_msg() {
         extern crate hex;
         use super::*;
 
-        let key = super::Key::new("appid", "side1");
+        let key = super::Key::new("appid", "side1", json!({}));
 
         let s1 = "7b2270616b655f7631223a22353337363331646366643064336164386130346234663531643935336131343563386538626663373830646461393834373934656634666136656536306339663665227d";
         let pake_msg = key.extract_pake_msg(hex::decode(s1).unwrap());
@@ -262,7 +322,7 @@ mod test {
         // output of derive_phase_key is:
         // "\xfe\x93\x15r\x96h\xa6'\x8a\x97D\x9d\xc9\x9a_L!\x02\xa6h\xc6\x8538\x15)\x06\xbbuRj\x96"
         // hexlified output: fe9315729668a6278a97449dc99a5f4c2102a668c6853338152906bb75526a96
-        let k = Key::new("appid1", "side");
+        let k = Key::new("appid1", "side", json!({}));
 
         let key = "key".as_bytes();
         let side = "side";
@@ -295,4 +355,33 @@ mod test {
             None => panic!(),
         }
     }
+
+    #[test]
+    fn test_decrypt_short_data() {
+        let data_key = Key::derive_phase_key("side", b"key", "phase");
+        assert_eq!(Key::decrypt_data(data_key, b"abc"), None);
+    }
+
+    #[test]
+    fn test_garbage_pake() {
+        let code = "4-purple-sausages".to_string();
+        let garbage = |body: &[u8]| {
+            let mut k = Key::new("appid", "side1", json!({}));
+            k.process(KeyEvent::GotCode(code.clone()));
+            k.process(KeyEvent::GotPake(body.to_vec()))
+        };
+        for body in &[&b"garbage"[..], br#"{"pake_v1": "not hex"}"#] {
+            match garbage(body).events[..] {
+                [Event::Boss(BossEvent::Error(
+                    WormholeError::MalformedMessage(_),
+                ))] => {}
+                ref other => panic!("unexpected {:?}", other),
+            }
+        }
+        // hex, but not a point on the curve
+        assert_eq!(
+            garbage(br#"{"pake_v1": "0102"}"#),
+            events![BossEvent::Scared]
+        );
+    }
 }
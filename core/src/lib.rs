@@ -1,10 +1,12 @@
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 mod events;
 extern crate hkdf;
+extern crate rand;
 extern crate sha2;
 extern crate sodiumoxide;
 extern crate spake2;
@@ -21,17 +23,25 @@ mod nameplate;
 mod order;
 mod receive;
 mod rendezvous;
-mod server_messages;
+// the JSON the Mailbox Server and its clients exchange
+pub mod server_messages;
 mod send;
 mod terminator;
 mod wordlist;
 mod util;
+#[cfg(test)]
+mod mock_server;
 
 use std::collections::VecDeque;
 use events::{Event, Events};
-pub use api::{APIAction, APIEvent, Action, IOAction, IOEvent, TimerHandle,
-              WSHandle};
+pub use api::{APIAction, APIEvent, Action, Clock, IOAction, IOEvent,
+              InputHelperError, Mood, Random, TimerHandle, WSHandle,
+              WormholeError};
+pub use wordlist::{default_wordlist, AlternatingWordlist, Wordlist};
 
+// The whole core can be saved with serialize() and brought back with
+// restore(), e.g. when a mobile app is killed in the background.
+#[derive(Serialize, Deserialize)]
 pub struct WormholeCore {
     allocator: allocator::Allocator,
     boss: boss::Boss,
@@ -53,15 +63,123 @@ pub struct WormholeCore {
     from.into_iter().map(|r| Result::from(r)).collect::<Vec<Result>>()
 }*/
 
-impl WormholeCore {
-    pub fn new(appid: &str, relay_url: &str) -> WormholeCore {
-        let side = "side1"; // TODO: generate randomly
+// Everything except the appid and relay URL has a reasonable default:
+//
+//     let mut wc = WormholeCoreBuilder::new(APPID, RELAY_URL)
+//         .app_versions(json!({"protocol": 1}))
+//         .build();
+pub struct WormholeCoreBuilder {
+    appid: String,
+    relay_url: String,
+    side: Option<String>,
+    reconnect_delay: f32,
+    reconnect_max_delay: f32,
+    reconnect_max_attempts: Option<u32>,
+    keepalive: Option<f32>,
+    code_length: u8,
+    app_versions: serde_json::Value,
+    clock: Clock,
+    random: Random,
+}
+
+impl WormholeCoreBuilder {
+    pub fn new(appid: &str, relay_url: &str) -> WormholeCoreBuilder {
+        WormholeCoreBuilder {
+            appid: appid.to_string(),
+            relay_url: relay_url.to_string(),
+            side: None,
+            reconnect_delay: 5.0,
+            reconnect_max_delay: 300.0,
+            reconnect_max_attempts: None,
+            // comfortably inside the 60s idle timeout that many proxies use
+            keepalive: Some(30.0),
+            // the Python client's completer assumes two words, and so do we
+            code_length: 2,
+            app_versions: json!({}),
+            clock: rendezvous::system_clock(),
+            random: rendezvous::thread_random(),
+        }
+    }
+
+    // a fresh random side is generated for each WormholeCore unless one is
+    // given here, which is mostly useful for tests
+    pub fn side(mut self, side: &str) -> WormholeCoreBuilder {
+        self.side = Some(side.to_string());
+        self
+    }
+
+    // seconds to wait before reconnecting to the relay server. The delay
+    // doubles (plus or minus some jitter) after each failed attempt, up to
+    // reconnect_max_delay.
+    pub fn reconnect_delay(mut self, seconds: f32) -> WormholeCoreBuilder {
+        self.reconnect_delay = seconds;
+        self
+    }
+
+    pub fn reconnect_max_delay(mut self, seconds: f32) -> WormholeCoreBuilder {
+        self.reconnect_max_delay = seconds;
+        self
+    }
+
+    // give up (with WormholeError::ConnectionFailed) after this many failed
+    // connection attempts in a row. By default we keep trying forever.
+    pub fn reconnect_max_attempts(
+        mut self,
+        attempts: u32,
+    ) -> WormholeCoreBuilder {
+        self.reconnect_max_attempts = Some(attempts);
+        self
+    }
+
+    // seconds between pings to the relay server while we're connected.
+    // Each pong is reported in APIAction::GotRoundTrip, and a ping that
+    // hasn't been answered by the next one makes us reconnect. None turns
+    // pings off entirely.
+    pub fn keepalive(mut self, seconds: Option<f32>) -> WormholeCoreBuilder {
+        self.keepalive = seconds;
+        self
+    }
+
+    // how many words our codes have: APIEvent::AllocateCode uses this unless
+    // it's given a length, and the input helper expects this many when
+    // completing a code
+    pub fn code_length(mut self, num_words: u8) -> WormholeCoreBuilder {
+        self.code_length = num_words;
+        self
+    }
+
+    // sent (encrypted) to our peer, who gets it in APIAction::GotVersions
+    pub fn app_versions(
+        mut self,
+        app_versions: serde_json::Value,
+    ) -> WormholeCoreBuilder {
+        self.app_versions = app_versions;
+        self
+    }
+
+    // the time, as far as the core is concerned. By default it's the system
+    // clock (Instant::now).
+    pub fn clock(mut self, clock: Clock) -> WormholeCoreBuilder {
+        self.clock = clock;
+        self
+    }
+
+    // random numbers between 0 and 1, for the jitter in reconnect_delay. By
+    // default they come from rand::thread_rng.
+    pub fn random(mut self, random: Random) -> WormholeCoreBuilder {
+        self.random = random;
+        self
+    }
+
+    pub fn build(self) -> WormholeCore {
+        let side = self.side.unwrap_or_else(util::random_side);
+        let appid = &self.appid;
         WormholeCore {
             allocator: allocator::Allocator::new(),
-            boss: boss::Boss::new(),
+            boss: boss::Boss::new(self.code_length),
             code: code::Code::new(),
-            input: input::Input::new(),
-            key: key::Key::new(appid, side),
+            input: input::Input::new(self.code_length),
+            key: key::Key::new(appid, &side, self.app_versions),
             lister: lister::Lister::new(),
             mailbox: mailbox::Mailbox::new(&side),
             nameplate: nameplate::Nameplate::new(),
@@ -69,14 +187,48 @@ impl WormholeCore {
             receive: receive::Receive::new(),
             rendezvous: rendezvous::Rendezvous::new(
                 appid,
-                relay_url,
-                side,
-                5.0,
+                &self.relay_url,
+                &side,
+                self.reconnect_delay,
+                self.reconnect_max_delay,
+                self.reconnect_max_attempts,
+                self.keepalive,
+                self.clock,
+                self.random,
             ),
-            send: send::Send::new(side),
+            send: send::Send::new(&side),
             terminator: terminator::Terminator::new(),
         }
     }
+}
+
+impl WormholeCore {
+    // Save everything we need to carry on in the same mailbox later: the
+    // code, the key (once known), and any messages that haven't been
+    // acknowledged yet. The bytes contain secrets, so store them carefully.
+    // The one thing that can't be saved is the secret half of the PAKE
+    // message we send once the code is known, so until our peer's arrives
+    // (and APIAction::GotUnverifiedKey or an error follows) this returns
+    // WormholeError::KeyExchangeInProgress.
+    pub fn serialize(&self) -> Result<Vec<u8>, WormholeError> {
+        if self.key.exchanging() {
+            return Err(WormholeError::KeyExchangeInProgress);
+        }
+        Ok(serde_json::to_vec(self).unwrap())
+    }
+
+    // A restored core is disconnected: call start() to reconnect, after
+    // which it picks up where the saved one left off. Any custom wordlist,
+    // clock or randomness is replaced by the default one.
+    pub fn restore(saved: &[u8]) -> Result<WormholeCore, WormholeError> {
+        let core: WormholeCore = serde_json::from_slice(saved)
+            .map_err(|e| WormholeError::MalformedSavedState(e.to_string()))?;
+        if core.key.exchanging() {
+            let e = "saved during the key exchange".to_string();
+            return Err(WormholeError::MalformedSavedState(e));
+        }
+        Ok(core)
+    }
 
     pub fn start(&mut self) -> Vec<Action> {
         // TODO: replace with Boss::Start, which will start rendezvous
@@ -93,12 +245,32 @@ impl WormholeCore {
         self._execute(events)
     }
 
-    pub fn derive_key(&mut self, _purpose: &str, _length: u8) -> Vec<u8> {
-        // TODO: only valid after GotVerifiedKey, but should return
-        // synchronously. Maybe the Core should expose the conversion
-        // function (which requires the key as input) and let the IO glue
-        // layer decide how to manage the synchronization?
-        Vec::new()
+    // Once APIEvent::InputCode has been sent, the application can ask for
+    // completions of the nameplate, and then (after choosing the nameplate
+    // with APIEvent::InputHelperChooseNameplate) of the words.
+    pub fn input_helper_get_nameplate_completions(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, InputHelperError> {
+        self.input.get_nameplate_completions(prefix)
+    }
+
+    pub fn input_helper_get_word_completions(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<String>, InputHelperError> {
+        self.input.get_word_completions(prefix)
+    }
+
+    // Derive a key for some other purpose (e.g. "{appid}/transit-key"),
+    // from the shared key. This only works after APIAction::GotVerifier has
+    // been delivered, and both sides must use the same purpose and length.
+    pub fn derive_key(
+        &self,
+        purpose: &str,
+        length: usize,
+    ) -> Result<Vec<u8>, WormholeError> {
+        self.boss.derive_key(purpose, length)
     }
 
     fn _execute(&mut self, events: Events) -> Vec<Action> {
@@ -108,7 +280,6 @@ impl WormholeCore {
         event_queue.append(&mut VecDeque::from(events.events));
 
         while let Some(e) = event_queue.pop_front() {
-            println!("event: {:?}", e);
             use events::Event::*; // machine names
             let actions: Events = match e {
                 API(a) => {
@@ -132,13 +303,11 @@ impl WormholeCore {
                 Rendezvous(e) => self.rendezvous.process(e),
                 Send(e) => self.send.process(e),
                 Terminator(e) => self.terminator.process(e),
-                _ => panic!(),
             };
 
             for a in actions.events {
                 // TODO use iter
                 // TODO: insert in front of queue: depth-first processing
-                println!("  out: {:?}", a);
                 event_queue.push_back(a);
             }
         }
@@ -215,3 +384,137 @@ mod test {
 
 // TODO: is there a generic way (e.g. impl From) to convert a Vec<A> into
 // Vec<B> when we've got an A->B convertor?
+
+#[cfg(test)]
+mod test {
+    extern crate hex;
+
+    use super::*;
+    use serde_json::Value;
+    use server_messages::{deserialize, Message};
+    use spake2::{Ed25519Group, SPAKE2};
+
+    fn sent_messages(actions: &[Action]) -> Vec<Message> {
+        actions
+            .iter()
+            .filter_map(|a| match *a {
+                Action::IO(IOAction::WebSocketSendMessage(_, ref m)) => {
+                    Some(deserialize(m))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn connection_failed() {
+        let mut w = WormholeCoreBuilder::new("appid", "url")
+            .reconnect_max_attempts(1)
+            .build();
+        let wsh = match w.start()[..] {
+            [Action::IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+            _ => panic!(),
+        };
+        w.do_api(APIEvent::SetCode("4-purple-sausages".into()));
+        let actions = w.do_io(IOEvent::WebSocketConnectionLost(wsh));
+        let err = WormholeError::ConnectionFailed(1);
+        assert_eq!(
+            actions,
+            vec![
+                Action::API(APIAction::GotError(err)),
+                Action::API(APIAction::GotClosed(Mood::Error)),
+            ]
+        );
+    }
+
+    // the body of the first "add" with this phase
+    fn added(sent: &[Message], phase: &str) -> Vec<u8> {
+        sent.iter()
+            .filter_map(|m| match *m {
+                Message::Add {
+                    phase: ref p,
+                    ref body,
+                } if p == phase =>
+                {
+                    Some(hex::decode(body).unwrap())
+                }
+                _ => None,
+            })
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn serialize_and_restore() {
+        let mut w = WormholeCoreBuilder::new("appid", "url")
+            .side("side1")
+            .build();
+        w.start();
+        let wsh = WSHandle::new(1);
+        w.do_io(IOEvent::WebSocketConnectionMade(wsh));
+        let actions = w.do_api(APIEvent::SetCode("4-purple-sausages".into()));
+        assert!(
+            sent_messages(&actions).contains(&Message::Claim {
+                nameplate: "4".to_string(),
+            })
+        );
+        let actions = w.do_io(IOEvent::WebSocketMessageReceived(
+            wsh,
+            r#"{"type": "claimed", "mailbox": "mb1"}"#.to_string(),
+        ));
+
+        // our PAKE message is out, and its secret half can't be saved
+        assert_eq!(
+            w.serialize().unwrap_err(),
+            WormholeError::KeyExchangeInProgress
+        );
+        let pake = added(&sent_messages(&actions), "pake");
+        let pake: Value = serde_json::from_slice(&pake).unwrap();
+        let pake = hex::decode(pake["pake_v1"].as_str().unwrap()).unwrap();
+        let (sp, their_pake) = SPAKE2::<Ed25519Group>::start_symmetric(
+            b"4-purple-sausages",
+            b"appid",
+        );
+        let key = sp.finish(&pake).unwrap();
+        let body = json!({"pake_v1": hex::encode(their_pake)});
+        let message = json!({
+            "type": "message",
+            "id": "1",
+            "side": "side2",
+            "phase": "pake",
+            "body": hex::encode(body.to_string()),
+        });
+        w.do_io(IOEvent::WebSocketMessageReceived(wsh, message.to_string()));
+
+        // once our peer's has arrived, we can be saved again
+        let saved = w.serialize().unwrap();
+        let mut w2 = WormholeCore::restore(&saved).unwrap();
+        match w2.start()[..] {
+            [Action::IO(IOAction::WebSocketOpen(_, ref url))] => {
+                assert_eq!(url, "url");
+            }
+            _ => panic!(),
+        }
+        // we wake up disconnected, and get back into the same mailbox (with
+        // the same side) once we reconnect
+        let actions = w2.do_io(IOEvent::WebSocketConnectionMade(wsh));
+        let sent = sent_messages(&actions);
+        assert!(sent.contains(&Message::Bind {
+            appid: "appid".to_string(),
+            side: "side1".to_string(),
+        }));
+        assert!(sent.contains(&Message::Open {
+            mailbox: "mb1".to_string(),
+        }));
+        // our "version" message hasn't been echoed back yet, so it goes out
+        // again, still encrypted with the key from the same exchange
+        let version = added(&sent, "version");
+        let data_key = key::Key::derive_phase_key("side1", &key, "version");
+        assert!(key::Key::decrypt_data(data_key, &version).is_some());
+
+        match WormholeCore::restore(b"garbage") {
+            Err(WormholeError::MalformedSavedState(_)) => (),
+            _ => panic!(),
+        }
+    }
+}
@@ -1,22 +1,152 @@
+use serde::{Serialize, Serializer};
 use events::Events;
+use api::WormholeError;
 // we process these
 use events::ListerEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
+use events::RendezvousEvent::TxList as RC_TxList;
+use events::InputEvent::GotNameplates as I_GotNameplates;
 
-pub struct Lister {}
+// all -A states are not-connected, while -B states are yes-connected
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum State {
+    // S0: nobody wants a list right now
+    S0A_idle,
+    S0B_idle_connected,
+    // S1: someone asked for a list, which we'll send when possible
+    S1A_wanting,
+    S1B_wanting_connected,
+}
+
+// B states serialize as A, so we wake up disconnected
+fn serialize_state<S>(state: &State, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use self::State::*;
+    let disconnected = match *state {
+        S0B_idle_connected => S0A_idle,
+        S1B_wanting_connected => S1A_wanting,
+        ref s => return s.serialize(serializer),
+    };
+    disconnected.serialize(serializer)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Lister {
+    #[serde(serialize_with = "serialize_state")]
+    state: State,
+}
 
 impl Lister {
     pub fn new() -> Lister {
-        Lister {}
+        Lister {
+            state: State::S0A_idle,
+        }
     }
 
     pub fn process(&mut self, event: ListerEvent) -> Events {
+        use self::State::*;
+        let (newstate, actions) = match self.state {
+            S0A_idle => self.do_S0A(event),
+            S0B_idle_connected => self.do_S0B(event),
+            S1A_wanting => self.do_S1A(event),
+            S1B_wanting_connected => self.do_S1B(event),
+        };
+        match newstate {
+            Some(s) => {
+                self.state = s;
+            }
+            None => {}
+        }
+        actions
+    }
+
+    fn do_S0A(&self, event: ListerEvent) -> (Option<State>, Events) {
+        use events::ListerEvent::*;
+        match event {
+            Connected => (Some(State::S0B_idle_connected), events![]),
+            Lost => self.unexpected("Lost"),
+            RxNameplates(_nameplates) => self.unexpected("RxNameplates"),
+            Refresh => (Some(State::S1A_wanting), events![]),
+        }
+    }
+
+    fn do_S0B(&self, event: ListerEvent) -> (Option<State>, Events) {
+        use events::ListerEvent::*;
+        match event {
+            Connected => self.unexpected("Connected"),
+            Lost => (Some(State::S0A_idle), events![]),
+            // an unsolicited list (or a response to an earlier request
+            // that we've since stopped wanting) is still useful
+            RxNameplates(nameplates) => {
+                (None, events![I_GotNameplates(nameplates)])
+            }
+            Refresh => {
+                (Some(State::S1B_wanting_connected), events![RC_TxList])
+            }
+        }
+    }
+
+    fn do_S1A(&self, event: ListerEvent) -> (Option<State>, Events) {
+        use events::ListerEvent::*;
+        match event {
+            Connected => {
+                (Some(State::S1B_wanting_connected), events![RC_TxList])
+            }
+            Lost => self.unexpected("Lost"),
+            RxNameplates(_nameplates) => self.unexpected("RxNameplates"),
+            Refresh => (None, events![]),
+        }
+    }
+
+    fn do_S1B(&self, event: ListerEvent) -> (Option<State>, Events) {
         use events::ListerEvent::*;
         match event {
-            Connected => events![],
-            Lost => events![],
-            RxNameplates => events![],
-            Refresh => events![],
+            Connected => self.unexpected("Connected"),
+            // the request died with the connection, so we'll re-send it
+            // once we're connected again
+            Lost => (Some(State::S1A_wanting), events![]),
+            RxNameplates(nameplates) => (
+                Some(State::S0B_idle_connected),
+                events![I_GotNameplates(nameplates)],
+            ),
+            Refresh => (None, events![RC_TxList]),
         }
     }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("lister: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use events::ListerEvent::*;
+    use events::InputEvent;
+    use events::RendezvousEvent;
+
+    #[test]
+    fn refresh_while_connected() {
+        let mut l = Lister::new();
+        assert_eq!(l.process(Connected), events![]);
+        assert_eq!(l.process(Refresh), events![RendezvousEvent::TxList]);
+        let nameplates = vec!["1".to_string(), "4".to_string()];
+        assert_eq!(
+            l.process(RxNameplates(nameplates.clone())),
+            events![InputEvent::GotNameplates(nameplates)]
+        );
+    }
+
+    #[test]
+    fn refresh_survives_reconnect() {
+        let mut l = Lister::new();
+        assert_eq!(l.process(Refresh), events![]);
+        assert_eq!(l.process(Connected), events![RendezvousEvent::TxList]);
+        assert_eq!(l.process(Lost), events![]);
+        assert_eq!(l.process(Connected), events![RendezvousEvent::TxList]);
+    }
 }
@@ -1,8 +1,10 @@
 use std::collections::HashSet;
 use std::collections::HashMap;
 
+use serde::{Serialize, Serializer};
 use events::Events;
 use events::Event;
+use api::WormholeError;
 // we process these
 use events::MailboxEvent;
 use events::TerminatorEvent::MailboxDone as T_MailboxDone;
@@ -11,8 +13,9 @@ use events::RendezvousEvent::{TxAdd as RC_TxAdd, TxClose as RC_TxClose,
 use events::NameplateEvent::Release as N_Release;
 use events::OrderEvent::GotMessage as O_GotMessage;
 // we emit these
+use events::BossEvent::Error as B_Error;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     // S0: We know nothing
     S0A,
@@ -30,7 +33,25 @@ enum State {
     S4B,
 }
 
+// B states serialize as A, so we wake up disconnected
+fn serialize_state<S>(state: &State, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use self::State::*;
+    let disconnected = match *state {
+        S0B => S0A,
+        S2B(ref mailbox) => S2A(mailbox.clone()),
+        S3B(ref mailbox, ref mood) => S3A(mailbox.clone(), mood.clone()),
+        S4B => S4A,
+        ref s => return s.serialize(serializer),
+    };
+    disconnected.serialize(serializer)
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Mailbox {
+    #[serde(serialize_with = "serialize_state")]
     state: State,
     side: String,
     pending_outbound: HashMap<String, Vec<u8>>, // HashMap<phase, body>
@@ -58,11 +79,6 @@ impl Mailbox {
     pub fn process(&mut self, event: MailboxEvent) -> Events {
         use self::State::*;
 
-        println!(
-            "mailbox: current state = {:?}, got event = {:?}",
-            self.state, event
-        );
-
         let (newstate, actions, queue) = match self.state {
             S0A => self.do_S0A(event),
             S0B => self.do_S0B(event),
@@ -73,7 +89,6 @@ impl Mailbox {
             S3B(ref mailbox, ref mood) => self.do_S3B(&mailbox, &mood, event),
             S4A => self.do_S4A(event),
             S4B => self.do_S4B(event),
-            _ => panic!(),
         };
         match newstate {
             Some(s) => {
@@ -107,9 +122,9 @@ impl Mailbox {
 
         match event {
             Connected => (Some(State::S0B), events![], QueueCtrl::NoAction),
-            Lost => panic!(),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
             Close(_) => (
                 Some(State::S4A),
                 events![T_MailboxDone],
@@ -118,7 +133,7 @@ impl Mailbox {
             GotMailbox(mailbox) => {
                 (Some(State::S1A(mailbox)), events![], QueueCtrl::NoAction)
             }
-            GotMessage => panic!(),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(phase, body) => {
                 let mut v = vec![];
                 v.push((phase, body));
@@ -134,10 +149,10 @@ impl Mailbox {
         use events::MailboxEvent::*;
 
         match event {
-            Connected => panic!(),
+            Connected => self.unexpected("Connected"),
             Lost => (Some(State::S0A), events![], QueueCtrl::NoAction),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
             Close(_) => (
                 Some(State::S4B),
                 events![T_MailboxDone],
@@ -155,7 +170,7 @@ impl Mailbox {
                     QueueCtrl::Drain,
                 )
             }
-            GotMessage => panic!(),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(phase, body) => {
                 let mut v = vec![];
                 v.push((phase, body));
@@ -183,16 +198,16 @@ impl Mailbox {
                     QueueCtrl::Drain,
                 )
             }
-            Lost => panic!(),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
             Close(_) => (
                 Some(State::S4A),
                 events![T_MailboxDone],
                 QueueCtrl::NoAction,
             ),
-            GotMailbox(_) => panic!(),
-            GotMessage => panic!(),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(phase, body) => {
                 let mut v = vec![];
                 v.push((phase, body));
@@ -224,16 +239,16 @@ impl Mailbox {
                     QueueCtrl::Drain,
                 )
             }
-            Lost => panic!(),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
             Close(mood) => (
                 Some(State::S3A(mailbox.to_string(), mood)),
                 events![],
                 QueueCtrl::NoAction,
             ),
-            GotMailbox(_) => panic!(),
-            GotMessage => panic!(),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(phase, body) => {
                 let mut v = vec![];
                 v.push((phase, body));
@@ -254,7 +269,7 @@ impl Mailbox {
         use events::MailboxEvent::*;
 
         match event {
-            Connected => panic!(),
+            Connected => self.unexpected("Connected"),
             Lost => (
                 Some(State::S2A(mailbox.to_string())),
                 events![],
@@ -290,14 +305,14 @@ impl Mailbox {
                     )
                 }
             }
-            RxClosed => panic!(),
+            RxClosed => self.unexpected("RxClosed"),
             Close(mood) => (
                 Some(State::S3B(mailbox.to_string(), mood.to_string())),
-                events![RC_TxClose],
+                events![RC_TxClose(mailbox.to_string(), mood)],
                 QueueCtrl::NoAction,
             ),
-            GotMailbox(_) => panic!(),
-            GotMessage => panic!(),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(phase, body) => {
                 // queue
                 let mut v = vec![];
@@ -323,16 +338,16 @@ impl Mailbox {
         match event {
             Connected => (
                 Some(State::S3B(mailbox.to_string(), mood.to_string())),
-                events![RC_TxClose],
+                events![RC_TxClose(mailbox.to_string(), mood.to_string())],
                 QueueCtrl::NoAction,
             ),
-            Lost => panic!(),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
-            Close(_) => panic!(),
-            GotMailbox(_) => panic!(),
-            GotMessage => panic!(),
-            AddMessage(_, _) => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
+            Close(_) => self.unexpected("Close"),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
+            AddMessage(_, _) => self.unexpected("AddMessage"),
         }
     }
 
@@ -345,7 +360,7 @@ impl Mailbox {
         use events::MailboxEvent::*;
 
         match event {
-            Connected => panic!(),
+            Connected => self.unexpected("Connected"),
             Lost => (
                 Some(State::S3A(mailbox.to_string(), mood.to_string())),
                 events![],
@@ -369,8 +384,8 @@ impl Mailbox {
                 events![],
                 QueueCtrl::NoAction,
             ),
-            GotMailbox(_) => panic!(),
-            GotMessage => panic!(),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(_, _) => (
                 Some(State::S3B(mailbox.to_string(), mood.to_string())),
                 events![],
@@ -387,13 +402,13 @@ impl Mailbox {
 
         match event {
             Connected => (Some(State::S4B), events![], QueueCtrl::NoAction),
-            Lost => panic!(),
-            RxMessage(_, _, _) => panic!(),
-            RxClosed => panic!(),
-            Close(String) => panic!(),
-            GotMailbox(String) => panic!(),
-            GotMessage => panic!(),
-            AddMessage(_, _) => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxMessage(_, _, _) => self.unexpected("RxMessage"),
+            RxClosed => self.unexpected("RxClosed"),
+            Close(_) => self.unexpected("Close"),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
+            AddMessage(_, _) => self.unexpected("AddMessage"),
         }
     }
 
@@ -404,18 +419,27 @@ impl Mailbox {
         use events::MailboxEvent::*;
 
         match event {
-            Connected => panic!(),
+            Connected => self.unexpected("Connected"),
             Lost => (Some(State::S4B), events![], QueueCtrl::NoAction),
             RxMessage(side, phase, body) => {
                 (Some(State::S4B), events![], QueueCtrl::NoAction)
             }
-            RxClosed => panic!(),
+            RxClosed => self.unexpected("RxClosed"),
             Close(_) => (Some(State::S4B), events![], QueueCtrl::NoAction),
-            GotMailbox(String) => panic!(),
-            GotMessage => panic!(),
+            GotMailbox(_) => self.unexpected("GotMailbox"),
+            GotMessage => self.unexpected("GotMessage"),
             AddMessage(_, _) => {
                 (Some(State::S4B), events![], QueueCtrl::NoAction)
             }
         }
     }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events, QueueCtrl) {
+        let e = format!("mailbox: {} in state {:?}", event, self.state);
+        (
+            None,
+            events![B_Error(WormholeError::BadTransition(e))],
+            QueueCtrl::NoAction,
+        )
+    }
 }
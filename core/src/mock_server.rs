@@ -0,0 +1,831 @@
+// An in-memory Mailbox Server, plus a harness that wires WormholeCores up to
+// it, so tests can run whole conversations between two cores without any
+// sockets or timers. The server speaks the same JSON as the real one (see
+// server_messages.rs), but keeps everything in memory and never expires
+// anything. The harness only fires timers when asked to, so a test decides
+// exactly when a lost connection comes back.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use serde_json::{self, Value};
+use api::{APIAction, Action, IOAction, IOEvent, TimerHandle, WSHandle};
+use server_messages::Message;
+use WormholeCore;
+
+pub struct MockServer {
+    connections: HashMap<usize, Connection>,
+    next_connection: usize,
+    apps: HashMap<String, App>,
+    next_mailbox: u32,
+    next_message: u32,
+}
+
+struct Connection {
+    // set by "bind"
+    appid: Option<String>,
+    side: Option<String>,
+    // set by "open"
+    mailbox: Option<String>,
+    outbound: VecDeque<String>,
+}
+
+#[derive(Default)]
+struct App {
+    nameplates: HashMap<String, NameplateRecord>,
+    mailboxes: HashMap<String, MailboxRecord>,
+}
+
+struct NameplateRecord {
+    mailbox: String,
+    sides: HashSet<String>,
+}
+
+#[derive(Default)]
+struct MailboxRecord {
+    sides: HashSet<String>,
+    messages: Vec<Value>,
+}
+
+impl MockServer {
+    pub fn new() -> MockServer {
+        MockServer {
+            connections: HashMap::new(),
+            next_connection: 0,
+            apps: HashMap::new(),
+            next_mailbox: 0,
+            next_message: 0,
+        }
+    }
+
+    // a new client connection, which is welcomed right away
+    pub fn connect(&mut self) -> usize {
+        self.next_connection += 1;
+        let c = self.next_connection;
+        self.connections.insert(
+            c,
+            Connection {
+                appid: None,
+                side: None,
+                mailbox: None,
+                outbound: VecDeque::new(),
+            },
+        );
+        self.send(c, json!({"type": "welcome", "welcome": {}}));
+        c
+    }
+
+    // anything still waiting to be delivered is lost
+    pub fn disconnect(&mut self, c: usize) {
+        self.connections.remove(&c);
+    }
+
+    // everything we've said to this connection since last time
+    pub fn outbound(&mut self, c: usize) -> Vec<String> {
+        match self.connections.get_mut(&c) {
+            Some(connection) => connection.outbound.drain(..).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn receive(&mut self, c: usize, text: &str) {
+        let orig: Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => return self.error(c, &e.to_string(), Value::Null),
+        };
+        // like the real server, we ack everything before looking at it
+        self.send(c, json!({"type": "ack", "id": orig["id"].clone()}));
+        let m: Message = match serde_json::from_value(orig.clone()) {
+            Ok(m) => m,
+            Err(e) => return self.error(c, &e.to_string(), orig),
+        };
+        let result = match m {
+            Message::Bind { appid, side } => self.bind(c, appid, side),
+            Message::Ping { ping } => {
+                self.send(c, json!({"type": "pong", "pong": ping}));
+                Ok(())
+            }
+            m => match self.bound(c) {
+                Some((appid, side)) => self.dispatch(c, &appid, &side, m),
+                None => Err("must bind first".to_string()),
+            },
+        };
+        if let Err(e) = result {
+            self.error(c, &e, orig);
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        m: Message,
+    ) -> Result<(), String> {
+        match m {
+            Message::List {} => self.list(c, appid),
+            Message::Allocate {} => self.allocate(c, appid, side),
+            Message::Claim { nameplate } => {
+                self.claim(c, appid, side, &nameplate)
+            }
+            Message::Release { nameplate } => {
+                self.release(c, appid, side, &nameplate)
+            }
+            Message::Open { mailbox } => self.open(c, appid, side, &mailbox),
+            Message::Add { phase, body } => {
+                self.add(c, appid, side, &phase, &body)
+            }
+            Message::Close { mailbox, .. } => {
+                self.close(c, appid, side, &mailbox)
+            }
+            _ => Err("unknown type".to_string()),
+        }
+    }
+
+    fn bound(&self, c: usize) -> Option<(String, String)> {
+        let connection = &self.connections[&c];
+        match (&connection.appid, &connection.side) {
+            (&Some(ref appid), &Some(ref side)) => {
+                Some((appid.clone(), side.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn bind(
+        &mut self,
+        c: usize,
+        appid: String,
+        side: String,
+    ) -> Result<(), String> {
+        let connection = self.connections.get_mut(&c).unwrap();
+        if connection.appid.is_some() {
+            return Err("already bound".to_string());
+        }
+        connection.appid = Some(appid);
+        connection.side = Some(side);
+        Ok(())
+    }
+
+    fn list(&mut self, c: usize, appid: &str) -> Result<(), String> {
+        let mut ids: Vec<&String> = match self.apps.get(appid) {
+            Some(app) => app.nameplates.keys().collect(),
+            None => vec![],
+        };
+        ids.sort();
+        let nameplates: Vec<Value> =
+            ids.iter().map(|id| json!({ "id": id })).collect();
+        self.send(c, json!({"type": "nameplates", "nameplates": nameplates}));
+        Ok(())
+    }
+
+    fn allocate(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+    ) -> Result<(), String> {
+        // the lowest number that isn't in use
+        let nameplate = {
+            let app = self.apps.entry(appid.to_string()).or_default();
+            (1..)
+                .map(|n: u32| n.to_string())
+                .find(|n| !app.nameplates.contains_key(n))
+                .unwrap()
+        };
+        // allocation implies a claim
+        self.claim_nameplate(appid, side, &nameplate)?;
+        self.send(c, json!({"type": "allocated", "nameplate": nameplate}));
+        Ok(())
+    }
+
+    fn claim(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        nameplate: &str,
+    ) -> Result<(), String> {
+        let mailbox = self.claim_nameplate(appid, side, nameplate)?;
+        self.send(c, json!({"type": "claimed", "mailbox": mailbox}));
+        Ok(())
+    }
+
+    fn claim_nameplate(
+        &mut self,
+        appid: &str,
+        side: &str,
+        nameplate: &str,
+    ) -> Result<String, String> {
+        let next_mailbox = &mut self.next_mailbox;
+        let app = self.apps.entry(appid.to_string()).or_default();
+        let record = app.nameplates
+            .entry(nameplate.to_string())
+            .or_insert_with(|| {
+                *next_mailbox += 1;
+                NameplateRecord {
+                    mailbox: format!("mailbox{}", next_mailbox),
+                    sides: HashSet::new(),
+                }
+            });
+        if !record.sides.contains(side) && record.sides.len() >= 2 {
+            return Err("crowded".to_string());
+        }
+        record.sides.insert(side.to_string());
+        Ok(record.mailbox.clone())
+    }
+
+    fn release(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        nameplate: &str,
+    ) -> Result<(), String> {
+        let app = self.apps.entry(appid.to_string()).or_default();
+        let empty = match app.nameplates.get_mut(nameplate) {
+            Some(record) => {
+                record.sides.remove(side);
+                record.sides.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            app.nameplates.remove(nameplate);
+        }
+        self.send(c, json!({"type": "released"}));
+        Ok(())
+    }
+
+    fn open(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        mailbox: &str,
+    ) -> Result<(), String> {
+        if self.connections[&c].mailbox.is_some() {
+            return Err("only one open per connection".to_string());
+        }
+        let messages = {
+            let app = self.apps.entry(appid.to_string()).or_default();
+            let record = app.mailboxes.entry(mailbox.to_string()).or_default();
+            record.sides.insert(side.to_string());
+            record.messages.clone()
+        };
+        self.connections.get_mut(&c).unwrap().mailbox =
+            Some(mailbox.to_string());
+        // everything that was added before we opened it
+        for m in messages {
+            self.send(c, m);
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        phase: &str,
+        body: &str,
+    ) -> Result<(), String> {
+        let mailbox = match self.connections[&c].mailbox {
+            Some(ref mailbox) => mailbox.clone(),
+            None => return Err("must open mailbox before adding".to_string()),
+        };
+        self.next_message += 1;
+        let m = json!({
+            "type": "message",
+            "side": side,
+            "phase": phase,
+            "body": body,
+            "id": self.next_message.to_string(),
+        });
+        self.apps
+            .entry(appid.to_string())
+            .or_default()
+            .mailboxes
+            .entry(mailbox.clone())
+            .or_default()
+            .messages
+            .push(m.clone());
+        // to everybody who has this mailbox open, including the sender
+        let listeners: Vec<usize> = self.connections
+            .iter()
+            .filter(|&(_, connection)| {
+                connection.appid.as_ref().map(|a| a.as_str()) == Some(appid)
+                    && connection.mailbox.as_ref() == Some(&mailbox)
+            })
+            .map(|(&c, _)| c)
+            .collect();
+        for listener in listeners {
+            self.send(listener, m.clone());
+        }
+        Ok(())
+    }
+
+    fn close(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        mailbox: &str,
+    ) -> Result<(), String> {
+        let app = self.apps.entry(appid.to_string()).or_default();
+        let empty = match app.mailboxes.get_mut(mailbox) {
+            Some(record) => {
+                record.sides.remove(side);
+                record.sides.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            app.mailboxes.remove(mailbox);
+        }
+        self.connections.get_mut(&c).unwrap().mailbox = None;
+        self.send(c, json!({"type": "closed"}));
+        Ok(())
+    }
+
+    fn error(&mut self, c: usize, error: &str, orig: Value) {
+        self.send(c, json!({"type": "error", "error": error, "orig": orig}));
+    }
+
+    fn send(&mut self, c: usize, mut m: Value) {
+        m["server_tx"] = json!(0.0);
+        if let Some(connection) = self.connections.get_mut(&c) {
+            connection.outbound.push_back(m.to_string());
+        }
+    }
+}
+
+pub struct Client {
+    pub core: WormholeCore,
+    // everything the core has delivered to the application so far
+    pub actions: Vec<APIAction>,
+    io: VecDeque<IOAction>,
+    // the current connection, both as the core and as the server know it
+    connection: Option<(WSHandle, usize)>,
+    timers: Vec<TimerHandle>,
+}
+
+impl Client {
+    fn queue(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::IO(io) => self.io.push_back(io),
+                Action::API(api) => self.actions.push(api),
+            }
+        }
+    }
+
+    pub fn code(&self) -> Option<String> {
+        self.actions
+            .iter()
+            .filter_map(|a| match *a {
+                APIAction::GotCode(ref code) => Some(code.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    pub fn verifier(&self) -> Option<Vec<u8>> {
+        self.actions
+            .iter()
+            .filter_map(|a| match *a {
+                APIAction::GotVerifier(ref verifier) => Some(verifier.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.actions
+            .iter()
+            .filter_map(|a| match *a {
+                APIAction::GotMessage(ref message) => Some(message.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub struct Harness {
+    pub server: MockServer,
+    pub clients: Vec<Client>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        Harness {
+            server: MockServer::new(),
+            clients: Vec::new(),
+        }
+    }
+
+    // starts the core, and returns its index in clients
+    pub fn add(&mut self, mut core: WormholeCore) -> usize {
+        let actions = core.start();
+        let mut client = Client {
+            core: core,
+            actions: Vec::new(),
+            io: VecDeque::new(),
+            connection: None,
+            timers: Vec::new(),
+        };
+        client.queue(actions);
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    pub fn api(&mut self, i: usize, event: ::api::APIEvent) {
+        let actions = self.clients[i].core.do_api(event);
+        self.clients[i].queue(actions);
+    }
+
+    // deliver everything that's waiting, until nothing more happens
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    // Perform each client's IO actions, then deliver whatever the server
+    // said to them. Returns false if there was nothing to do.
+    pub fn step(&mut self) -> bool {
+        let mut progress = false;
+        for i in 0..self.clients.len() {
+            while let Some(io) = self.clients[i].io.pop_front() {
+                progress = true;
+                self.perform(i, io);
+            }
+        }
+        for i in 0..self.clients.len() {
+            if let Some((wsh, c)) = self.clients[i].connection {
+                for m in self.server.outbound(c) {
+                    progress = true;
+                    self.feed(i, IOEvent::WebSocketMessageReceived(wsh, m));
+                }
+            }
+        }
+        progress
+    }
+
+    // The client is saved, goes away, and comes back as a restored core.
+    // Like a killed app, it doesn't get to close its connection first.
+    pub fn restart(&mut self, i: usize) {
+        let saved = self.clients[i].core.serialize().unwrap();
+        if let Some((_, c)) = self.clients[i].connection.take() {
+            self.server.disconnect(c);
+        }
+        self.clients[i].io.clear();
+        self.clients[i].timers.clear();
+        let mut core = WormholeCore::restore(&saved).unwrap();
+        let actions = core.start();
+        self.clients[i].core = core;
+        self.clients[i].queue(actions);
+    }
+
+    // the connection drops (without the client asking for it)
+    pub fn disconnect(&mut self, i: usize) {
+        if let Some((wsh, c)) = self.clients[i].connection.take() {
+            self.server.disconnect(c);
+            self.feed(i, IOEvent::WebSocketConnectionLost(wsh));
+        }
+    }
+
+    // everything the client is waiting for (e.g. to reconnect) happens now
+    pub fn fire_timers(&mut self, i: usize) {
+        // timers it has asked for but we haven't started yet count too
+        while let Some(io) = self.clients[i].io.pop_front() {
+            self.perform(i, io);
+        }
+        let timers: Vec<TimerHandle> =
+            self.clients[i].timers.drain(..).collect();
+        for th in timers {
+            self.feed(i, IOEvent::TimerExpired(th));
+        }
+    }
+
+    fn feed(&mut self, i: usize, event: IOEvent) {
+        let actions = self.clients[i].core.do_io(event);
+        self.clients[i].queue(actions);
+    }
+
+    fn perform(&mut self, i: usize, io: IOAction) {
+        match io {
+            IOAction::StartTimer(th, _) => self.clients[i].timers.push(th),
+            IOAction::CancelTimer(th) => {
+                self.clients[i].timers.retain(|&t| t != th)
+            }
+            IOAction::WebSocketOpen(wsh, _) => {
+                let c = self.server.connect();
+                self.clients[i].connection = Some((wsh, c));
+                self.feed(i, IOEvent::WebSocketConnectionMade(wsh));
+            }
+            IOAction::WebSocketSendMessage(wsh, m) => {
+                match self.clients[i].connection {
+                    Some((current, c)) if current == wsh => {
+                        self.server.receive(c, &m)
+                    }
+                    // like a real socket, a dead one swallows messages
+                    _ => {}
+                }
+            }
+            IOAction::WebSocketClose(wsh) => {
+                match self.clients[i].connection {
+                    Some((current, _)) if current == wsh => self.disconnect(i),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Harness;
+    use api::{APIAction, APIEvent, Mood, WormholeError};
+    use std::time::Instant;
+    use {WormholeCore, WormholeCoreBuilder};
+
+    fn core(side: &str, app_versions: ::serde_json::Value) -> WormholeCore {
+        // nothing here depends on the time, or on chance
+        let start = Instant::now();
+        WormholeCoreBuilder::new("appid", "url")
+            .side(side)
+            .keepalive(None)
+            .app_versions(app_versions)
+            .clock(Box::new(move || start))
+            .random(Box::new(|| 0.5))
+            .build()
+    }
+
+    fn closed(h: &Harness, i: usize) -> Option<Mood> {
+        h.clients[i]
+            .actions
+            .iter()
+            .filter_map(|a| match *a {
+                APIAction::GotClosed(mood) => Some(mood),
+                _ => None,
+            })
+            .next()
+    }
+
+    #[test]
+    fn builder_code_length() {
+        let mut h = Harness::new();
+        let a = h.add(
+            WormholeCoreBuilder::new("appid", "url")
+                .keepalive(None)
+                .code_length(3)
+                .build(),
+        );
+        h.api(a, APIEvent::AllocateCode(None, None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+        assert_eq!(code.split('-').count(), 4);
+    }
+
+    #[test]
+    fn two_cores() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({"from": "a"})));
+        let b = h.add(core("sideB", json!({"from": "b"})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+        assert!(code.starts_with("1-"));
+
+        h.api(b, APIEvent::SetCode(code));
+        h.api(a, APIEvent::Send(b"hello".to_vec()));
+        h.run();
+        let verifier = h.clients[a].verifier().unwrap();
+        assert_eq!(h.clients[b].verifier(), Some(verifier));
+        assert!(
+            h.clients[a]
+                .actions
+                .contains(&APIAction::GotVersions(json!({"from": "b"})))
+        );
+        assert!(
+            h.clients[b]
+                .actions
+                .contains(&APIAction::GotVersions(json!({"from": "a"})))
+        );
+        assert_eq!(h.clients[b].messages(), vec![b"hello".to_vec()]);
+
+        h.api(b, APIEvent::Send(b"hi back".to_vec()));
+        h.run();
+        assert_eq!(h.clients[a].messages(), vec![b"hi back".to_vec()]);
+
+        h.api(a, APIEvent::Close);
+        h.api(b, APIEvent::Close);
+        h.run();
+        assert_eq!(closed(&h, a), Some(Mood::Happy));
+        assert_eq!(closed(&h, b), Some(Mood::Happy));
+    }
+
+    #[test]
+    fn reconnect() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+
+        // A drops out before B shows up, and misses nothing
+        h.disconnect(a);
+        h.api(b, APIEvent::SetCode(code));
+        h.api(b, APIEvent::Send(b"one".to_vec()));
+        h.run();
+        assert_eq!(h.clients[a].verifier(), None);
+        h.fire_timers(a);
+        h.run();
+        assert_eq!(h.clients[a].messages(), vec![b"one".to_vec()]);
+
+        // A's message is sent while it's disconnected, and B drops out
+        // while the reply is on its way
+        h.disconnect(a);
+        h.api(a, APIEvent::Send(b"two".to_vec()));
+        h.fire_timers(a);
+        h.step();
+        h.disconnect(b);
+        h.run();
+        h.fire_timers(b);
+        h.run();
+        assert_eq!(h.clients[b].messages(), vec![b"two".to_vec()]);
+        // and nobody saw anything twice
+        assert_eq!(h.clients[a].messages(), vec![b"one".to_vec()]);
+        assert_eq!(h.clients[a].verifier(), h.clients[b].verifier());
+
+        h.api(a, APIEvent::Close);
+        h.api(b, APIEvent::Close);
+        h.run();
+        assert_eq!(closed(&h, a), Some(Mood::Happy));
+        assert_eq!(closed(&h, b), Some(Mood::Happy));
+    }
+
+    #[test]
+    fn reconnect_with_unacked_add() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        h.api(b, APIEvent::SetCode(h.clients[a].code().unwrap()));
+        h.run();
+
+        // the add never reaches the server, so it's still unacked when the
+        // connection comes back, and must follow the open again
+        h.api(b, APIEvent::Send(b"hello".to_vec()));
+        h.disconnect(b);
+        h.fire_timers(b);
+        h.run();
+        assert_eq!(h.clients[a].messages(), vec![b"hello".to_vec()]);
+        for &i in &[a, b] {
+            assert!(!h.clients[i].actions.iter().any(|a| match *a {
+                APIAction::GotError(_) => true,
+                _ => false,
+            }));
+        }
+
+        h.api(a, APIEvent::Close);
+        h.api(b, APIEvent::Close);
+        h.run();
+        assert_eq!(closed(&h, a), Some(Mood::Happy));
+        assert_eq!(closed(&h, b), Some(Mood::Happy));
+    }
+
+    #[test]
+    fn reconnect_with_unacked_open() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        h.api(b, APIEvent::SetCode(h.clients[a].code().unwrap()));
+        h.step();
+
+        // B's open (and PAKE) reach the server, but the acks don't make it
+        // back. Opening the mailbox twice on the new connection would be an
+        // error, so it must only be sent once.
+        while let Some(io) = h.clients[b].io.pop_front() {
+            h.perform(b, io);
+        }
+        h.disconnect(b);
+        h.fire_timers(b);
+        h.run();
+        for &i in &[a, b] {
+            assert!(!h.clients[i].actions.iter().any(|a| match *a {
+                APIAction::GotError(_) => true,
+                _ => false,
+            }));
+        }
+        assert!(h.clients[a].verifier().is_some());
+        assert_eq!(h.clients[a].verifier(), h.clients[b].verifier());
+
+        h.api(b, APIEvent::Send(b"hello".to_vec()));
+        h.run();
+        assert_eq!(h.clients[a].messages(), vec![b"hello".to_vec()]);
+        h.api(a, APIEvent::Close);
+        h.api(b, APIEvent::Close);
+        h.run();
+        assert_eq!(closed(&h, a), Some(Mood::Happy));
+        assert_eq!(closed(&h, b), Some(Mood::Happy));
+    }
+
+    #[test]
+    fn restart_after_key_exchange() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+
+        // A has sent its PAKE and is waiting for B's, so it can't be saved
+        assert_eq!(
+            h.clients[a].core.serialize().unwrap_err(),
+            WormholeError::KeyExchangeInProgress
+        );
+        h.api(b, APIEvent::SetCode(code));
+        h.run();
+        assert!(h.clients[a].verifier().is_some());
+
+        // now both can, and carry on in the same mailbox after a restart
+        h.restart(a);
+        h.restart(b);
+        h.run();
+        h.api(b, APIEvent::Send(b"hello".to_vec()));
+        h.run();
+        assert_eq!(h.clients[a].messages(), vec![b"hello".to_vec()]);
+
+        h.api(a, APIEvent::Close);
+        h.api(b, APIEvent::Close);
+        h.run();
+        assert_eq!(closed(&h, a), Some(Mood::Happy));
+        assert_eq!(closed(&h, b), Some(Mood::Happy));
+    }
+
+    #[test]
+    fn restart_with_unsent_message() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+        h.api(b, APIEvent::SetCode(code));
+        h.run();
+
+        // the message is still waiting for a connection when A is killed
+        h.disconnect(a);
+        h.api(a, APIEvent::Send(b"hello".to_vec()));
+        h.restart(a);
+        h.run();
+        assert_eq!(h.clients[b].messages(), vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn input_code_with_wordlist() {
+        use wordlist::AlternatingWordlist;
+
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        let code = h.clients[a].code().unwrap();
+
+        // once B has claimed the nameplate, it completes from its own words
+        let words = vec!["apple".to_string(), "avocado".to_string()];
+        let wordlist = AlternatingWordlist::new(vec![words]);
+        h.api(b, APIEvent::InputCode(Some(Box::new(wordlist))));
+        h.run();
+        let nameplate = code.splitn(2, '-').next().unwrap().to_string();
+        h.api(b, APIEvent::InputHelperChooseNameplate(nameplate));
+        h.run();
+        assert_eq!(
+            h.clients[b].core.input_helper_get_word_completions("a"),
+            Ok(vec!["apple-".to_string(), "avocado-".to_string()])
+        );
+    }
+
+    #[test]
+    fn wrong_code() {
+        let mut h = Harness::new();
+        let a = h.add(core("sideA", json!({})));
+        let b = h.add(core("sideB", json!({})));
+        h.api(a, APIEvent::AllocateCode(Some(2), None));
+        h.run();
+        h.api(b, APIEvent::SetCode("1-wrong-code".to_string()));
+        h.run();
+        for &i in &[a, b] {
+            assert!(
+                h.clients[i]
+                    .actions
+                    .contains(&APIAction::GotError(WormholeError::WrongCode))
+            );
+            assert_eq!(h.clients[i].verifier(), None);
+            assert_eq!(closed(&h, i), Some(Mood::Scary));
+        }
+    }
+}
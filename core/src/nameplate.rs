@@ -1,15 +1,19 @@
-use events::Events;
+use std::sync::Arc;
+use serde::{Serialize, Serializer};
+use events::{Events, Wordlist};
+use wordlist::{default_wordlist, deserialize_wordlist, serialize_wordlist};
+use api::WormholeError;
 // we process these
 use events::NameplateEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
 use events::RendezvousEvent::{TxClaim as RC_TxClaim, TxRelease as RC_TxRelease};
 use events::TerminatorEvent::NameplateDone as T_NameplateDone;
 use events::InputEvent::GotWordlist as I_GotWordlist;
 use events::MailboxEvent::GotMailbox as M_GotMailbox;
 
 // all -A states are not-connected, while -B states are yes-connected
-// B states serialize as A, so we wake up disconnected
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     // S0: we know nothing
     S0A,
@@ -29,13 +33,38 @@ enum State {
     S5,
 }
 
+// B states serialize as A, so we wake up disconnected
+fn serialize_state<S>(state: &State, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use self::State::*;
+    let disconnected = match *state {
+        S0B => S0A,
+        S2B(ref nameplate) => S2A(nameplate.clone()),
+        S3B(ref nameplate) => S3A(nameplate.clone()),
+        S4B(ref nameplate) => S4A(nameplate.clone()),
+        ref s => return s.serialize(serializer),
+    };
+    disconnected.serialize(serializer)
+}
+
+#[derive(Serialize, Deserialize)]
 pub(crate) struct Nameplate {
+    #[serde(serialize_with = "serialize_state")]
     state: State,
+    // the Input machine completes words from this, once we've claimed
+    #[serde(serialize_with = "serialize_wordlist",
+            deserialize_with = "deserialize_wordlist")]
+    wordlist: Arc<Wordlist>,
 }
 
 impl Nameplate {
     pub fn new() -> Nameplate {
-        Nameplate { state: State::S0A }
+        Nameplate {
+            state: State::S0A,
+            wordlist: Arc::new(default_wordlist()),
+        }
     }
 
     pub fn process(&mut self, event: NameplateEvent) -> Events {
@@ -51,7 +80,6 @@ impl Nameplate {
             S4A(ref nameplate) => self.do_S4A(&nameplate, event),
             S4B(ref nameplate) => self.do_S4B(&nameplate, event),
             S5 => self.do_S5(event),
-            _ => panic!(),
         };
         match newstate {
             Some(s) => {
@@ -62,39 +90,41 @@ impl Nameplate {
         actions
     }
 
-    fn do_S0A(&self, event: NameplateEvent) -> (Option<State>, Events) {
+    fn do_S0A(&mut self, event: NameplateEvent) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (Some(State::S0B), events![]),
-            Lost => panic!(),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => {
+            Lost => self.unexpected("Lost"),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(nameplate, wordlist) => {
                 // TODO: validate_nameplate(nameplate)
+                self.wordlist = wordlist;
                 (Some(State::S1A(nameplate.to_string())), events![])
             }
-            Release => panic!(),
+            Release => self.unexpected("Release"),
             Close => (Some(State::S5), events![T_NameplateDone]),
         }
     }
 
-    fn do_S0B(&self, event: NameplateEvent) -> (Option<State>, Events) {
+    fn do_S0B(&mut self, event: NameplateEvent) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
-            Connected => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Connected => self.unexpected("Connected"),
             Lost => (Some(State::S0A), events![]),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => {
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(nameplate, wordlist) => {
                 // TODO: validate_nameplate(nameplate)
+                self.wordlist = wordlist;
                 (
                     Some(State::S2B(nameplate.to_string())),
                     events![RC_TxClaim(nameplate.to_string())],
                 )
             }
-            Release => panic!(),
+            Release => self.unexpected("Release"),
             Close => (Some(State::S5), events![T_NameplateDone]),
         }
     }
@@ -106,16 +136,16 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (
                 Some(State::S2B(nameplate.to_string())),
                 events![RC_TxClaim(nameplate.to_string())],
             ),
-            Lost => panic!(),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
-            Release => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
+            Release => self.unexpected("Release"),
             Close => (Some(State::S5), events![T_NameplateDone]),
         }
     }
@@ -127,16 +157,16 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (
                 Some(State::S2B(nameplate.to_string())),
                 events![RC_TxClaim(nameplate.to_string())],
             ),
-            Lost => panic!(),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
-            Release => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
+            Release => self.unexpected("Release"),
             Close => (Some(State::S4A(nameplate.to_string())), events![]),
         }
     }
@@ -148,19 +178,19 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
-            Connected => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Connected => self.unexpected("Connected"),
             Lost => (Some(State::S2A(nameplate.to_string())), events![]),
             RxClaimed(mailbox) => (
                 Some(State::S3B(nameplate.to_string())),
                 events![
-                    I_GotWordlist, // TODO: ->wordlist
+                    I_GotWordlist(Arc::clone(&self.wordlist)),
                     M_GotMailbox(mailbox)
                 ],
             ),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
-            Release => panic!(),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
+            Release => self.unexpected("Release"),
             Close => (
                 Some(State::S4B(nameplate.to_string())),
                 events![RC_TxRelease(nameplate.to_string())],
@@ -175,13 +205,13 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (Some(State::S3B(nameplate.to_string())), events![]),
-            Lost => panic!(),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
-            Release => panic!(),
+            Lost => self.unexpected("Lost"),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
+            Release => self.unexpected("Release"),
             Close => (Some(State::S4A(nameplate.to_string())), events![]),
         }
     }
@@ -193,12 +223,12 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
-            Connected => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Connected => self.unexpected("Connected"),
             Lost => (Some(State::S3A(nameplate.to_string())), events![]),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
             Release => (
                 Some(State::S4B(nameplate.to_string())),
                 events![RC_TxRelease(nameplate.to_string())],
@@ -217,16 +247,16 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (
                 Some(State::S4B(nameplate.to_string())),
                 events![RC_TxRelease(nameplate.to_string())],
             ),
             Lost => (None, events![]),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
-            Release => panic!(),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
+            Release => self.unexpected("Release"),
             Close => (None, events![]),
         }
     }
@@ -238,7 +268,7 @@ impl Nameplate {
     ) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (
                 Some(State::S4B(nameplate.to_string())),
                 events![RC_TxRelease(nameplate.to_string())],
@@ -246,7 +276,7 @@ impl Nameplate {
             Lost => (Some(State::S4A(nameplate.to_string())), events![]),
             RxClaimed(_mailbox) => (None, events![]),
             RxReleased => (Some(State::S5), events![T_NameplateDone]),
-            SetNameplate(nameplate) => panic!(),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
             Release => (None, events![]),
             Close => (None, events![]),
         }
@@ -255,14 +285,19 @@ impl Nameplate {
     fn do_S5(&self, event: NameplateEvent) -> (Option<State>, Events) {
         use events::NameplateEvent::*;
         match event {
-            NameplateDone => panic!(),
+            NameplateDone => self.unexpected("NameplateDone"),
             Connected => (None, events![]),
             Lost => (None, events![]),
-            RxClaimed(_mailbox) => panic!(),
-            RxReleased => panic!(),
-            SetNameplate(nameplate) => panic!(),
+            RxClaimed(_mailbox) => self.unexpected("RxClaimed"),
+            RxReleased => self.unexpected("RxReleased"),
+            SetNameplate(_, _) => self.unexpected("SetNameplate"),
             Release => (None, events![]),
             Close => (None, events![]),
         }
     }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("nameplate: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
 }
@@ -5,12 +5,13 @@ use events::OrderEvent;
 use events::ReceiveEvent::GotMessage as R_GotMessage;
 use events::KeyEvent::GotPake as K_GotPake;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     S0, //no pake
     S1, //yes pake
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Order {
     state: State,
     queue: Vec<(String, String, Vec<u8>)>,
@@ -33,11 +34,6 @@ impl Order {
     pub fn process(&mut self, event: OrderEvent) -> Events {
         use self::State::*;
 
-        println!(
-            "order: current state = {:?}, got event = {:?}",
-            self.state, event
-        );
-
         let (newstate, actions, queue_status) = match self.state {
             S0 => self.do_S0(event),
             S1 => self.do_S1(event),
@@ -89,7 +85,6 @@ impl Order {
                     )
                 }
             }
-            _ => panic!(),
         }
     }
 
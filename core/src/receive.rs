@@ -1,15 +1,16 @@
 use key::Key;
 use std::str;
+use api::WormholeError;
 use events::Events;
 // we process these
 use events::ReceiveEvent;
 // we emit these
-use events::BossEvent::{GotMessage as B_GotMessage,
+use events::BossEvent::{Error as B_Error, GotMessage as B_GotMessage,
                         GotVerifier as B_GotVerifier, Happy as B_Happy,
                         Scared as B_Scared};
 use events::SendEvent::GotVerifiedKey as S_GotVerifiedKey;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     S0_unknown_key,
     S1_unverified_key(Vec<u8>),
@@ -17,6 +18,7 @@ enum State {
     S3_scared,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Receive {
     state: State,
 }
@@ -31,11 +33,6 @@ impl Receive {
     pub fn process(&mut self, event: ReceiveEvent) -> Events {
         use self::State::*;
 
-        println!(
-            "receive: current state = {:?}, got event = {:?}",
-            self.state, event
-        );
-
         let (newstate, actions) = match self.state {
             S0_unknown_key => self.do_S0_unknown_key(event),
             S1_unverified_key(ref key) => self.do_S1_unverified_key(key, event),
@@ -50,7 +47,10 @@ impl Receive {
     fn do_S0_unknown_key(&self, event: ReceiveEvent) -> (State, Events) {
         use events::ReceiveEvent::*;
         match event {
-            GotMessage(side, phase, body) => panic!(),
+            // Order holds messages back until the key is known
+            GotMessage(_, _, _) => {
+                (State::S0_unknown_key, self.unexpected("GotMessage"))
+            }
             GotKey(key) => (State::S1_unverified_key(key), events![]),
         }
     }
@@ -73,7 +73,10 @@ impl Receive {
     ) -> (State, Events) {
         use events::ReceiveEvent::*;
         match event {
-            GotKey(_) => panic!(),
+            GotKey(_) => (
+                State::S1_unverified_key(key.to_vec()),
+                self.unexpected("GotKey"),
+            ),
             GotMessage(side, phase, body) => {
                 match Self::derive_key_and_decrypt(&side, &key, &phase, body) {
                     Some(plaintext) => {
@@ -106,7 +109,10 @@ impl Receive {
     ) -> (State, Events) {
         use events::ReceiveEvent::*;
         match event {
-            GotKey(_) => panic!(),
+            GotKey(_) => (
+                State::S2_verified_key(key.to_vec()),
+                self.unexpected("GotKey"),
+            ),
             GotMessage(side, phase, body) => {
                 match Self::derive_key_and_decrypt(&side, &key, &phase, body) {
                     Some(plaintext) => {
@@ -128,8 +134,14 @@ impl Receive {
     fn do_S3_scared(&self, event: ReceiveEvent) -> (State, Events) {
         use events::ReceiveEvent::*;
         match event {
-            GotKey(_) => panic!(),
+            GotKey(_) => (State::S3_scared, self.unexpected("GotKey")),
             GotMessage(_, _, _) => (State::S3_scared, events![]),
         }
     }
+
+    fn unexpected(&self, event: &str) -> Events {
+        // our states hold secrets (the code or the key), so leave them out
+        let e = format!("receive: unexpected {}", event);
+        events![B_Error(WormholeError::BadTransition(e))]
+    }
 }
@@ -9,18 +9,31 @@
 
 extern crate hex;
 
+use std::time::Instant;
 use serde_json;
-use api::{TimerHandle, WSHandle};
+use rand::{thread_rng, Rng};
+use util;
+use api::{APIAction, Clock, Random, TimerHandle, WSHandle, WormholeError};
 use events::Events;
-use server_messages::{add, bind, claim, deserialize, open, Message};
+use server_messages::{add, allocate, bind, claim, close, list, open, ping,
+                      release, Message, OutboundMessage};
 // we process these
 use events::RendezvousEvent;
 use api::IOEvent;
 // we emit these
 use api::IOAction;
-use events::NameplateEvent::{Connected as N_Connected,
-                             RxClaimed as N_RxClaimed};
-use events::MailboxEvent::{Connected as M_Connected, RxMessage as M_RxMessage};
+use events::NameplateEvent::{Connected as N_Connected, Lost as N_Lost,
+                             RxClaimed as N_RxClaimed,
+                             RxReleased as N_RxReleased};
+use events::MailboxEvent::{Connected as M_Connected, Lost as M_Lost,
+                           RxClosed as M_RxClosed, RxMessage as M_RxMessage};
+use events::TerminatorEvent::Stopped as T_Stopped;
+use events::BossEvent::{Error as B_Error, RxError as B_RxError,
+                        RxWelcome as B_RxWelcome};
+use events::AllocatorEvent::{Connected as A_Connected, Lost as A_Lost,
+                             RxAllocated as A_RxAllocated};
+use events::ListerEvent::{Connected as L_Connected, Lost as L_Lost,
+                          RxNameplates as L_RxNameplates};
 use events::RendezvousEvent::TxBind as RC_TxBind; // loops around
 
 #[derive(Debug, PartialEq)]
@@ -33,16 +46,82 @@ enum State {
     Stopped,
 }
 
-#[derive(Debug)]
+impl Default for State {
+    fn default() -> State {
+        State::Idle
+    }
+}
+
+// Only our configuration is saved: a restored Rendezvous is Idle, and
+// reconnects when it is started.
+#[derive(Serialize, Deserialize)]
 pub struct Rendezvous {
     appid: String,
     relay_url: String,
     side: String,
+    // after a failed connection attempt, we wait retry_timer seconds, then
+    // twice that, and so on (up to max_retry_timer), giving up completely
+    // after max_attempts failures in a row (if set)
     retry_timer: f32,
+    max_retry_timer: f32,
+    max_attempts: Option<u32>,
+    // while connected, we ping the server every keepalive seconds (if set),
+    // so that idle connections aren't dropped by proxies. If the previous
+    // ping hasn't been answered by then, the connection is presumed dead.
+    keepalive: Option<f32>,
+    #[serde(skip, default = "system_clock")]
+    clock: Clock,
+    #[serde(skip, default = "thread_random")]
+    random: Random,
+    #[serde(skip)]
     state: State,
+    #[serde(skip)]
     connected_at_least_once: bool,
+    #[serde(skip)]
+    failed_attempts: u32,
+    // every connection and timer gets a new handle, so we can ignore events
+    // about old ones
+    #[serde(skip)]
+    last_handle: u32,
+    #[serde(skip, default = "no_handle")]
     wsh: WSHandle,
+    #[serde(skip)]
     reconnect_timer: Option<TimerHandle>,
+    #[serde(skip)]
+    keepalive_timer: Option<TimerHandle>,
+    #[serde(skip)]
+    last_ping: u32,
+    // the ping we're waiting to hear a pong for, and when we sent it
+    #[serde(skip)]
+    outstanding_ping: Option<(u32, Instant)>,
+    // messages the server hasn't acknowledged yet, with their ids and when
+    // we sent them. Those still here when we lose the connection are sent
+    // again (in the same order) right after the next bind.
+    #[serde(skip)]
+    unacked: Vec<(String, Message, Instant)>,
+    // the mailbox we last opened: the server forgets it along with the
+    // connection, and an add or close can't be sent again without it
+    #[serde(skip)]
+    mailbox: Option<String>,
+    // what we've sent again since reconnecting. The machines ask for most
+    // of it again when they hear about the connection, and each of those
+    // requests is dropped (once) rather than sent twice.
+    #[serde(skip)]
+    resent: Vec<Message>,
+}
+
+// handle 0 is never used for a real connection, so this stands in when we
+// don't have one (before start(), or after giving up on a connection)
+fn no_handle() -> WSHandle {
+    WSHandle::new(0)
+}
+
+pub fn system_clock() -> Clock {
+    Box::new(Instant::now)
+}
+
+pub fn thread_random() -> Random {
+    Box::new(|| thread_rng().gen())
 }
 
 impl Rendezvous {
@@ -51,25 +130,54 @@ impl Rendezvous {
         relay_url: &str,
         side: &str,
         retry_timer: f32,
+        max_retry_timer: f32,
+        max_attempts: Option<u32>,
+        keepalive: Option<f32>,
+        clock: Clock,
+        random: Random,
     ) -> Rendezvous {
-        // we use a handle here just in case we need to open multiple
-        // connections in the future. For now we ignore it, but the IO layer
-        // is supposed to pass this back in websocket_* messages
-        let wsh = WSHandle::new(1);
         Rendezvous {
             appid: appid.to_string(),
             relay_url: relay_url.to_string(),
             side: side.to_string(),
             retry_timer: retry_timer,
+            max_retry_timer: max_retry_timer,
+            max_attempts: max_attempts,
+            keepalive: keepalive,
+            clock: clock,
+            random: random,
             state: State::Idle,
             connected_at_least_once: false,
-            wsh: wsh,
+            failed_attempts: 0,
+            last_handle: 0,
+            wsh: no_handle(),
             reconnect_timer: None,
+            keepalive_timer: None,
+            last_ping: 0,
+            outstanding_ping: None,
+            unacked: Vec::new(),
+            mailbox: None,
+            resent: Vec::new(),
         }
     }
 
     pub fn process_io(&mut self, event: IOEvent) -> Events {
         use api::IOEvent::*;
+        // The IO layer might still tell us about a connection (or timer)
+        // that we've since given up on. Those must not be mistaken for the
+        // current one.
+        let current = match event {
+            WebSocketConnectionMade(wsh)
+            | WebSocketMessageReceived(wsh, _)
+            | WebSocketConnectionLost(wsh) => wsh == self.wsh,
+            TimerExpired(th) => {
+                Some(th) == self.reconnect_timer
+                    || Some(th) == self.keepalive_timer
+            }
+        };
+        if !current {
+            return events![];
+        }
         match event {
             WebSocketConnectionMade(wsh) => self.connection_made(wsh),
             WebSocketMessageReceived(wsh, message) => {
@@ -82,18 +190,20 @@ impl Rendezvous {
 
     pub fn process(&mut self, e: RendezvousEvent) -> Events {
         use events::RendezvousEvent::*;
-        println!("rendezvous: {:?}", e);
         match e {
             Start => self.start(),
-            TxBind(appid, side) => self.send(bind(&appid, &side)),
-            TxOpen(mailbox) => self.send(open(&mailbox)),
+            TxBind(appid, side) => self.bind(&appid, &side),
+            TxOpen(mailbox) => {
+                self.mailbox = Some(mailbox.clone());
+                self.send(open(&mailbox))
+            }
             TxAdd(phase, body) => self.send(add(&phase, &body)),
-            TxClose => events![],
+            TxClose(mailbox, mood) => self.send(close(&mailbox, &mood)),
             Stop => self.stop(),
             TxClaim(nameplate) => self.send(claim(&nameplate)),
-            TxRelease(_nameplate) => events![],
-            TxAllocate => events![],
-            TxList => events![],
+            TxRelease(nameplate) => self.send(release(&nameplate)),
+            TxAllocate => self.send(allocate()),
+            TxList => self.send(list()),
         }
     }
 
@@ -104,13 +214,10 @@ impl Rendezvous {
         let actions;
         let newstate = match self.state {
             State::Idle => {
-                actions = events![
-                    IOAction::WebSocketOpen(self.wsh, self.relay_url.clone())
-                ];
-                //"url".to_string());
+                actions = self.open();
                 State::Connecting
             }
-            _ => panic!("bad transition from {:?}", self),
+            _ => return self.unexpected("Start"),
         };
         self.state = newstate;
         actions
@@ -120,26 +227,57 @@ impl Rendezvous {
         // TODO: assert handle == self.handle
         let (actions, newstate) = match self.state {
             State::Connecting => {
+                self.connected_at_least_once = true;
+                self.failed_attempts = 0;
                 // TODO: does the order of this matter? if so, oh boy.
-                let a = events![
+                let mut a = events![
                     RC_TxBind(self.appid.to_string(), self.side.to_string()),
                     N_Connected,
-                    M_Connected
+                    M_Connected,
+                    A_Connected,
+                    L_Connected
                 ];
-                //actions.push(A_Connected);
-                //actions.push(L_Connected);
+                a.append(&mut self.start_keepalive());
                 (a, State::Connected)
             }
-            _ => panic!("bad transition from {:?}", self),
+            // we were stopped while it was still connecting, so the IO
+            // layer may not have been able to close it yet
+            State::Disconnecting => (
+                events![IOAction::WebSocketClose(self.wsh)],
+                State::Disconnecting,
+            ),
+            _ => return self.unexpected("WebSocketConnectionMade"),
         };
         self.state = newstate;
         actions
     }
 
     fn message_received(&mut self, _handle: WSHandle, message: &str) -> Events {
-        println!("msg is {:?}", message);
-        let m = deserialize(message);
+        if self.state == State::Disconnecting {
+            // the machines are all done with the server, so whatever else
+            // it has to say goes away with the connection
+            return events![IOAction::WebSocketClose(self.wsh)];
+        }
+        let m: Message = match serde_json::from_str(message) {
+            Ok(m) => m,
+            Err(e) => return malformed(format!("{}: {}", e, message)),
+        };
         match m {
+            Message::Welcome { welcome, .. } => {
+                // an unparseable welcome is treated like an empty one
+                let welcome = welcome
+                    .map(|w| serde_json::to_value(w).unwrap())
+                    .unwrap_or(json!({}));
+                events![B_RxWelcome(welcome)]
+            }
+            Message::Error { error, .. } => events![B_RxError(error)],
+            Message::Allocated { nameplate } => {
+                events![A_RxAllocated(nameplate)]
+            }
+            Message::Nameplates { nameplates } => {
+                let ids = nameplates.into_iter().map(|n| n.id).collect();
+                events![L_RxNameplates(ids)]
+            }
             Message::Claimed { mailbox } => {
                 events![N_RxClaimed(mailbox.to_string())]
             }
@@ -148,7 +286,14 @@ impl Rendezvous {
                 phase,
                 body,
                 //id,
-            } => events![M_RxMessage(side, phase, hex::decode(body).unwrap())],
+            } => match hex::decode(&body) {
+                Ok(body) => events![M_RxMessage(side, phase, body)],
+                Err(e) => malformed(format!("{}: {}", e, body)),
+            },
+            Message::Released {} => events![N_RxReleased],
+            Message::Closed {} => events![M_RxClosed],
+            Message::Ack { id, .. } => self.ack_received(id),
+            Message::Pong { pong } => self.pong_received(pong),
             _ => events![], // TODO
         }
     }
@@ -156,31 +301,34 @@ impl Rendezvous {
     fn connection_lost(&mut self, _handle: WSHandle) -> Events {
         // TODO: assert handle == self.handle
         let (actions, newstate) = match self.state {
-            State::Connecting | State::Connected => {
-                let new_handle = TimerHandle::new(2);
-                self.reconnect_timer = Some(new_handle);
-                (
-                    events![IOAction::StartTimer(new_handle, self.retry_timer)],
-                    State::Waiting,
-                )
+            State::Connecting => {
+                self.failed_attempts += 1;
+                match self.max_attempts {
+                    Some(max) if self.failed_attempts >= max => {
+                        let e = WormholeError::ConnectionFailed(max);
+                        (events![B_Error(e)], State::Stopped)
+                    }
+                    _ => (self.start_timer(), State::Waiting),
+                }
             }
-            State::Disconnecting => (events![], State::Stopped),
-            _ => panic!("bad transition from {:?}", self),
+            State::Connected => (self.lost(), State::Waiting),
+            State::Disconnecting => (events![T_Stopped], State::Stopped),
+            _ => return self.unexpected("WebSocketConnectionLost"),
         };
         self.state = newstate;
         actions
     }
 
-    fn timer_expired(&mut self, _handle: TimerHandle) -> Events {
-        // TODO: assert handle == self.handle
+    fn timer_expired(&mut self, handle: TimerHandle) -> Events {
+        if Some(handle) == self.keepalive_timer {
+            return self.keepalive_expired();
+        }
         let (actions, newstate) = match self.state {
             State::Waiting => {
-                let new_handle = WSHandle::new(2);
-                let open =
-                    IOAction::WebSocketOpen(new_handle, self.relay_url.clone());
-                (events![open], State::Connecting)
+                self.reconnect_timer = None;
+                (self.open(), State::Connecting)
             }
-            _ => panic!("bad transition from {:?}", self),
+            _ => return self.unexpected("TimerExpired"),
         };
         self.state = newstate;
         actions
@@ -188,15 +336,24 @@ impl Rendezvous {
 
     fn stop(&mut self) -> Events {
         let (actions, newstate) = match self.state {
-            State::Idle | State::Stopped => (events![], State::Stopped),
-            State::Connecting | State::Connected => {
+            State::Idle => (events![T_Stopped], State::Stopped),
+            State::Stopped => (events![], State::Stopped),
+            State::Connecting => {
                 let close = IOAction::WebSocketClose(self.wsh);
                 (events![close], State::Disconnecting)
             }
+            State::Connected => {
+                let mut actions = self.stop_keepalive();
+                actions.push(IOAction::WebSocketClose(self.wsh));
+                (actions, State::Disconnecting)
+            }
             State::Waiting => {
-                let cancel =
-                    IOAction::CancelTimer(self.reconnect_timer.unwrap());
-                (events![cancel], State::Stopped)
+                let mut actions = match self.reconnect_timer.take() {
+                    Some(th) => events![IOAction::CancelTimer(th)],
+                    None => events![],
+                };
+                actions.push(T_Stopped);
+                (actions, State::Stopped)
             }
             State::Disconnecting => (events![], State::Disconnecting),
         };
@@ -204,30 +361,220 @@ impl Rendezvous {
         actions
     }
 
+    // the connection went away (or we gave up on it): tell the machines
+    // that saw Connected, and try again later
+    fn lost(&mut self) -> Events {
+        let mut actions = self.stop_keepalive();
+        // anything we sent again and the machines haven't asked for yet is
+        // still unacked, so it'll be sent again after the next bind
+        self.resent.clear();
+        actions.append(&mut events![N_Lost, M_Lost, A_Lost, L_Lost]);
+        actions.append(&mut self.start_timer());
+        actions
+    }
+
+    fn start_keepalive(&mut self) -> Events {
+        match self.keepalive {
+            Some(interval) => {
+                let th = TimerHandle::new(self.next_handle());
+                self.keepalive_timer = Some(th);
+                events![IOAction::StartTimer(th, interval)]
+            }
+            None => events![],
+        }
+    }
+
+    fn stop_keepalive(&mut self) -> Events {
+        self.outstanding_ping = None;
+        match self.keepalive_timer.take() {
+            Some(th) => events![IOAction::CancelTimer(th)],
+            None => events![],
+        }
+    }
+
+    fn keepalive_expired(&mut self) -> Events {
+        self.keepalive_timer = None;
+        if self.outstanding_ping.is_some() {
+            // the server never answered: drop this connection and forget
+            // about it, so anything else the IO layer tells us about it is
+            // ignored
+            let mut actions = events![IOAction::WebSocketClose(self.wsh)];
+            self.wsh = no_handle();
+            actions.append(&mut self.lost());
+            self.state = State::Waiting;
+            return actions;
+        }
+        self.last_ping += 1;
+        self.outstanding_ping = Some((self.last_ping, (self.clock)()));
+        let mut actions = self.send(ping(self.last_ping));
+        actions.append(&mut self.start_keepalive());
+        actions
+    }
+
+    fn pong_received(&mut self, pong: u32) -> Events {
+        match self.outstanding_ping {
+            Some((ping, sent)) if ping == pong => {
+                self.outstanding_ping = None;
+                let rtt = (self.clock)().duration_since(sent);
+                events![APIAction::GotRoundTrip("ping".to_string(), rtt)]
+            }
+            // a pong for a ping we've given up on, or didn't send
+            _ => events![],
+        }
+    }
+
+    fn ack_received(&mut self, id: Option<String>) -> Events {
+        let found = id.and_then(|id| {
+            self.unacked.iter().position(|&(ref i, _, _)| *i == id)
+        });
+        match found {
+            Some(i) => {
+                let (_, m, sent) = self.unacked.remove(i);
+                let rtt = (self.clock)().duration_since(sent);
+                events![APIAction::GotRoundTrip(m.kind(), rtt)]
+            }
+            // pings aren't tracked (the pong tells us what we want to know)
+            None => events![],
+        }
+    }
+
+    // the first thing on every connection, followed by whatever the last one
+    // didn't get to acknowledge
+    fn bind(&mut self, appid: &str, side: &str) -> Events {
+        let unacked: Vec<Message> = self
+            .unacked
+            .drain(..)
+            .map(|(_, m, _)| m)
+            .filter(|m| match *m {
+                Message::Bind { .. } => false,
+                _ => true,
+            })
+            .collect();
+        let mut resend = vec![];
+        let opened = unacked.iter().any(|m| match *m {
+            Message::Open { .. } => true,
+            _ => false,
+        });
+        let needs_open = unacked.iter().any(|m| match *m {
+            Message::Add { .. } | Message::Close { .. } => true,
+            _ => false,
+        });
+        if needs_open && !opened {
+            if let Some(ref mailbox) = self.mailbox {
+                resend.push(open(mailbox));
+            }
+        }
+        resend.extend(unacked);
+        let mut actions = self.send(bind(appid, side));
+        for m in &resend {
+            actions.append(&mut self.send(m.clone()));
+        }
+        self.resent = resend;
+        actions
+    }
+
     fn send(&mut self, m: Message) -> Events {
-        // TODO: add 'id' (a random string, used to correlate 'ack' responses
-        // for timing-graph instrumentation)
+        if let Some(i) = self.resent.iter().position(|r| *r == m) {
+            // a machine catching up after the reconnect: we've already
+            // sent this one again
+            self.resent.remove(i);
+            return events![];
+        }
+        let id = util::random_id();
+        let outbound = OutboundMessage {
+            id: id.clone(),
+            message: m.clone(),
+        };
         let s = IOAction::WebSocketSendMessage(
             self.wsh,
-            serde_json::to_string(&m).unwrap(),
+            serde_json::to_string(&outbound).unwrap(),
         );
+        match m {
+            Message::Ping { .. } => {}
+            m => self.unacked.push((id, m, (self.clock)())),
+        }
         events![s]
     }
+
+    fn next_handle(&mut self) -> u32 {
+        self.last_handle += 1;
+        self.last_handle
+    }
+
+    fn open(&mut self) -> Events {
+        self.wsh = WSHandle::new(self.next_handle());
+        events![IOAction::WebSocketOpen(self.wsh, self.relay_url.clone())]
+    }
+
+    fn start_timer(&mut self) -> Events {
+        let th = TimerHandle::new(self.next_handle());
+        self.reconnect_timer = Some(th);
+        events![IOAction::StartTimer(th, self.retry_delay())]
+    }
+
+    // exponential backoff, with some jitter so that a whole crowd of clients
+    // doesn't come back at the same moment after a server restart
+    fn retry_delay(&mut self) -> f32 {
+        // the first retry (whether the connection failed or was lost) waits
+        // for the base delay
+        let exponent = self.failed_attempts.saturating_sub(1).min(16) as i32;
+        let delay = self.retry_timer * 2f32.powi(exponent);
+        let jitter = 0.8 + 0.4 * (self.random)();
+        (delay * jitter).min(self.max_retry_timer)
+    }
+
+    fn unexpected(&self, event: &str) -> Events {
+        let e = format!("rendezvous: {} in state {:?}", event, self.state);
+        events![B_Error(WormholeError::BadTransition(e))]
+    }
+}
+
+fn malformed(e: String) -> Events {
+    events![B_Error(WormholeError::MalformedMessage(e))]
 }
 
 #[cfg(test)]
 mod test {
+    use serde_json::{self, Value};
     use server_messages::{deserialize, Message};
     use api::{TimerHandle, WSHandle};
-    use events::Event::{Nameplate, Rendezvous, API, IO};
-    use api::IOAction;
-    use api::IOEvent;
+    use events::Event::{Nameplate, Rendezvous, Terminator, API, IO};
+    use api::{APIAction, IOAction, IOEvent};
     use events::RendezvousEvent::{Stop as RC_Stop, TxBind as RC_TxBind};
-    use events::NameplateEvent::Connected as N_Connected;
+    use events::NameplateEvent::{Connected as N_Connected, Lost as N_Lost};
+    use events::TerminatorEvent::Stopped as T_Stopped;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use api::Clock;
+
+    // The clock only moves when the test moves it, and the randomness always
+    // lands in the middle, so there's no jitter.
+    fn rendezvous(
+        max_retry_timer: f32,
+        max_attempts: Option<u32>,
+        keepalive: Option<f32>,
+    ) -> (super::Rendezvous, Arc<Mutex<Duration>>) {
+        let start = Instant::now();
+        let elapsed = Arc::new(Mutex::new(Duration::from_secs(0)));
+        let now = Arc::clone(&elapsed);
+        let clock: Clock = Box::new(move || start + *now.lock().unwrap());
+        let r = super::Rendezvous::new(
+            "appid",
+            "url",
+            "side1",
+            5.0,
+            max_retry_timer,
+            max_attempts,
+            keepalive,
+            clock,
+            Box::new(|| 0.5),
+        );
+        (r, elapsed)
+    }
 
     #[test]
     fn create() {
-        let mut r = super::Rendezvous::new("appid", "url", "side1", 5.0);
+        let (mut r, _) = rendezvous(60.0, None, None);
 
         let wsh: WSHandle;
         let th: TimerHandle;
@@ -254,8 +601,9 @@ mod test {
         actions = r.process_io(IOEvent::WebSocketConnectionMade(wsh)).events;
         // it should tell itself to send a BIND
         // then it should notify several other machines
-        // at this point, we have BIND, N_Connected, M_Connected
-        assert_eq!(actions.len(), 3);
+        // at this point, we have BIND, N_Connected, M_Connected, A_Connected,
+        // L_Connected
+        assert_eq!(actions.len(), 5);
         let e = actions.remove(0);
         println!("e is {:?}", e);
         let b;
@@ -301,7 +649,8 @@ mod test {
         }
 
         actions = r.process_io(IOEvent::WebSocketConnectionLost(wsh)).events;
-        assert_eq!(actions.len(), 1);
+        // N_Lost, M_Lost, A_Lost, L_Lost, then the reconnect timer
+        assert_eq!(actions.len(), 5);
         let e = actions.pop().unwrap();
         match e {
             IO(IOAction::StartTimer(handle, duration)) => {
@@ -317,26 +666,283 @@ mod test {
         let wsh2;
         match e {
             IO(IOAction::WebSocketOpen(wsh0, url0)) => {
-                // TODO: should be a different handle, once implemented
+                assert_ne!(wsh0, wsh);
                 wsh2 = wsh0;
                 assert_eq!(url0, "url");
             }
             _ => panic!(),
         }
 
+        // news about the old connection is ignored
+        actions = r.process_io(IOEvent::WebSocketConnectionLost(wsh)).events;
+        assert_eq!(actions, vec![]);
+        actions = r.process_io(IOEvent::TimerExpired(th)).events;
+        assert_eq!(actions, vec![]);
+
         actions = r.process(RC_Stop).events;
         // we were Connecting, so we should see a close and then wait for
         // disconnect
         assert_eq!(actions.len(), 1);
         let e = actions.pop().unwrap();
         match e {
-            IO(IOAction::WebSocketClose(_wsh0)) => {
-                //assert_eq!(wsh0, wsh2);
+            IO(IOAction::WebSocketClose(wsh0)) => {
+                assert_eq!(wsh0, wsh2);
             }
             _ => panic!(),
         }
 
         actions = r.process_io(IOEvent::WebSocketConnectionLost(wsh2)).events;
-        assert_eq!(actions.len(), 0);
+        assert_eq!(actions, vec![Terminator(T_Stopped)]);
+    }
+
+    #[test]
+    fn backoff() {
+        use super::WormholeError;
+        use events::BossEvent;
+        use events::Event::Boss;
+
+        let (mut r, _) = rendezvous(12.0, Some(4), None);
+        let mut wsh = match r.start().events[..] {
+            [IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+            _ => panic!(),
+        };
+        // each failure waits longer, up to the maximum
+        let mut delays = vec![];
+        for _ in 0..3 {
+            let th = match r
+                .process_io(IOEvent::WebSocketConnectionLost(wsh))
+                .events[..]
+            {
+                [IO(IOAction::StartTimer(th, delay))] => {
+                    delays.push(delay);
+                    th
+                }
+                _ => panic!(),
+            };
+            wsh = match r.process_io(IOEvent::TimerExpired(th)).events[..] {
+                [IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+                _ => panic!(),
+            };
+        }
+        assert_eq!(delays, vec![5.0, 10.0, 12.0]);
+        // and then we give up
+        assert_eq!(
+            r.process_io(IOEvent::WebSocketConnectionLost(wsh)).events,
+            vec![Boss(BossEvent::Error(WormholeError::ConnectionFailed(4)))]
+        );
+    }
+
+    #[test]
+    fn keepalive() {
+        let (mut r, elapsed) = rendezvous(60.0, None, Some(30.0));
+        let wsh = match r.start().events[..] {
+            [IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+            _ => panic!(),
+        };
+        let mut actions =
+            r.process_io(IOEvent::WebSocketConnectionMade(wsh)).events;
+        let mut th = match actions.pop() {
+            Some(IO(IOAction::StartTimer(th, delay))) => {
+                assert_eq!(delay, 30.0);
+                th
+            }
+            _ => panic!(),
+        };
+
+        // each tick sends a ping, and the pong tells us the round-trip time
+        for expected in 1..3 {
+            actions = r.process_io(IOEvent::TimerExpired(th)).events;
+            assert_eq!(actions.len(), 2);
+            match actions[0] {
+                IO(IOAction::WebSocketSendMessage(wsh0, ref m)) => {
+                    assert_eq!(wsh0, wsh);
+                    match deserialize(m) {
+                        Message::Ping { ping } => assert_eq!(ping, expected),
+                        _ => panic!(),
+                    }
+                }
+                _ => panic!(),
+            }
+            th = match actions[1] {
+                IO(IOAction::StartTimer(th, _)) => th,
+                _ => panic!(),
+            };
+            *elapsed.lock().unwrap() += Duration::from_millis(250);
+            let pong = format!(r#"{{"type": "pong", "pong": {}}}"#, expected);
+            let event = IOEvent::WebSocketMessageReceived(wsh, pong);
+            actions = r.process_io(event).events;
+            let rtt = Duration::from_millis(250);
+            assert_eq!(
+                actions,
+                vec![API(APIAction::GotRoundTrip("ping".to_string(), rtt))]
+            );
+        }
+
+        // a ping that goes unanswered means the connection is dead
+        actions = r.process_io(IOEvent::TimerExpired(th)).events;
+        th = match actions[1] {
+            IO(IOAction::StartTimer(th, _)) => th,
+            _ => panic!(),
+        };
+        actions = r.process_io(IOEvent::TimerExpired(th)).events;
+        assert_eq!(actions[0], IO(IOAction::WebSocketClose(wsh)));
+        assert_eq!(actions[1], Nameplate(N_Lost));
+        match actions[actions.len() - 1] {
+            IO(IOAction::StartTimer(th0, delay)) => {
+                assert_ne!(th0, th);
+                assert_eq!(delay, 5.0);
+            }
+            _ => panic!(),
+        }
+        // and we don't care when it finally goes away
+        actions = r.process_io(IOEvent::WebSocketConnectionLost(wsh)).events;
+        assert_eq!(actions, vec![]);
+    }
+
+    #[test]
+    fn acks() {
+        use events::RendezvousEvent::{TxAdd as RC_TxAdd,
+                                      TxClaim as RC_TxClaim,
+                                      TxList as RC_TxList,
+                                      TxOpen as RC_TxOpen};
+        use events::Event;
+        use server_messages::{add, bind, list, open};
+
+        let (mut r, elapsed) = rendezvous(60.0, None, None);
+        let wsh = WSHandle::new(1);
+        r.start();
+        r.process_io(IOEvent::WebSocketConnectionMade(wsh));
+        r.process(RC_TxBind("appid".to_string(), "side1".to_string()));
+
+        // every message has an id, which the server echoes in its ack
+        let actions = r.process(RC_TxClaim("nameplate1".to_string())).events;
+        let id = match actions[..] {
+            [IO(IOAction::WebSocketSendMessage(_, ref m))] => {
+                let v: Value = serde_json::from_str(m).unwrap();
+                assert_eq!(v["type"], "claim");
+                v["id"].as_str().unwrap().to_string()
+            }
+            _ => panic!(),
+        };
+        *elapsed.lock().unwrap() += Duration::from_millis(100);
+        let ack = json!({"type": "ack", "id": id, "server_tx": 1.0});
+        let event = IOEvent::WebSocketMessageReceived(wsh, ack.to_string());
+        let rtt = Duration::from_millis(100);
+        assert_eq!(
+            r.process_io(event).events,
+            vec![API(APIAction::GotRoundTrip("claim".to_string(), rtt))]
+        );
+
+        let reconnect = |r: &mut super::Rendezvous, wsh| {
+            let actions =
+                r.process_io(IOEvent::WebSocketConnectionLost(wsh)).events;
+            let th = match actions[actions.len() - 1] {
+                IO(IOAction::StartTimer(th, _)) => th,
+                _ => panic!(),
+            };
+            let wsh = match r.process_io(IOEvent::TimerExpired(th)).events[..]
+            {
+                [IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+                _ => panic!(),
+            };
+            r.process_io(IOEvent::WebSocketConnectionMade(wsh));
+            wsh
+        };
+        let sent = |wsh, actions: Vec<Event>| -> Vec<Message> {
+            actions
+                .iter()
+                .map(|a| match *a {
+                    IO(IOAction::WebSocketSendMessage(w, ref m)) => {
+                        assert_eq!(w, wsh);
+                        deserialize(m)
+                    }
+                    _ => panic!(),
+                })
+                .collect()
+        };
+        let rebind = || RC_TxBind("appid".to_string(), "side1".to_string());
+
+        // the open is never acked, so it's sent again right after the bind
+        r.process(RC_TxOpen("mailbox1".to_string()));
+        let wsh2 = reconnect(&mut r, wsh);
+        let actions = r.process(rebind()).events;
+        let open_id = match actions[1] {
+            IO(IOAction::WebSocketSendMessage(_, ref m)) => {
+                let v: Value = serde_json::from_str(m).unwrap();
+                v["id"].as_str().unwrap().to_string()
+            }
+            _ => panic!(),
+        };
+        assert_eq!(
+            sent(wsh2, actions),
+            vec![bind("appid", "side1"), open("mailbox1")]
+        );
+        // so when the mailbox machine asks for it again, there's nothing to do
+        let actions = r.process(RC_TxOpen("mailbox1".to_string())).events;
+        assert_eq!(sent(wsh2, actions), vec![]);
+        // but asking twice for anything else means sending twice
+        let actions = r.process(RC_TxList).events;
+        assert_eq!(sent(wsh2, actions), vec![list()]);
+        let actions = r.process(RC_TxList).events;
+        assert_eq!(sent(wsh2, actions), vec![list()]);
+
+        // an add is sent again after the open, even if that was acked (the
+        // lists weren't, so they go too)
+        let ack = json!({"type": "ack", "id": open_id, "server_tx": 1.0});
+        r.process_io(IOEvent::WebSocketMessageReceived(wsh2, ack.to_string()));
+        r.process(RC_TxAdd("pake".to_string(), b"body".to_vec()));
+        let wsh3 = reconnect(&mut r, wsh2);
+        let actions = r.process(rebind()).events;
+        assert_eq!(
+            sent(wsh3, actions),
+            vec![
+                bind("appid", "side1"),
+                open("mailbox1"),
+                list(),
+                list(),
+                add("pake", b"body"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stopped_while_connecting() {
+        let (mut r, _) = rendezvous(60.0, None, None);
+        let wsh = match r.start().events[..] {
+            [IO(IOAction::WebSocketOpen(wsh, _))] => wsh,
+            _ => panic!(),
+        };
+        let close = vec![IO(IOAction::WebSocketClose(wsh))];
+        assert_eq!(r.process(RC_Stop).events, close);
+        // the connection turns up anyway, and is closed again (as is
+        // anything the server sends before it goes)
+        let event = IOEvent::WebSocketConnectionMade(wsh);
+        assert_eq!(r.process_io(event).events, close);
+        let welcome = r#"{"type": "welcome", "welcome": {}}"#.to_string();
+        let event = IOEvent::WebSocketMessageReceived(wsh, welcome);
+        assert_eq!(r.process_io(event).events, close);
+        let event = IOEvent::WebSocketConnectionLost(wsh);
+        assert_eq!(r.process_io(event).events, vec![Terminator(T_Stopped)]);
+    }
+
+    #[test]
+    fn malformed_message() {
+        use super::WormholeError;
+        use events::BossEvent;
+        use events::Event::Boss;
+
+        let (mut r, _) = rendezvous(60.0, None, None);
+        let wsh = WSHandle::new(1);
+        r.start();
+        r.process_io(IOEvent::WebSocketConnectionMade(wsh));
+        let actions = r.process_io(IOEvent::WebSocketMessageReceived(
+            wsh,
+            "not json".to_string(),
+        )).events;
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            Boss(BossEvent::Error(WormholeError::MalformedMessage(_))) => {}
+            _ => panic!(),
+        }
     }
 }
@@ -1,10 +1,13 @@
+use api::WormholeError;
 use events::Events;
 use key::Key;
 // we process these
 use events::SendEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
 use events::MailboxEvent::AddMessage as M_AddMessage;
 
+#[derive(Serialize, Deserialize)]
 pub struct Send {
     state: State,
     side: String,
@@ -12,7 +15,7 @@ pub struct Send {
     queue: Vec<(String, Vec<u8>)>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 enum State {
     S0,
     S1(Vec<u8>),
@@ -37,10 +40,6 @@ impl Send {
     pub fn process(&mut self, event: SendEvent) -> Events {
         use events::SendEvent::*;
 
-        println!(
-            "send: current state = {:?}, got event = {:?}",
-            self.state, event
-        );
         let (newstate, actions, queue_status) = match self.state {
             State::S0 => self.do_S0(event),
             State::S1(ref key) => self.do_S1(key.to_vec(), event),
@@ -106,7 +105,11 @@ impl Send {
     ) -> (State, Events, QueueStatus) {
         use events::SendEvent::*;
         match event {
-            GotVerifiedKey(_) => panic!(),
+            GotVerifiedKey(_) => (
+                State::S1(key),
+                self.unexpected("GotVerifiedKey"),
+                QueueStatus::NoAction,
+            ),
             Send(phase, plaintext) => {
                 let deliver_events =
                     self.deliver(key.clone(), phase, plaintext);
@@ -114,4 +117,10 @@ impl Send {
             }
         }
     }
+
+    fn unexpected(&self, event: &str) -> Events {
+        // our states hold secrets (the code or the key), so leave them out
+        let e = format!("send: unexpected {}", event);
+        events![B_Error(WormholeError::BadTransition(e))]
+    }
 }
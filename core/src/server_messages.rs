@@ -3,14 +3,22 @@ use serde_json;
 use serde::{self, Deserialize, Deserializer, Serializer};
 use util;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Nameplate {
-    id: String,
+    pub id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+// every field is optional: the server only sends the ones it has something
+// to say about
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct WelcomeMsg {
-    motd: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_cli_version: Option<String>,
+    // the server refuses to serve us (e.g. because our client is too old)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 // convert an optional field (which may result in deserialization error)
@@ -23,7 +31,7 @@ where
     Option::<T>::deserialize(de).or_else(|_| Ok(None))
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "type")]
 pub enum Message {
@@ -73,25 +81,61 @@ pub enum Message {
         mood: String,
     },
     Closed {},
-    Ack {},
+    // the server acknowledges every message as soon as it arrives, echoing
+    // its "id"
+    Ack {
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        server_tx: Option<f64>,
+    },
     Ping {
         ping: u32,
     },
     Pong {
         pong: u32,
     },
-    //Error { error: String, orig: Message },
+    // "orig" is the message that provoked the error, which may not be
+    // something we know how to parse
+    Error {
+        error: String,
+        orig: serde_json::Value,
+    },
 }
 
 // Client only sends: bind, list, allocate, claim, release, open, add, close,
 // ping
 
+// everything the client sends is wrapped in one of these, so that the
+// server's "ack" can tell us which message it is acknowledging
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct OutboundMessage {
+    pub id: String,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+impl Message {
+    // the "type" field, e.g. "claim"
+    pub fn kind(&self) -> String {
+        let v = serde_json::to_value(self).unwrap();
+        v["type"].as_str().unwrap().to_string()
+    }
+}
+
 pub fn bind(appid: &str, side: &str) -> Message {
     Message::Bind {
         appid: appid.to_string(),
         side: side.to_string(),
     }
 }
+pub fn nameplates(ids: &[&str]) -> Message {
+    Message::Nameplates {
+        nameplates: ids.iter()
+            .map(|id| Nameplate { id: id.to_string() })
+            .collect(),
+    }
+}
 pub fn list() -> Message {
     Message::List {}
 }
@@ -138,7 +182,9 @@ pub fn ping(ping: u32) -> Message {
 pub fn welcome(motd: &str, timestamp: f64) -> Message {
     Message::Welcome {
         welcome: Some(WelcomeMsg {
-            motd: motd.to_string(),
+            motd: Some(motd.to_string()),
+            current_cli_version: None,
+            error: None,
         }),
         server_tx: Some(timestamp),
     }
@@ -171,6 +217,13 @@ mod test {
         assert_eq!(m1, m2);
     }
 
+    #[test]
+    fn test_nameplates() {
+        let s = r#"{"type": "nameplates", "nameplates": [{"id": "1"}, {"id": "4"}]}"#;
+        let m = deserialize(&s);
+        assert_eq!(m, nameplates(&["1", "4"]));
+    }
+
     #[test]
     fn test_allocate() {
         let m1 = allocate();
@@ -227,6 +280,36 @@ mod test {
         assert_eq!(m1, m2);
     }
 
+    #[test]
+    fn test_outbound() {
+        let m1 = OutboundMessage {
+            id: "abcd".to_string(),
+            message: claim("nameplate1"),
+        };
+        let s = serde_json::to_string(&m1).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&s).unwrap();
+        assert_eq!(
+            v,
+            json!({"type": "claim", "id": "abcd", "nameplate": "nameplate1"})
+        );
+        // the server ignores the id, and so can we
+        assert_eq!(deserialize(&s), claim("nameplate1"));
+        assert_eq!(claim("nameplate1").kind(), "claim");
+    }
+
+    #[test]
+    fn test_ack_with_id() {
+        let s = r#"{"type": "ack", "id": "abcd", "server_tx": 1234.56}"#;
+        let m = deserialize(&s);
+        assert_eq!(
+            m,
+            Message::Ack {
+                id: Some("abcd".to_string()),
+                server_tx: Some(1234.56),
+            }
+        );
+    }
+
     #[test]
     fn test_welcome1() {
         let m1 = welcome("hi", 1234.56);
@@ -253,7 +336,11 @@ mod test {
                 server_tx: ts,
             } => {
                 match msg {
-                    None => (),
+                    Some(WelcomeMsg {
+                        motd: None,
+                        current_cli_version: None,
+                        error: None,
+                    }) => (),
                     _ => panic!(),
                 }
                 assert_eq!(ts, Some(1234.56));
@@ -272,7 +359,7 @@ mod test {
                 server_tx: ts,
             } => {
                 match msg {
-                    None => (),
+                    Some(WelcomeMsg { motd: None, .. }) => (),
                     _ => panic!(),
                 }
                 match ts {
@@ -298,8 +385,8 @@ mod test {
             } => {
                 match msg {
                     Some(wmsg) => match wmsg {
-                        WelcomeMsg { motd: msg_of_day } => {
-                            assert_eq!(msg_of_day, "hello world".to_string());
+                        WelcomeMsg { motd: msg_of_day, .. } => {
+                            assert_eq!(msg_of_day, Some("hello world".to_string()));
                         }
                         _ => panic!(),
                     },
@@ -316,12 +403,39 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_welcome_error() {
+        let s = r#"{"type": "welcome", "welcome": {"error": "go away"}}"#;
+        match deserialize(&s) {
+            Message::Welcome {
+                welcome: Some(w), ..
+            } => {
+                assert_eq!(w.error, Some("go away".to_string()));
+                assert_eq!(w.motd, None);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let s = r#"{"type": "error", "error": "nameplate crowded",
+                    "orig": {"type": "claim", "nameplate": "4"}}"#;
+        match deserialize(&s) {
+            Message::Error { error, orig } => {
+                assert_eq!(error, "nameplate crowded");
+                assert_eq!(orig["type"], "claim");
+            }
+            _ => panic!(),
+        }
+    }
+
     #[test]
     fn test_ack() {
         let s = r#"{"type": "ack", "id": null, "server_tx": 1234.56}"#;
         let m = deserialize(&s);
         match m {
-            Message::Ack {} => (),
+            Message::Ack { id: None, .. } => (),
             _ => panic!(),
         }
     }
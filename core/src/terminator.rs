@@ -1,22 +1,226 @@
+use api::Mood;
 use events::Events;
+use api::WormholeError;
 // we process these
 use events::TerminatorEvent;
 // we emit these
+use events::BossEvent::Error as B_Error;
+use events::NameplateEvent::Close as N_Close;
+use events::MailboxEvent::Close as M_Close;
+use events::RendezvousEvent::Stop as RC_Stop;
+use events::BossEvent::Closed as B_Closed;
 
-pub struct Terminator {}
+// "n" means the nameplate is still active (not yet released), "m" means the
+// mailbox is still active (not yet closed), and "o" means we're still open
+// (nobody has asked us to close). We can only stop the connection once both
+// the nameplate and the mailbox are done.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum State {
+    Snmo,
+    Smo,
+    Sno,
+    S0o,
+    Snm,
+    Sm,
+    Sn,
+    S_stopping,
+    S_stopped,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Terminator {
+    state: State,
+}
 
 impl Terminator {
     pub fn new() -> Terminator {
-        Terminator {}
+        Terminator { state: State::Snmo }
     }
 
     pub fn process(&mut self, event: TerminatorEvent) -> Events {
+        use self::State::*;
+        let (newstate, actions) = match self.state {
+            Snmo => self.do_Snmo(event),
+            Smo => self.do_Smo(event),
+            Sno => self.do_Sno(event),
+            S0o => self.do_S0o(event),
+            Snm => self.do_Snm(event),
+            Sm => self.do_Sm(event),
+            Sn => self.do_Sn(event),
+            S_stopping => self.do_S_stopping(event),
+            S_stopped => self.do_S_stopped(event),
+        };
+        match newstate {
+            Some(s) => {
+                self.state = s;
+            }
+            None => {}
+        }
+        actions
+    }
+
+    fn do_Snmo(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(mood) => (Some(State::Snm), close_both(mood)),
+            MailboxDone => (Some(State::Sno), events![]),
+            NameplateDone => (Some(State::Smo), events![]),
+            Stopped => self.unexpected("Stopped"),
+        }
+    }
+
+    fn do_Smo(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(mood) => (Some(State::Sm), close_both(mood)),
+            MailboxDone => (Some(State::S0o), events![]),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Stopped => self.unexpected("Stopped"),
+        }
+    }
+
+    fn do_Sno(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(mood) => (Some(State::Sn), close_both(mood)),
+            MailboxDone => self.unexpected("MailboxDone"),
+            NameplateDone => (Some(State::S0o), events![]),
+            Stopped => self.unexpected("Stopped"),
+        }
+    }
+
+    fn do_S0o(&self, event: TerminatorEvent) -> (Option<State>, Events) {
         use events::TerminatorEvent::*;
         match event {
-            Close(_mood) => events![],
-            MailboxDone => events![],
-            NameplateDone => events![],
-            Stopped => events![],
+            Close(mood) => {
+                // both are already done, so the mood won't go anywhere,
+                // but tell them anyways for symmetry
+                let mut actions = close_both(mood);
+                actions.push(RC_Stop);
+                (Some(State::S_stopping), actions)
+            }
+            MailboxDone => self.unexpected("MailboxDone"),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Stopped => self.unexpected("Stopped"),
         }
     }
+
+    // While we wait for the server, the connection can stop for good (the
+    // Rendezvous gave up on reconnecting). Nothing will be released or
+    // closed after that, so we're done.
+
+    fn do_Snm(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(_mood) => (None, events![]),
+            MailboxDone => (Some(State::Sn), events![]),
+            NameplateDone => (Some(State::Sm), events![]),
+            Stopped => (Some(State::S_stopped), events![B_Closed]),
+        }
+    }
+
+    fn do_Sm(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(_mood) => (None, events![]),
+            MailboxDone => (Some(State::S_stopping), events![RC_Stop]),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Stopped => (Some(State::S_stopped), events![B_Closed]),
+        }
+    }
+
+    fn do_Sn(&self, event: TerminatorEvent) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(_mood) => (None, events![]),
+            MailboxDone => self.unexpected("MailboxDone"),
+            NameplateDone => (Some(State::S_stopping), events![RC_Stop]),
+            Stopped => (Some(State::S_stopped), events![B_Closed]),
+        }
+    }
+
+    fn do_S_stopping(
+        &self,
+        event: TerminatorEvent,
+    ) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(_mood) => (None, events![]),
+            MailboxDone => self.unexpected("MailboxDone"),
+            NameplateDone => self.unexpected("NameplateDone"),
+            Stopped => (Some(State::S_stopped), events![B_Closed]),
+        }
+    }
+
+    fn do_S_stopped(
+        &self,
+        event: TerminatorEvent,
+    ) -> (Option<State>, Events) {
+        use events::TerminatorEvent::*;
+        match event {
+            Close(_mood) => (None, events![]),
+            // if we stopped early, the nameplate and mailbox can still give
+            // up on the server afterwards
+            MailboxDone => (None, events![]),
+            NameplateDone => (None, events![]),
+            Stopped => self.unexpected("Stopped"),
+        }
+    }
+
+    fn unexpected(&self, event: &str) -> (Option<State>, Events) {
+        let e = format!("terminator: {} in state {:?}", event, self.state);
+        (None, events![B_Error(WormholeError::BadTransition(e))])
+    }
+}
+
+fn close_both(mood: Mood) -> Events {
+    events![N_Close, M_Close(mood.to_protocol_string())]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::Mood;
+    use events::TerminatorEvent::*;
+    use events::{BossEvent, MailboxEvent, NameplateEvent, RendezvousEvent};
+
+    #[test]
+    fn close_after_nameplate_released() {
+        let mut t = Terminator::new();
+        assert_eq!(t.process(NameplateDone), events![]);
+        assert_eq!(
+            t.process(Close(Mood::Happy)),
+            events![
+                NameplateEvent::Close,
+                MailboxEvent::Close("happy".to_string())
+            ]
+        );
+        assert_eq!(t.process(MailboxDone), events![RendezvousEvent::Stop]);
+        assert_eq!(t.process(Stopped), events![BossEvent::Closed]);
+    }
+
+    #[test]
+    fn close_waits_for_both() {
+        let mut t = Terminator::new();
+        assert_eq!(
+            t.process(Close(Mood::Lonely)),
+            events![
+                NameplateEvent::Close,
+                MailboxEvent::Close("lonely".to_string())
+            ]
+        );
+        assert_eq!(t.process(MailboxDone), events![]);
+        assert_eq!(t.process(NameplateDone), events![RendezvousEvent::Stop]);
+        assert_eq!(t.process(Stopped), events![BossEvent::Closed]);
+    }
+
+    #[test]
+    fn connection_stops_while_closing() {
+        let mut t = Terminator::new();
+        t.process(Close(Mood::Error));
+        assert_eq!(t.process(Stopped), events![BossEvent::Closed]);
+        // and whatever finishes later is no longer interesting
+        assert_eq!(t.process(NameplateDone), events![]);
+        assert_eq!(t.process(MailboxDone), events![]);
+    }
 }
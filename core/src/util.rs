@@ -1,5 +1,6 @@
 use std;
 use std::str;
+use rand::{OsRng, Rng};
 
 // bytestring to hex representation of each byte as two characters.
 // so the resulting string's size is 2x the size of the input bytestring
@@ -9,6 +10,26 @@ pub fn bytes_to_hexstr(b: &[u8]) -> String {
     hexstr.join("")
 }
 
+// each session gets a new random side, so we can tell our own messages apart
+// from our peer's when they are echoed back by the server. The Python client
+// uses 5 random bytes, so we do too.
+pub fn random_side() -> String {
+    random_hexstr(5)
+}
+
+// message ids only need to be unique among the messages still waiting for
+// an ack, so (like the Python client) we use 2 random bytes
+pub fn random_id() -> String {
+    random_hexstr(2)
+}
+
+fn random_hexstr(num_bytes: usize) -> String {
+    let mut rng = OsRng::new().unwrap();
+    let mut bytes = vec![0u8; num_bytes];
+    rng.fill_bytes(&mut bytes);
+    bytes_to_hexstr(&bytes)
+}
+
 fn hex_to_char(s: &str) -> Result<char, std::num::ParseIntError> {
     u8::from_str_radix(s, 16).map(|n| n as char)
 }
@@ -38,6 +59,21 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_random_side() {
+        let side = random_side();
+        assert_eq!(side.len(), 10);
+        assert!(side.chars().all(|c| c.is_digit(16)));
+        assert_ne!(side, random_side());
+    }
+
+    #[test]
+    fn test_random_id() {
+        let id = random_id();
+        assert_eq!(id.len(), 4);
+        assert!(id.chars().all(|c| c.is_digit(16)));
+    }
+
     #[test]
     fn test_hexstr_to_string() {
         let s1 = "7b2270616b655f7631223a22353337363331646366643064336164386130346234663531643935336131343563386538626663373830646461393834373934656634666136656536306339663665227d";
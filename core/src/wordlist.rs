@@ -1 +1,446 @@
+// The PGP biometric word list (the same one the Python client uses). Each
+// byte value maps to an "even" word (two syllables) and an "odd" word (three
+// syllables). Codes alternate between the two lists, starting with an odd
+// word, which makes transposed or dropped words easy to notice.
 
+use std::fmt;
+use std::sync::Arc;
+use rand::{OsRng, Rng};
+use serde::{Deserialize, Deserializer, Serializer};
+
+// Applications can supply their own wordlist (e.g. for a different
+// language) to APIEvent::AllocateCode and InputCode. Both sides must agree
+// on the wordlist for completion to be useful, but the code itself is just
+// a string, so any words will work.
+pub trait Wordlist: fmt::Debug + Send + Sync {
+    // build `length` random words, joined with hyphens
+    fn choose_words(&self, length: u8) -> String;
+
+    // complete the last (partial) word of `prefix`, which holds just the
+    // words of the code (not the nameplate). A hyphen should be appended
+    // when more words are expected.
+    fn get_completions(&self, prefix: &str, num_words: u8) -> Vec<String>;
+}
+
+// Events carry wordlists and derive PartialEq, but there's no useful way to
+// compare two arbitrary wordlists, so we compare identity instead.
+impl PartialEq for Wordlist {
+    fn eq(&self, other: &Wordlist) -> bool {
+        self as *const Wordlist as *const u8
+            == other as *const Wordlist as *const u8
+    }
+}
+
+// An application's wordlist could be anything, so we can't save it along with
+// the rest of WormholeCore. A restored core uses the default wordlist instead.
+pub fn serialize_wordlist<S>(
+    _wordlist: &Arc<Wordlist>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_unit()
+}
+
+pub fn deserialize_wordlist<'de, D>(
+    deserializer: D,
+) -> Result<Arc<Wordlist>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    <()>::deserialize(deserializer)?;
+    Ok(Arc::new(default_wordlist()))
+}
+
+// picks words from each of several lists in turn
+#[derive(PartialEq, Clone)]
+pub struct AlternatingWordlist {
+    lists: Vec<Vec<String>>,
+}
+
+// the derived Debug would print every word, every time we log an event (or
+// a state) that holds a wordlist
+impl fmt::Debug for AlternatingWordlist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sizes: Vec<usize> = self.lists.iter().map(|l| l.len()).collect();
+        write!(f, "AlternatingWordlist({:?})", sizes)
+    }
+}
+
+impl AlternatingWordlist {
+    pub fn new(lists: Vec<Vec<String>>) -> AlternatingWordlist {
+        AlternatingWordlist { lists: lists }
+    }
+
+    // is this word in any of our lists? (ignoring case)
+    pub fn contains(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        self.lists.iter().any(|words| words.contains(&word))
+    }
+}
+
+impl Wordlist for AlternatingWordlist {
+    // pick one random word from each list in turn (cycling back to the
+    // first list if length > lists.len())
+    fn choose_words(&self, length: u8) -> String {
+        let mut rng = OsRng::new().unwrap();
+        let words: Vec<String> = self.lists
+            .iter()
+            .cycle()
+            .take(length as usize)
+            .filter_map(|words| rng.choose(words))
+            .cloned()
+            .collect();
+        words.join("-")
+    }
+
+    fn get_completions(&self, prefix: &str, num_words: u8) -> Vec<String> {
+        if self.lists.is_empty() {
+            return Vec::new();
+        }
+        let prefix = prefix.to_lowercase();
+        let count = prefix.matches('-').count();
+        let words = &self.lists[count % self.lists.len()];
+        let (completed, partial) = match prefix.rfind('-') {
+            Some(i) => prefix.split_at(i + 1),
+            None => ("", prefix.as_str()),
+        };
+        let mut completions: Vec<String> = words
+            .iter()
+            .filter(|w| w.starts_with(partial))
+            .map(|w| {
+                let mut c = format!("{}{}", completed, w);
+                if count + 1 < num_words as usize {
+                    c.push('-');
+                }
+                c
+            })
+            .collect();
+        completions.sort();
+        completions
+    }
+}
+
+pub fn default_wordlist() -> AlternatingWordlist {
+    let odd = PGP_WORDS.iter().map(|&(_, odd)| odd.to_string()).collect();
+    let even = PGP_WORDS.iter().map(|&(even, _)| even.to_string()).collect();
+    AlternatingWordlist::new(vec![odd, even])
+}
+
+// (even, odd), indexed by byte value
+const PGP_WORDS: [(&'static str, &'static str); 256] = [
+    ("aardvark", "adroitness"),
+    ("absurd", "adviser"),
+    ("accrue", "aftermath"),
+    ("acme", "aggregate"),
+    ("adrift", "alkali"),
+    ("adult", "almighty"),
+    ("afflict", "amulet"),
+    ("ahead", "amusement"),
+    ("aimless", "antenna"),
+    ("algol", "applicant"),
+    ("allow", "apollo"),
+    ("alone", "armistice"),
+    ("ammo", "article"),
+    ("ancient", "asteroid"),
+    ("apple", "atlantic"),
+    ("artist", "atmosphere"),
+    ("assume", "autopsy"),
+    ("athens", "babylon"),
+    ("atlas", "backwater"),
+    ("aztec", "barbecue"),
+    ("baboon", "belowground"),
+    ("backfield", "bifocals"),
+    ("backward", "bodyguard"),
+    ("banjo", "bookseller"),
+    ("beaming", "borderline"),
+    ("bedlamp", "bottomless"),
+    ("beehive", "bradbury"),
+    ("beeswax", "bravado"),
+    ("befriend", "brazilian"),
+    ("belfast", "breakaway"),
+    ("berserk", "burlington"),
+    ("billiard", "businessman"),
+    ("bison", "butterfat"),
+    ("blackjack", "camelot"),
+    ("blockade", "candidate"),
+    ("blowtorch", "cannonball"),
+    ("bluebird", "capricorn"),
+    ("bombast", "caravan"),
+    ("bookshelf", "caretaker"),
+    ("brackish", "celebrate"),
+    ("breadline", "cellulose"),
+    ("breakup", "certify"),
+    ("brickyard", "chambermaid"),
+    ("briefcase", "cherokee"),
+    ("burbank", "chicago"),
+    ("button", "clergyman"),
+    ("buzzard", "coherence"),
+    ("cement", "combustion"),
+    ("chairlift", "commando"),
+    ("chatter", "company"),
+    ("checkup", "component"),
+    ("chisel", "concurrent"),
+    ("choking", "confidence"),
+    ("chopper", "conformist"),
+    ("christmas", "congregate"),
+    ("clamshell", "consensus"),
+    ("classic", "consulting"),
+    ("classroom", "corporate"),
+    ("cleanup", "corrosion"),
+    ("clockwork", "councilman"),
+    ("cobra", "crossover"),
+    ("commence", "crucifix"),
+    ("concert", "cumbersome"),
+    ("cowbell", "customer"),
+    ("crackdown", "dakota"),
+    ("cranky", "decadence"),
+    ("crowfoot", "december"),
+    ("crucial", "decimal"),
+    ("crumpled", "designing"),
+    ("crusade", "detector"),
+    ("cubic", "detergent"),
+    ("dashboard", "determine"),
+    ("deadbolt", "dictator"),
+    ("deckhand", "dinosaur"),
+    ("dogsled", "direction"),
+    ("dragnet", "disable"),
+    ("drainage", "disbelief"),
+    ("dreadful", "disruptive"),
+    ("drifter", "distortion"),
+    ("dropper", "document"),
+    ("drumbeat", "embezzle"),
+    ("drunken", "enchanting"),
+    ("dupont", "enrollment"),
+    ("dwelling", "enterprise"),
+    ("eating", "equation"),
+    ("edict", "equipment"),
+    ("egghead", "escapade"),
+    ("eightball", "eskimo"),
+    ("endorse", "everyday"),
+    ("endow", "examine"),
+    ("enlist", "existence"),
+    ("erase", "exodus"),
+    ("escape", "fascinate"),
+    ("exceed", "filament"),
+    ("eyeglass", "finicky"),
+    ("eyetooth", "forever"),
+    ("facial", "fortitude"),
+    ("fallout", "frequency"),
+    ("flagpole", "gadgetry"),
+    ("flatfoot", "galveston"),
+    ("flytrap", "getaway"),
+    ("fracture", "glossary"),
+    ("framework", "gossamer"),
+    ("freedom", "graduate"),
+    ("frighten", "gravity"),
+    ("gazelle", "guitarist"),
+    ("geiger", "hamburger"),
+    ("glitter", "hamilton"),
+    ("glucose", "handiwork"),
+    ("goggles", "hazardous"),
+    ("goldfish", "headwaters"),
+    ("gremlin", "hemisphere"),
+    ("guidance", "hesitate"),
+    ("hamlet", "hideaway"),
+    ("highchair", "holiness"),
+    ("hockey", "hurricane"),
+    ("indoors", "hydraulic"),
+    ("indulge", "impartial"),
+    ("inverse", "impetus"),
+    ("involve", "inception"),
+    ("island", "indigo"),
+    ("jawbone", "inertia"),
+    ("keyboard", "infancy"),
+    ("kickoff", "inferno"),
+    ("kiwi", "informant"),
+    ("klaxon", "insincere"),
+    ("locale", "insurgent"),
+    ("lockup", "integrate"),
+    ("merit", "intention"),
+    ("minnow", "inventive"),
+    ("miser", "istanbul"),
+    ("mohawk", "jamaica"),
+    ("mural", "jupiter"),
+    ("music", "leprosy"),
+    ("necklace", "letterhead"),
+    ("neptune", "liberty"),
+    ("newborn", "maritime"),
+    ("nightcap", "matchmaker"),
+    ("oakland", "maverick"),
+    ("oblong", "medusa"),
+    ("octopus", "megaton"),
+    ("offload", "microscope"),
+    ("optic", "microwave"),
+    ("orca", "midsummer"),
+    ("payday", "millionaire"),
+    ("peachy", "miracle"),
+    ("pheasant", "misnomer"),
+    ("physique", "molasses"),
+    ("playhouse", "molecule"),
+    ("pluto", "montana"),
+    ("preclude", "monument"),
+    ("prefer", "mosquito"),
+    ("preshrunk", "narrative"),
+    ("printer", "nebula"),
+    ("prowler", "newsletter"),
+    ("pupil", "norwegian"),
+    ("puppy", "october"),
+    ("python", "ohio"),
+    ("quadrant", "onlooker"),
+    ("quiver", "opulent"),
+    ("quota", "orlando"),
+    ("ragtime", "outfielder"),
+    ("ratchet", "pacific"),
+    ("rebirth", "pandemic"),
+    ("reform", "pandora"),
+    ("regain", "paperweight"),
+    ("reindeer", "paragon"),
+    ("rematch", "paragraph"),
+    ("repay", "paramount"),
+    ("retouch", "passenger"),
+    ("revenge", "pedigree"),
+    ("reward", "pegasus"),
+    ("rhythm", "penetrate"),
+    ("ribcage", "perceptive"),
+    ("ringbolt", "performance"),
+    ("robust", "pharmacy"),
+    ("rocker", "phonetic"),
+    ("ruffled", "photograph"),
+    ("sailboat", "pioneer"),
+    ("sawdust", "pocketful"),
+    ("scallion", "politeness"),
+    ("scenic", "positive"),
+    ("scorecard", "potato"),
+    ("scotland", "processor"),
+    ("seabird", "provincial"),
+    ("select", "proximate"),
+    ("sentence", "puberty"),
+    ("shadow", "publisher"),
+    ("shamrock", "pyramid"),
+    ("showgirl", "quantity"),
+    ("skullcap", "racketeer"),
+    ("skydive", "rebellion"),
+    ("slingshot", "recipe"),
+    ("slowdown", "recover"),
+    ("snapline", "repellent"),
+    ("snapshot", "replica"),
+    ("snowcap", "reproduce"),
+    ("snowslide", "resistor"),
+    ("solo", "responsive"),
+    ("southward", "retraction"),
+    ("soybean", "retrieval"),
+    ("spaniel", "retrospect"),
+    ("spearhead", "revenue"),
+    ("spellbind", "revival"),
+    ("spheroid", "revolver"),
+    ("spigot", "sandalwood"),
+    ("spindle", "sardonic"),
+    ("spyglass", "saturday"),
+    ("stagehand", "savagery"),
+    ("stagnate", "scavenger"),
+    ("stairway", "sensation"),
+    ("standard", "sociable"),
+    ("stapler", "souvenir"),
+    ("steamship", "specialist"),
+    ("sterling", "speculate"),
+    ("stockman", "stethoscope"),
+    ("stopwatch", "stupendous"),
+    ("stormy", "supportive"),
+    ("sugar", "surrender"),
+    ("surmount", "suspicious"),
+    ("suspense", "sympathy"),
+    ("sweatband", "tambourine"),
+    ("swelter", "telephone"),
+    ("tactics", "therapist"),
+    ("talon", "tobacco"),
+    ("tapeworm", "tolerance"),
+    ("tempest", "tomorrow"),
+    ("tiger", "torpedo"),
+    ("tissue", "tradition"),
+    ("tonic", "travesty"),
+    ("topmost", "trombonist"),
+    ("tracker", "truncated"),
+    ("transit", "typewriter"),
+    ("trauma", "ultimate"),
+    ("treadmill", "undaunted"),
+    ("trojan", "underfoot"),
+    ("trouble", "unicorn"),
+    ("tumor", "unify"),
+    ("tunnel", "universe"),
+    ("tycoon", "unravel"),
+    ("uncut", "upcoming"),
+    ("unearth", "vacancy"),
+    ("unwind", "vagabond"),
+    ("uproot", "vertigo"),
+    ("upset", "virginia"),
+    ("upshot", "visitor"),
+    ("vapor", "vocalist"),
+    ("village", "voyager"),
+    ("virus", "warranty"),
+    ("vulcan", "waterloo"),
+    ("waffle", "whimsical"),
+    ("wallet", "wichita"),
+    ("watchword", "wilmington"),
+    ("wayside", "wyoming"),
+    ("willow", "yesteryear"),
+    ("woodlark", "yucatan"),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pgp_words() {
+        let w = default_wordlist();
+        assert_eq!(w.lists.len(), 2);
+        assert_eq!(w.lists[0].len(), 256);
+        assert_eq!(w.lists[1].len(), 256);
+        assert_eq!(w.lists[0][0], "adroitness");
+        assert_eq!(w.lists[1][0], "aardvark");
+        assert_eq!(w.lists[0][255], "yucatan");
+        assert_eq!(w.lists[1][255], "woodlark");
+    }
+
+    #[test]
+    fn test_choose_words() {
+        let w = default_wordlist();
+        let code = w.choose_words(3);
+        let words: Vec<String> =
+            code.split('-').map(|word| word.to_string()).collect();
+        assert_eq!(words.len(), 3);
+        // odd, even, odd
+        assert!(w.lists[0].contains(&words[0]));
+        assert!(w.lists[1].contains(&words[1]));
+        assert!(w.lists[0].contains(&words[2]));
+    }
+
+    #[test]
+    fn test_contains() {
+        let w = default_wordlist();
+        assert!(w.contains("guitarist"));
+        assert!(w.contains("Revenge"));
+        assert!(!w.contains("purple"));
+    }
+
+    #[test]
+    fn test_completions() {
+        let w = default_wordlist();
+        assert_eq!(w.get_completions("guit", 2), vec!["guitarist-"]);
+        assert_eq!(
+            w.get_completions("guitarist-rev", 2),
+            vec!["guitarist-revenge"]
+        );
+        assert_eq!(
+            w.get_completions("Guitarist-REV", 3),
+            vec!["guitarist-revenge-"]
+        );
+        assert_eq!(w.get_completions("sna", 2), Vec::<String>::new());
+        assert_eq!(
+            w.get_completions("guitarist-sna", 2),
+            vec!["guitarist-snapline", "guitarist-snapshot"]
+        );
+    }
+}
@@ -0,0 +1,21 @@
+extern crate magic_wormhole_io_blocking;
+
+use magic_wormhole_io_blocking::Wormhole;
+use std::time::Duration;
+
+const MAILBOX_SERVER: &'static str = "ws://127.0.0.1:4000/v1";
+const APPID: &'static str = "lothar.com/wormhole/text-or-file-xfer";
+
+// the Python "wormhole receive 4-purple-sausages" will show our message
+fn main() {
+    let timeout = Duration::from_secs(60);
+    let w = Wormhole::connect(APPID, MAILBOX_SERVER).unwrap();
+    w.set_code("4-purple-sausages");
+    let offer = r#"{"offer": {"message": "hello from rust"}}"#;
+    w.send(offer.as_bytes().to_vec());
+    println!("verifier: {:?}", w.verifier(timeout).unwrap());
+    // we expect {"answer": {"message_ack": "ok"}}
+    let answer = w.receive(timeout).unwrap();
+    println!("got: {}", String::from_utf8(answer).unwrap());
+    println!("closed: {:?}", w.close(timeout).unwrap());
+}
@@ -0,0 +1,506 @@
+// Drive a WormholeCore from plain synchronous code: websockets run on a
+// background thread (using the ws crate), timers each get a sleeping thread
+// of their own, and the application's calls block (up to a timeout) until
+// the core has delivered what they're waiting for.
+//
+//     let w = Wormhole::connect(APPID, RELAY_URL)?;
+//     w.allocate_code(2);
+//     println!("code is {}", w.get_code(Duration::from_secs(10))?);
+//     w.send(b"hello".to_vec());
+//     let reply = w.receive(Duration::from_secs(300))?;
+//     w.close(Duration::from_secs(10))?;
+
+extern crate magic_wormhole_core;
+extern crate serde_json;
+extern crate url;
+extern crate ws;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use url::Url;
+use magic_wormhole_core::{APIAction, APIEvent, Action, IOAction, IOEvent,
+                          Mood, TimerHandle, WSHandle, WormholeCore,
+                          WormholeCoreBuilder, WormholeError};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    // the core gave up on the session (which is then closed)
+    Wormhole(WormholeError),
+    // the session was closed before the thing we were waiting for happened
+    Closed(Mood),
+    // nothing happened before the timeout
+    Timeout,
+    // the websocket event loop couldn't be started
+    WebSocket(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Wormhole(ref e) => write!(f, "{}", e),
+            Error::Closed(mood) => write!(f, "closed ({:?})", mood),
+            Error::Timeout => write!(f, "timed out"),
+            Error::WebSocket(ref e) => write!(f, "websocket error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Wormhole(ref e) => e.description(),
+            Error::Closed(_) => "closed",
+            Error::Timeout => "timed out",
+            Error::WebSocket(_) => "websocket error",
+        }
+    }
+}
+
+struct Shared {
+    core: WormholeCore,
+    // tells the event loop to make new connections (or to stop)
+    event_loop: Option<ws::Sender>,
+    // the connections we've asked for that the event loop hasn't started on
+    // yet, in the order we asked for them
+    opening: VecDeque<WSHandle>,
+    sockets: HashMap<WSHandle, ws::Sender>,
+    // timers that haven't been cancelled
+    timers: HashSet<TimerHandle>,
+    code: Option<String>,
+    verifier: Option<Vec<u8>>,
+    versions: Option<Value>,
+    messages: VecDeque<Vec<u8>>,
+    error: Option<WormholeError>,
+    closing: bool,
+    closed: Option<Mood>,
+}
+
+impl Shared {
+    fn deliver(&mut self, action: APIAction) {
+        match action {
+            APIAction::GotCode(code) => self.code = Some(code),
+            APIAction::GotVerifier(verifier) => self.verifier = Some(verifier),
+            APIAction::GotVersions(versions) => self.versions = Some(versions),
+            APIAction::GotMessage(message) => self.messages.push_back(message),
+            APIAction::GotError(e) => self.error = Some(e),
+            APIAction::GotClosed(mood) => self.closed = Some(mood),
+            // the welcome and the diagnostics aren't interesting here
+            _ => {}
+        }
+    }
+}
+
+struct State {
+    shared: Mutex<Shared>,
+    // notified whenever the core delivers something
+    changed: Condvar,
+}
+
+// A Wormhole can be used from any thread. Dropping it stops the background
+// thread, so close() it first.
+pub struct Wormhole {
+    state: Arc<State>,
+    event_loop: Option<thread::JoinHandle<()>>,
+}
+
+impl Wormhole {
+    pub fn connect(appid: &str, relay_url: &str) -> Result<Wormhole, Error> {
+        let core = WormholeCoreBuilder::new(appid, relay_url).build();
+        Wormhole::new(core)
+    }
+
+    // for a core that needs non-default settings, or was restored from a
+    // saved one
+    pub fn new(core: WormholeCore) -> Result<Wormhole, Error> {
+        let state = Arc::new(State {
+            shared: Mutex::new(Shared {
+                core: core,
+                event_loop: None,
+                opening: VecDeque::new(),
+                sockets: HashMap::new(),
+                timers: HashSet::new(),
+                code: None,
+                verifier: None,
+                versions: None,
+                messages: VecDeque::new(),
+                error: None,
+                closing: false,
+                closed: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let factory = Factory {
+            state: Arc::clone(&state),
+        };
+        let socket = ws::WebSocket::new(factory)
+            .map_err(|e| Error::WebSocket(e.to_string()))?;
+        let mut shared = state.shared.lock().unwrap();
+        shared.event_loop = Some(socket.broadcaster());
+        // if this stops early, every connection we ask for afterwards
+        // fails, and the core hears about that like any other failure
+        let event_loop = thread::spawn(move || {
+            let _ = socket.run();
+        });
+        let actions = shared.core.start();
+        process(&state, shared, actions);
+        Ok(Wormhole {
+            state: state,
+            event_loop: Some(event_loop),
+        })
+    }
+
+    pub fn allocate_code(&self, num_words: u8) {
+        self.do_api(APIEvent::AllocateCode(Some(num_words), None));
+    }
+
+    pub fn set_code(&self, code: &str) {
+        self.do_api(APIEvent::SetCode(code.to_string()));
+    }
+
+    // messages sent before the key is verified are queued until it is
+    pub fn send(&self, message: Vec<u8>) {
+        self.do_api(APIEvent::Send(message));
+    }
+
+    pub fn get_code(&self, timeout: Duration) -> Result<String, Error> {
+        self.wait(timeout, |shared| shared.code.clone())
+    }
+
+    pub fn verifier(&self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        self.wait(timeout, |shared| shared.verifier.clone())
+    }
+
+    // the app_versions our peer gave its core
+    pub fn versions(&self, timeout: Duration) -> Result<Value, Error> {
+        self.wait(timeout, |shared| shared.versions.clone())
+    }
+
+    // each call gets the next message from our peer, in order
+    pub fn receive(&self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        self.wait(timeout, |shared| shared.messages.pop_front())
+    }
+
+    // waits for the key to be verified first
+    pub fn derive_key(
+        &self,
+        purpose: &str,
+        length: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        self.verifier(timeout)?;
+        let shared = self.state.shared.lock().unwrap();
+        shared
+            .core
+            .derive_key(purpose, length)
+            .map_err(Error::Wormhole)
+    }
+
+    // Returns our mood once the server has closed the mailbox, or whatever
+    // error closed the session instead.
+    pub fn close(&self, timeout: Duration) -> Result<Mood, Error> {
+        {
+            let mut shared = self.state.shared.lock().unwrap();
+            let start = !shared.closing && shared.error.is_none()
+                && shared.closed.is_none();
+            shared.closing = true;
+            if start {
+                let actions = shared.core.do_api(APIEvent::Close);
+                process(&self.state, shared, actions);
+            }
+        }
+        let deadline = Instant::now() + timeout;
+        let mut shared = self.state.shared.lock().unwrap();
+        loop {
+            if let Some(mood) = shared.closed {
+                return match shared.error {
+                    Some(ref e) => Err(Error::Wormhole(e.clone())),
+                    None => Ok(mood),
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            shared = self.state
+                .changed
+                .wait_timeout(shared, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn do_api(&self, event: APIEvent) {
+        let mut shared = self.state.shared.lock().unwrap();
+        let actions = shared.core.do_api(event);
+        process(&self.state, shared, actions);
+    }
+
+    fn wait<T, F>(&self, timeout: Duration, mut get: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut Shared) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut shared = self.state.shared.lock().unwrap();
+        loop {
+            if let Some(value) = get(&mut shared) {
+                return Ok(value);
+            }
+            if let Some(ref e) = shared.error {
+                return Err(Error::Wormhole(e.clone()));
+            }
+            if let Some(mood) = shared.closed {
+                return Err(Error::Closed(mood));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            shared = self.state
+                .changed
+                .wait_timeout(shared, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for Wormhole {
+    fn drop(&mut self) {
+        let event_loop = {
+            let mut shared = self.state.shared.lock().unwrap();
+            // sleeping timer threads will find nothing to do
+            shared.timers.clear();
+            shared.event_loop.take()
+        };
+        if let Some(event_loop) = event_loop {
+            let _ = event_loop.shutdown();
+        }
+        if let Some(event_loop) = self.event_loop.take() {
+            let _ = event_loop.join();
+        }
+    }
+}
+
+fn do_io(state: &Arc<State>, mut shared: MutexGuard<Shared>, event: IOEvent) {
+    let actions = shared.core.do_io(event);
+    process(state, shared, actions);
+}
+
+// What the core asked of the event loop, which is done once we've let go of
+// the lock: the event loop takes it too (to tell the core what happened),
+// and a ws::Sender blocks while the event loop's queue is full.
+enum Outside {
+    Connect(WSHandle, ws::Sender, Url),
+    Send(ws::Sender, String),
+    Close(ws::Sender),
+    // a connection we couldn't even ask for
+    Failed(WSHandle),
+}
+
+fn process<'a>(
+    state: &'a Arc<State>,
+    mut shared: MutexGuard<'a, Shared>,
+    mut actions: Vec<Action>,
+) {
+    loop {
+        let mut outside = vec![];
+        for action in actions.drain(..) {
+            match action {
+                Action::IO(io) => {
+                    outside.extend(process_io(state, &mut shared, io))
+                }
+                Action::API(api) => {
+                    shared.deliver(api);
+                    state.changed.notify_all();
+                }
+            }
+        }
+        drop(shared);
+        let mut failed = vec![];
+        for o in outside {
+            match o {
+                Outside::Connect(wsh, event_loop, url) => {
+                    if event_loop.connect(url).is_err() {
+                        failed.push(wsh);
+                    }
+                }
+                // if the connection has already gone, the core will hear
+                // about it soon enough
+                Outside::Send(out, message) => {
+                    let _ = out.send(message);
+                }
+                Outside::Close(out) => {
+                    let _ = out.close(ws::CloseCode::Normal);
+                }
+                Outside::Failed(wsh) => failed.push(wsh),
+            }
+        }
+        if failed.is_empty() {
+            return;
+        }
+        // the core decides whether (and when) to try again
+        shared = state.shared.lock().unwrap();
+        for wsh in failed {
+            shared.opening.retain(|&w| w != wsh);
+            let lost = IOEvent::WebSocketConnectionLost(wsh);
+            actions.append(&mut shared.core.do_io(lost));
+        }
+    }
+}
+
+fn process_io(
+    state: &Arc<State>,
+    shared: &mut Shared,
+    action: IOAction,
+) -> Option<Outside> {
+    match action {
+        IOAction::StartTimer(th, seconds) => {
+            start_timer(state, shared, th, seconds);
+            None
+        }
+        IOAction::CancelTimer(th) => {
+            shared.timers.remove(&th);
+            None
+        }
+        IOAction::WebSocketOpen(wsh, url) => Some(open(shared, wsh, &url)),
+        IOAction::WebSocketSendMessage(wsh, message) => shared
+            .sockets
+            .get(&wsh)
+            .map(|out| Outside::Send(out.clone(), message)),
+        IOAction::WebSocketClose(wsh) => shared
+            .sockets
+            .get(&wsh)
+            .map(|out| Outside::Close(out.clone())),
+    }
+}
+
+fn start_timer(
+    state: &Arc<State>,
+    shared: &mut Shared,
+    th: TimerHandle,
+    seconds: f32,
+) {
+    shared.timers.insert(th);
+    let state = Arc::clone(state);
+    let delay = Duration::from_millis((seconds * 1000.0) as u64);
+    thread::spawn(move || {
+        thread::sleep(delay);
+        let mut shared = state.shared.lock().unwrap();
+        // cancelled timers still go off, but nobody wants to hear about it
+        if shared.timers.remove(&th) {
+            do_io(&state, shared, IOEvent::TimerExpired(th));
+        }
+    });
+}
+
+fn open(shared: &mut Shared, wsh: WSHandle, url: &str) -> Outside {
+    match (Url::parse(url), shared.event_loop.as_ref()) {
+        (Ok(url), Some(event_loop)) => {
+            // the Factory picks this up when the event loop gets to it
+            shared.opening.push_back(wsh);
+            Outside::Connect(wsh, event_loop.clone(), url)
+        }
+        _ => Outside::Failed(wsh),
+    }
+}
+
+struct Factory {
+    state: Arc<State>,
+}
+
+impl ws::Factory for Factory {
+    type Handler = Handler;
+
+    // we only make outgoing connections, in the order the core asked for
+    // them
+    fn connection_made(&mut self, out: ws::Sender) -> Handler {
+        let mut shared = self.state.shared.lock().unwrap();
+        let wsh = shared.opening.pop_front();
+        if let Some(wsh) = wsh {
+            shared.sockets.insert(wsh, out.clone());
+        }
+        Handler {
+            state: Arc::clone(&self.state),
+            wsh: wsh,
+            out: out,
+        }
+    }
+
+    // this is called for connections that failed, as well as for ones that
+    // were closed
+    fn connection_lost(&mut self, handler: Handler) {
+        if let Some(wsh) = handler.wsh {
+            let mut shared = self.state.shared.lock().unwrap();
+            shared.sockets.remove(&wsh);
+            do_io(&self.state, shared, IOEvent::WebSocketConnectionLost(wsh));
+        }
+    }
+}
+
+struct Handler {
+    state: Arc<State>,
+    // None for a connection the core didn't ask for (or has given up on
+    // asking for), which is closed as soon as it opens
+    wsh: Option<WSHandle>,
+    out: ws::Sender,
+}
+
+impl ws::Handler for Handler {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        match self.wsh {
+            Some(wsh) => {
+                let shared = self.state.shared.lock().unwrap();
+                let made = IOEvent::WebSocketConnectionMade(wsh);
+                do_io(&self.state, shared, made);
+                Ok(())
+            }
+            None => self.out.close(ws::CloseCode::Normal),
+        }
+    }
+
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        if let (Some(wsh), ws::Message::Text(text)) = (self.wsh, message) {
+            let shared = self.state.shared.lock().unwrap();
+            let received = IOEvent::WebSocketMessageReceived(wsh, text);
+            do_io(&self.state, shared, received);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{Error, Wormhole};
+    use magic_wormhole_core::{WormholeCoreBuilder, WormholeError};
+
+    // nothing listens on port 1, so every attempt fails right away
+    const NOWHERE: &'static str = "ws://127.0.0.1:1/v1";
+
+    #[test]
+    fn unreachable_server() {
+        let core = WormholeCoreBuilder::new("appid", NOWHERE)
+            .reconnect_delay(0.01)
+            .reconnect_max_attempts(2)
+            .build();
+        let w = Wormhole::new(core).unwrap();
+        w.allocate_code(2);
+        let e = w.get_code(Duration::from_secs(10)).unwrap_err();
+        assert_eq!(e, Error::Wormhole(WormholeError::ConnectionFailed(2)));
+    }
+
+    #[test]
+    fn timeout() {
+        let core = WormholeCoreBuilder::new("appid", NOWHERE)
+            .reconnect_delay(60.0)
+            .build();
+        let w = Wormhole::new(core).unwrap();
+        let e = w.receive(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(e, Error::Timeout);
+    }
+}
@@ -0,0 +1,24 @@
+extern crate magic_wormhole_io_tokio;
+extern crate tokio_core;
+
+use magic_wormhole_io_tokio::Wormhole;
+use tokio_core::reactor::Core;
+
+// Can websocket do hostname lookup? Use ip addr, not localhost, for now
+const MAILBOX_SERVER: &'static str = "ws://127.0.0.1:4000/v1";
+const APPID: &'static str = "lothar.com/wormhole/text-or-file-xfer";
+
+// the Python "wormhole receive 4-purple-sausages" will show our message
+fn main() {
+    let mut core = Core::new().unwrap();
+    let w = Wormhole::connect(APPID, MAILBOX_SERVER, &core.handle());
+    w.set_code("4-purple-sausages");
+    let offer = r#"{"offer": {"message": "hello from rust"}}"#;
+    w.send(offer.as_bytes().to_vec());
+    let verifier = core.run(w.verifier()).unwrap();
+    println!("verifier: {:?}", verifier);
+    // we expect {"answer": {"message_ack": "ok"}}
+    let answer = core.run(w.receive()).unwrap();
+    println!("got: {}", String::from_utf8(answer).unwrap());
+    println!("closed: {:?}", core.run(w.close()).unwrap());
+}
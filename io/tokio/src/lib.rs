@@ -0,0 +1,422 @@
+// Drive a WormholeCore with tokio: the core tells us which websockets to
+// open and which timers to start, we tell it what happened to them, and the
+// application gets futures for the things it is waiting for.
+//
+//     let mut core = Core::new().unwrap();
+//     let w = Wormhole::connect(APPID, RELAY_URL, &core.handle());
+//     w.allocate_code(2);
+//     println!("code is {}", core.run(w.get_code()).unwrap());
+//     w.send(b"hello".to_vec());
+//     let reply = core.run(w.receive()).unwrap();
+//     core.run(w.close()).unwrap();
+
+extern crate futures;
+extern crate magic_wormhole_core;
+extern crate serde_json;
+extern crate tokio_core;
+extern crate websocket;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+use futures::future;
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Sink, Stream};
+use serde_json::Value;
+use tokio_core::reactor::{Handle, Timeout};
+use websocket::{ClientBuilder, OwnedMessage};
+use magic_wormhole_core::{APIAction, APIEvent, Action, IOAction, IOEvent,
+                          Mood, TimerHandle, WSHandle, WormholeCore,
+                          WormholeCoreBuilder, WormholeError};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    // the core gave up on the session (which is then closed)
+    Wormhole(WormholeError),
+    // the session was closed before the thing we were waiting for happened
+    Closed(Mood),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Wormhole(ref e) => write!(f, "{}", e),
+            Error::Closed(mood) => write!(f, "closed ({:?})", mood),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Wormhole(ref e) => e.description(),
+            Error::Closed(_) => "closed",
+        }
+    }
+}
+
+struct Inner {
+    core: WormholeCore,
+    handle: Handle,
+    // outbound queues for our open websockets
+    sockets: HashMap<WSHandle, mpsc::UnboundedSender<OwnedMessage>>,
+    // timers that haven't been cancelled
+    timers: HashSet<TimerHandle>,
+    code: Option<String>,
+    verifier: Option<Vec<u8>>,
+    versions: Option<Value>,
+    messages: VecDeque<Vec<u8>>,
+    error: Option<WormholeError>,
+    closing: bool,
+    closed: Option<Mood>,
+    // futures waiting for any of the above to change
+    waiting: Vec<Task>,
+}
+
+impl Inner {
+    fn deliver(&mut self, action: APIAction) {
+        match action {
+            APIAction::GotCode(code) => self.code = Some(code),
+            APIAction::GotVerifier(verifier) => self.verifier = Some(verifier),
+            APIAction::GotVersions(versions) => self.versions = Some(versions),
+            APIAction::GotMessage(message) => self.messages.push_back(message),
+            APIAction::GotError(e) => self.error = Some(e),
+            APIAction::GotClosed(mood) => self.closed = Some(mood),
+            // the welcome and the diagnostics aren't interesting here
+            _ => {}
+        }
+        for task in self.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+// A Wormhole must be used on the thread that owns the tokio Core it was
+// given a Handle for. Clones share the same session.
+#[derive(Clone)]
+pub struct Wormhole {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Wormhole {
+    pub fn connect(appid: &str, relay_url: &str, handle: &Handle) -> Wormhole {
+        let core = WormholeCoreBuilder::new(appid, relay_url).build();
+        Wormhole::new(core, handle)
+    }
+
+    // for a core that needs non-default settings, or was restored from a
+    // saved one
+    pub fn new(core: WormholeCore, handle: &Handle) -> Wormhole {
+        let inner = Rc::new(RefCell::new(Inner {
+            core: core,
+            handle: handle.clone(),
+            sockets: HashMap::new(),
+            timers: HashSet::new(),
+            code: None,
+            verifier: None,
+            versions: None,
+            messages: VecDeque::new(),
+            error: None,
+            closing: false,
+            closed: None,
+            waiting: Vec::new(),
+        }));
+        let actions = inner.borrow_mut().core.start();
+        process(&inner, actions);
+        Wormhole { inner: inner }
+    }
+
+    pub fn allocate_code(&self, num_words: u8) {
+        self.do_api(APIEvent::AllocateCode(Some(num_words), None));
+    }
+
+    pub fn set_code(&self, code: &str) {
+        self.do_api(APIEvent::SetCode(code.to_string()));
+    }
+
+    // messages sent before the key is verified are queued until it is
+    pub fn send(&self, message: Vec<u8>) {
+        self.do_api(APIEvent::Send(message));
+    }
+
+    pub fn get_code(&self) -> Box<Future<Item = String, Error = Error>> {
+        self.when(|inner| inner.code.clone())
+    }
+
+    pub fn verifier(&self) -> Box<Future<Item = Vec<u8>, Error = Error>> {
+        self.when(|inner| inner.verifier.clone())
+    }
+
+    // the app_versions our peer gave its core
+    pub fn versions(&self) -> Box<Future<Item = Value, Error = Error>> {
+        self.when(|inner| inner.versions.clone())
+    }
+
+    // each call gets the next message from our peer, in order
+    pub fn receive(&self) -> Box<Future<Item = Vec<u8>, Error = Error>> {
+        self.when(|inner| inner.messages.pop_front())
+    }
+
+    // waits for the key to be verified first
+    pub fn derive_key(
+        &self,
+        purpose: &str,
+        length: usize,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error>> {
+        let inner = Rc::clone(&self.inner);
+        let purpose = purpose.to_string();
+        Box::new(self.verifier().and_then(move |_| {
+            let inner = inner.borrow();
+            inner.core.derive_key(&purpose, length).map_err(Error::Wormhole)
+        }))
+    }
+
+    // Resolves with our mood once the server has closed the mailbox, or
+    // fails with whatever error closed the session instead.
+    pub fn close(&self) -> Box<Future<Item = Mood, Error = Error>> {
+        let start = {
+            let mut inner = self.inner.borrow_mut();
+            let start = !inner.closing && inner.error.is_none()
+                && inner.closed.is_none();
+            inner.closing = true;
+            start
+        };
+        if start {
+            self.do_api(APIEvent::Close);
+        }
+        Box::new(Waiter {
+            inner: Rc::clone(&self.inner),
+            check: |inner: &mut Inner| {
+                inner.closed.map(|mood| match inner.error {
+                    Some(ref e) => Err(Error::Wormhole(e.clone())),
+                    None => Ok(mood),
+                })
+            },
+        })
+    }
+
+    fn do_api(&self, event: APIEvent) {
+        let actions = self.inner.borrow_mut().core.do_api(event);
+        process(&self.inner, actions);
+    }
+
+    fn when<T, F>(&self, mut get: F) -> Box<Future<Item = T, Error = Error>>
+    where
+        T: 'static,
+        F: FnMut(&mut Inner) -> Option<T> + 'static,
+    {
+        Box::new(Waiter {
+            inner: Rc::clone(&self.inner),
+            check: move |inner: &mut Inner| {
+                if let Some(value) = get(inner) {
+                    return Some(Ok(value));
+                }
+                if let Some(ref e) = inner.error {
+                    return Some(Err(Error::Wormhole(e.clone())));
+                }
+                inner.closed.map(|mood| Err(Error::Closed(mood)))
+            },
+        })
+    }
+}
+
+// resolves once check() has an answer, re-checking whenever the core
+// delivers something
+struct Waiter<F> {
+    inner: Rc<RefCell<Inner>>,
+    check: F,
+}
+
+impl<T, F> Future for Waiter<F>
+where
+    F: FnMut(&mut Inner) -> Option<Result<T, Error>>,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        let mut inner = self.inner.borrow_mut();
+        match (self.check)(&mut inner) {
+            Some(Ok(value)) => Ok(Async::Ready(value)),
+            Some(Err(e)) => Err(e),
+            None => {
+                inner.waiting.push(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+fn do_io(inner: &Rc<RefCell<Inner>>, event: IOEvent) {
+    let actions = inner.borrow_mut().core.do_io(event);
+    process(inner, actions);
+}
+
+fn process(inner: &Rc<RefCell<Inner>>, actions: Vec<Action>) {
+    for action in actions {
+        match action {
+            Action::IO(io) => process_io(inner, io),
+            Action::API(api) => inner.borrow_mut().deliver(api),
+        }
+    }
+}
+
+fn process_io(inner: &Rc<RefCell<Inner>>, action: IOAction) {
+    match action {
+        IOAction::StartTimer(th, seconds) => start_timer(inner, th, seconds),
+        IOAction::CancelTimer(th) => {
+            inner.borrow_mut().timers.remove(&th);
+        }
+        IOAction::WebSocketOpen(wsh, url) => open(inner, wsh, &url),
+        IOAction::WebSocketSendMessage(wsh, message) => {
+            send(inner, wsh, OwnedMessage::Text(message))
+        }
+        IOAction::WebSocketClose(wsh) => close(inner, wsh),
+    }
+}
+
+fn start_timer(inner: &Rc<RefCell<Inner>>, th: TimerHandle, seconds: f32) {
+    let handle = inner.borrow().handle.clone();
+    inner.borrow_mut().timers.insert(th);
+    let delay = Duration::from_millis((seconds * 1000.0) as u64);
+    let timeout = Timeout::new(delay, &handle).unwrap();
+    let inner = Rc::clone(inner);
+    handle.spawn(timeout.then(move |_| {
+        // cancelled timers still go off, but nobody wants to hear about it
+        let live = inner.borrow_mut().timers.remove(&th);
+        if live {
+            do_io(&inner, IOEvent::TimerExpired(th));
+        }
+        Ok(())
+    }));
+}
+
+fn open(inner: &Rc<RefCell<Inner>>, wsh: WSHandle, url: &str) {
+    let (tx, rx) = mpsc::unbounded();
+    inner.borrow_mut().sockets.insert(wsh, tx);
+    let handle = inner.borrow().handle.clone();
+    let inner = Rc::clone(inner);
+    // TODO: wss:// (the public relay server doesn't use it yet)
+    let connect = future::result(ClientBuilder::new(url))
+        .map_err(|e| e.to_string())
+        .and_then(|builder| {
+            builder.async_connect_insecure().map_err(|e| e.to_string())
+        });
+    handle.spawn(connect.then(move |result| {
+        let (client, _headers) = match result {
+            Ok(connected) => connected,
+            // the core decides whether (and when) to try again
+            Err(_) => {
+                lost(&inner, wsh);
+                return future::Either::A(future::ok(()));
+            }
+        };
+        do_io(&inner, IOEvent::WebSocketConnectionMade(wsh));
+        let (sink, stream) = client.split();
+        let reader_inner = Rc::clone(&inner);
+        let reader = stream
+            .for_each(move |message| {
+                received(&reader_inner, wsh, message);
+                Ok(())
+            })
+            .map_err(|_| ());
+        // this ends when close() drops our sender
+        let writer = rx.forward(sink.sink_map_err(|_| ()));
+        future::Either::B(reader.select2(writer).then(move |_| {
+            lost(&inner, wsh);
+            Ok(())
+        }))
+    }));
+}
+
+fn received(inner: &Rc<RefCell<Inner>>, wsh: WSHandle, message: OwnedMessage) {
+    match message {
+        OwnedMessage::Text(text) => {
+            do_io(inner, IOEvent::WebSocketMessageReceived(wsh, text))
+        }
+        OwnedMessage::Ping(data) => send(inner, wsh, OwnedMessage::Pong(data)),
+        OwnedMessage::Close(_) => close(inner, wsh),
+        _ => {}
+    }
+}
+
+fn send(inner: &Rc<RefCell<Inner>>, wsh: WSHandle, message: OwnedMessage) {
+    if let Some(tx) = inner.borrow().sockets.get(&wsh) {
+        // if the connection has already gone, the core will hear about it
+        // soon enough
+        let _ = tx.unbounded_send(message);
+    }
+}
+
+fn close(inner: &Rc<RefCell<Inner>>, wsh: WSHandle) {
+    let tx = inner.borrow_mut().sockets.remove(&wsh);
+    if let Some(tx) = tx {
+        let _ = tx.unbounded_send(OwnedMessage::Close(None));
+    }
+}
+
+fn lost(inner: &Rc<RefCell<Inner>>, wsh: WSHandle) {
+    inner.borrow_mut().sockets.remove(&wsh);
+    do_io(inner, IOEvent::WebSocketConnectionLost(wsh));
+}
+
+#[cfg(test)]
+mod test {
+    extern crate magic_wormhole_server;
+
+    use std::thread;
+    use futures::Future;
+    use super::{Error, Wormhole};
+    use magic_wormhole_core::{Mood, WormholeCoreBuilder, WormholeError};
+    use tokio_core::reactor::Core;
+    use self::magic_wormhole_server::ServerBuilder;
+
+    #[test]
+    fn two_wormholes() {
+        let server = ServerBuilder::new("127.0.0.1:0").build().unwrap();
+        let url = server.url().unwrap();
+        let stopper = server.stopper();
+        let serving = thread::spawn(move || server.run().unwrap());
+
+        let mut reactor = Core::new().unwrap();
+        let a = Wormhole::connect("appid", &url, &reactor.handle());
+        a.allocate_code(2);
+        let code = reactor.run(a.get_code()).unwrap();
+        let b = Wormhole::connect("appid", &url, &reactor.handle());
+        b.set_code(&code);
+        a.send(b"hello".to_vec());
+        b.send(b"hi".to_vec());
+        assert_eq!(reactor.run(b.receive()).unwrap(), b"hello".to_vec());
+        assert_eq!(reactor.run(a.receive()).unwrap(), b"hi".to_vec());
+        assert_eq!(
+            reactor.run(a.verifier()).unwrap(),
+            reactor.run(b.verifier()).unwrap()
+        );
+        let both = a.close().join(b.close());
+        assert_eq!(reactor.run(both).unwrap(), (Mood::Happy, Mood::Happy));
+
+        stopper.shutdown().unwrap();
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn unreachable_server() {
+        let mut reactor = Core::new().unwrap();
+        // nothing listens on port 1, so every attempt fails right away
+        let core = WormholeCoreBuilder::new("appid", "ws://127.0.0.1:1/v1")
+            .reconnect_delay(0.01)
+            .reconnect_max_attempts(2)
+            .build();
+        let w = Wormhole::new(core, &reactor.handle());
+        w.allocate_code(2);
+        let e = reactor.run(w.get_code()).unwrap_err();
+        assert_eq!(e, Error::Wormhole(WormholeError::ConnectionFailed(2)));
+        // and anything else we ask for fails the same way
+        let e = reactor.run(w.receive()).unwrap_err();
+        assert_eq!(e, Error::Wormhole(WormholeError::ConnectionFailed(2)));
+    }
+}
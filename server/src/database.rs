@@ -0,0 +1,564 @@
+// Where the Mailbox Server keeps its nameplates, mailboxes and messages: an
+// sqlite database, either in a file (so a restarted server can carry on
+// where it left off, and clients can reconnect to the same mailboxes) or in
+// memory. Times are seconds since the epoch, so they survive restarts too.
+
+use std::collections::HashSet;
+use std::path::Path;
+use rand::{self, Rng};
+use rusqlite::{Connection, OptionalExtension, Result, NO_PARAMS};
+
+const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS nameplates (
+    app_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    mailbox_id TEXT NOT NULL,
+    updated REAL NOT NULL,
+    PRIMARY KEY (app_id, name)
+);
+CREATE TABLE IF NOT EXISTS nameplate_sides (
+    app_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    side TEXT NOT NULL,
+    PRIMARY KEY (app_id, name, side)
+);
+CREATE TABLE IF NOT EXISTS mailboxes (
+    app_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    started REAL NOT NULL,
+    second REAL,
+    updated REAL NOT NULL,
+    PRIMARY KEY (app_id, id)
+);
+-- mood is NULL until the side closes the mailbox
+CREATE TABLE IF NOT EXISTS mailbox_sides (
+    app_id TEXT NOT NULL,
+    mailbox_id TEXT NOT NULL,
+    side TEXT NOT NULL,
+    mood TEXT,
+    PRIMARY KEY (app_id, mailbox_id, side)
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    app_id TEXT NOT NULL,
+    mailbox_id TEXT NOT NULL,
+    side TEXT NOT NULL,
+    phase TEXT NOT NULL,
+    body TEXT NOT NULL,
+    server_rx REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS mailbox_usage (
+    started REAL NOT NULL,
+    waiting_time REAL,
+    total_time REAL NOT NULL,
+    result TEXT NOT NULL
+);
+";
+
+// what happened to a mailbox, recorded once everybody has closed it (or it
+// was pruned)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Usage {
+    pub started: f64,
+    // how long the first side waited for the second to show up
+    pub waiting_time: Option<f64>,
+    pub total_time: f64,
+    // "happy", "lonely", "scary", "errory" or "pruney"
+    pub result: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct MessageRecord {
+    pub id: i64,
+    pub side: String,
+    pub phase: String,
+    pub body: String,
+}
+
+// mailbox ids must be hard to guess: whoever knows one can read it
+fn random_mailbox_id() -> String {
+    const ALPHABET: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut rng = rand::thread_rng();
+    (0..13)
+        .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())] as char)
+        .collect()
+}
+
+fn summarize(
+    started: f64,
+    second: Option<f64>,
+    moods: &[Option<String>],
+    now: f64,
+    pruned: bool,
+) -> Usage {
+    let mut result = if moods.len() == 1 { "lonely" } else { "happy" };
+    let moods: HashSet<&str> = moods
+        .iter()
+        .filter_map(|mood| mood.as_ref().map(|m| m.as_str()))
+        .collect();
+    // the worst one wins
+    for mood in &["lonely", "errory", "scary"] {
+        if moods.contains(mood) {
+            result = mood;
+        }
+    }
+    if pruned {
+        result = "pruney";
+    }
+    Usage {
+        started: started,
+        waiting_time: second.map(|t| t - started),
+        total_time: now - started,
+        result: result.to_string(),
+    }
+}
+
+// Records how the mailbox went and deletes it along with its sides and
+// messages. Callers run this inside their own transaction, so none of it
+// happens unless all of it does.
+fn delete_mailbox(
+    conn: &Connection,
+    appid: &str,
+    mailbox: &str,
+    now: f64,
+    pruned: bool,
+) -> Result<Option<Usage>> {
+    let times: Option<(f64, Option<f64>)> = conn
+        .query_row(
+            "SELECT started, second FROM mailboxes
+             WHERE app_id = ? AND id = ?",
+            &[appid, mailbox],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let (started, second) = match times {
+        Some(times) => times,
+        None => return Ok(None),
+    };
+    let moods: Vec<Option<String>> = {
+        let mut stmt = conn.prepare(
+            "SELECT mood FROM mailbox_sides
+             WHERE app_id = ? AND mailbox_id = ?",
+        )?;
+        let rows = stmt.query_map(&[appid, mailbox], |row| row.get(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    let usage = summarize(started, second, &moods, now, pruned);
+    conn.execute(
+        "INSERT INTO mailbox_usage
+         (started, waiting_time, total_time, result)
+         VALUES (?, ?, ?, ?)",
+        params![
+            usage.started,
+            usage.waiting_time,
+            usage.total_time,
+            usage.result
+        ],
+    )?;
+    for table in &["messages", "mailbox_sides"] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE app_id = ? AND mailbox_id = ?",
+                table
+            ),
+            &[appid, mailbox],
+        )?;
+    }
+    conn.execute(
+        "DELETE FROM mailboxes WHERE app_id = ? AND id = ?",
+        &[appid, mailbox],
+    )?;
+    Ok(Some(usage))
+}
+
+// the sides a query (with app_id and a name or mailbox) finds
+fn sides(
+    conn: &Connection,
+    sql: &str,
+    appid: &str,
+    id: &str,
+) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare(sql)?;
+    let sides = stmt.query_map(&[appid, id], |row| row.get(0))?;
+    sides.collect()
+}
+
+fn touch(
+    conn: &Connection,
+    appid: &str,
+    mailbox: &str,
+    now: f64,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE mailboxes SET updated = ? WHERE app_id = ? AND id = ?",
+        params![now, appid, mailbox],
+    )?;
+    Ok(())
+}
+
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    // creates the file if it doesn't exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database> {
+        Database::init(Connection::open(path)?)
+    }
+
+    // forgets everything when the server stops
+    pub fn in_memory() -> Result<Database> {
+        Database::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Database> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Database { conn: conn })
+    }
+
+    pub fn nameplates(&self, appid: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name FROM nameplates WHERE app_id = ? ORDER BY name",
+        )?;
+        let names = stmt.query_map(&[appid], |row| row.get(0))?;
+        names.collect()
+    }
+
+    // returns the nameplate's mailbox, or None if two other sides already
+    // have it
+    pub fn claim_nameplate(
+        &mut self,
+        appid: &str,
+        name: &str,
+        side: &str,
+        now: f64,
+    ) -> Result<Option<String>> {
+        let tx = self.conn.transaction()?;
+        let mailbox: Option<String> = tx
+            .query_row(
+                "SELECT mailbox_id FROM nameplates
+                 WHERE app_id = ? AND name = ?",
+                &[appid, name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let mailbox = match mailbox {
+            Some(mailbox) => mailbox,
+            None => {
+                let mailbox = random_mailbox_id();
+                tx.execute(
+                    "INSERT INTO nameplates (app_id, name, mailbox_id, updated)
+                     VALUES (?, ?, ?, ?)",
+                    params![appid, name, mailbox, now],
+                )?;
+                mailbox
+            }
+        };
+        let sides = sides(
+            &tx,
+            "SELECT side FROM nameplate_sides WHERE app_id = ? AND name = ?",
+            appid,
+            name,
+        )?;
+        if !sides.contains(side) {
+            if sides.len() >= 2 {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT INTO nameplate_sides (app_id, name, side)
+                 VALUES (?, ?, ?)",
+                &[appid, name, side],
+            )?;
+        }
+        tx.execute(
+            "UPDATE nameplates SET updated = ? WHERE app_id = ? AND name = ?",
+            params![now, appid, name],
+        )?;
+        tx.commit()?;
+        Ok(Some(mailbox))
+    }
+
+    // the nameplate can be used again once nobody has it
+    pub fn release_nameplate(
+        &self,
+        appid: &str,
+        name: &str,
+        side: &str,
+    ) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM nameplate_sides
+             WHERE app_id = ? AND name = ? AND side = ?",
+            &[appid, name, side],
+        )?;
+        let sides = sides(
+            &self.conn,
+            "SELECT side FROM nameplate_sides WHERE app_id = ? AND name = ?",
+            appid,
+            name,
+        )?;
+        if sides.is_empty() {
+            self.conn.execute(
+                "DELETE FROM nameplates WHERE app_id = ? AND name = ?",
+                &[appid, name],
+            )?;
+        }
+        Ok(())
+    }
+
+    // returns everything added so far, or None if two other sides already
+    // have it open
+    pub(crate) fn open_mailbox(
+        &mut self,
+        appid: &str,
+        mailbox: &str,
+        side: &str,
+        now: f64,
+    ) -> Result<Option<Vec<MessageRecord>>> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO mailboxes (app_id, id, started, updated)
+             VALUES (?, ?, ?, ?)",
+            params![appid, mailbox, now, now],
+        )?;
+        let sides = sides(
+            &tx,
+            "SELECT side FROM mailbox_sides
+             WHERE app_id = ? AND mailbox_id = ?",
+            appid,
+            mailbox,
+        )?;
+        if !sides.contains(side) {
+            if sides.len() >= 2 {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT INTO mailbox_sides (app_id, mailbox_id, side)
+                 VALUES (?, ?, ?)",
+                &[appid, mailbox, side],
+            )?;
+            if sides.len() == 1 {
+                tx.execute(
+                    "UPDATE mailboxes SET second = ?
+                     WHERE app_id = ? AND id = ?",
+                    params![now, appid, mailbox],
+                )?;
+            }
+        }
+        touch(&tx, appid, mailbox, now)?;
+        let messages = {
+            let mut stmt = tx.prepare(
+                "SELECT id, side, phase, body FROM messages
+                 WHERE app_id = ? AND mailbox_id = ? ORDER BY id",
+            )?;
+            let rows = stmt.query_map(&[appid, mailbox], |row| {
+                Ok(MessageRecord {
+                    id: row.get(0)?,
+                    side: row.get(1)?,
+                    phase: row.get(2)?,
+                    body: row.get(3)?,
+                })
+            })?;
+            rows.collect::<Result<_>>()?
+        };
+        tx.commit()?;
+        Ok(Some(messages))
+    }
+
+    pub(crate) fn add_message(
+        &self,
+        appid: &str,
+        mailbox: &str,
+        side: &str,
+        phase: &str,
+        body: &str,
+        now: f64,
+    ) -> Result<MessageRecord> {
+        self.conn.execute(
+            "INSERT INTO messages
+             (app_id, mailbox_id, side, phase, body, server_rx)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![appid, mailbox, side, phase, body, now],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        touch(&self.conn, appid, mailbox, now)?;
+        Ok(MessageRecord {
+            id: id,
+            side: side.to_string(),
+            phase: phase.to_string(),
+            body: body.to_string(),
+        })
+    }
+
+    // once every side that opened the mailbox has closed it, it is deleted
+    // and we return how it went
+    pub fn close_mailbox(
+        &mut self,
+        appid: &str,
+        mailbox: &str,
+        side: &str,
+        mood: &str,
+        now: f64,
+    ) -> Result<Option<Usage>> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "UPDATE mailbox_sides SET mood = ?
+             WHERE app_id = ? AND mailbox_id = ? AND side = ?",
+            &[mood, appid, mailbox, side],
+        )?;
+        let open: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM mailbox_sides
+             WHERE app_id = ? AND mailbox_id = ? AND mood IS NULL",
+            &[appid, mailbox],
+            |row| row.get(0),
+        )?;
+        let usage = if open > 0 {
+            touch(&tx, appid, mailbox, now)?;
+            None
+        } else {
+            delete_mailbox(&tx, appid, mailbox, now, false)?
+        };
+        tx.commit()?;
+        Ok(usage)
+    }
+
+    // Deletes every mailbox that nobody has touched for `max_age` seconds
+    // (except the ones in `listening`, as (appid, mailbox)), and every
+    // nameplate that nobody has claimed for that long (unless its mailbox
+    // is still here). Returns how each mailbox went.
+    pub fn prune(
+        &mut self,
+        now: f64,
+        max_age: f64,
+        listening: &HashSet<(String, String)>,
+    ) -> Result<Vec<Usage>> {
+        let older_than = now - max_age;
+        let tx = self.conn.transaction()?;
+        let stale: Vec<(String, String)> = {
+            let mut stmt = tx.prepare(
+                "SELECT app_id, id FROM mailboxes WHERE updated < ?",
+            )?;
+            let rows = stmt.query_map(&[older_than], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+            rows.collect::<Result<_>>()?
+        };
+        let mut usage = Vec::new();
+        for (appid, mailbox) in stale {
+            if listening.contains(&(appid.clone(), mailbox.clone())) {
+                continue;
+            }
+            if let Some(u) = delete_mailbox(&tx, &appid, &mailbox, now, true)? {
+                usage.push(u);
+            }
+        }
+        for table in &["nameplate_sides", "nameplates"] {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE (app_id, name) IN
+                     (SELECT app_id, name FROM nameplates n
+                      WHERE updated < ? AND NOT EXISTS
+                      (SELECT * FROM mailboxes m
+                       WHERE m.app_id = n.app_id AND m.id = n.mailbox_id))",
+                    table
+                ),
+                &[older_than],
+            )?;
+        }
+        tx.commit()?;
+        Ok(usage)
+    }
+
+    pub fn usage(&self) -> Result<Vec<Usage>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT started, waiting_time, total_time, result
+             FROM mailbox_usage ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok(Usage {
+                started: row.get(0)?,
+                waiting_time: row.get(1)?,
+                total_time: row.get(2)?,
+                result: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use super::Database;
+
+    #[test]
+    fn survives_restart() {
+        let path = env::temp_dir()
+            .join(format!("wormhole-server-test-{}.sqlite", process::id()));
+        let _ = fs::remove_file(&path);
+        let mailbox = {
+            let mut db = Database::open(&path).unwrap();
+            let mailbox = db.claim_nameplate("appid", "4", "sideA", 1.0)
+                .unwrap()
+                .unwrap();
+            db.open_mailbox("appid", &mailbox, "sideA", 1.0).unwrap();
+            db.add_message("appid", &mailbox, "sideA", "pake", "01", 2.0)
+                .unwrap();
+            mailbox
+        };
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.nameplates("appid").unwrap(), vec!["4".to_string()]);
+        // sideA reconnects and carries on
+        assert_eq!(
+            db.claim_nameplate("appid", "4", "sideA", 3.0).unwrap(),
+            Some(mailbox.clone())
+        );
+        let messages = db.open_mailbox("appid", &mailbox, "sideB", 3.0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].phase, "pake");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prune() {
+        let mut db = Database::in_memory().unwrap();
+        db.claim_nameplate("appid", "1", "sideA", 1.0).unwrap();
+        db.open_mailbox("appid", "old", "sideA", 1.0).unwrap();
+        db.open_mailbox("appid", "busy", "sideA", 1.0).unwrap();
+        db.open_mailbox("appid", "new", "sideA", 10.0).unwrap();
+        let mut listening = HashSet::new();
+        listening.insert(("appid".to_string(), "busy".to_string()));
+        // nameplates claimed just as long ago, whose mailboxes are still in
+        // use (recently, or by somebody who's connected)
+        for &(name, now) in &[("2", 10.0), ("3", 1.0)] {
+            let mailbox = db.claim_nameplate("appid", name, "sideA", 1.0)
+                .unwrap()
+                .unwrap();
+            db.open_mailbox("appid", &mailbox, "sideA", now).unwrap();
+            if name == "3" {
+                listening.insert(("appid".to_string(), mailbox));
+            }
+        }
+
+        let usage = db.prune(10.0, 5.0, &listening).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].result, "pruney");
+        assert_eq!(usage[0].total_time, 9.0);
+        assert_eq!(db.usage().unwrap(), usage);
+        assert_eq!(
+            db.nameplates("appid").unwrap(),
+            vec!["2".to_string(), "3".to_string()]
+        );
+        // it's gone, so opening it starts a new one
+        let messages = db.open_mailbox("appid", "old", "sideB", 11.0)
+            .unwrap()
+            .unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(db.prune(12.0, 5.0, &listening).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,363 @@
+// A Mailbox (rendezvous) Server, speaking the same JSON as the Python one,
+// so clients can be tested against a server that runs on localhost. State
+// is kept in an sqlite database (in memory unless we're given a file), and
+// a background thread prunes mailboxes that have gone quiet. Websockets are
+// handled by the ws crate, on whichever thread calls run().
+//
+//     let server = ServerBuilder::new("127.0.0.1:4000")
+//         .database("relay.sqlite")
+//         .reporter(Box::new(|report| println!("{}", report)))
+//         .build()?;
+//     println!("listening on {}", server.url()?);
+//     server.run()?;
+
+extern crate magic_wormhole_core;
+extern crate rand;
+#[macro_use]
+extern crate rusqlite;
+#[macro_use]
+extern crate serde_json;
+extern crate ws;
+
+#[cfg(test)]
+extern crate magic_wormhole_io_blocking;
+
+mod database;
+mod rendezvous;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+pub use database::{Database, Usage};
+pub use rendezvous::Rendezvous;
+
+#[derive(Debug)]
+pub enum Error {
+    WebSocket(ws::Error),
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WebSocket(ref e) => write!(f, "websocket error: {}", e),
+            Error::Database(ref e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::WebSocket(_) => "websocket error",
+            Error::Database(_) => "database error",
+        }
+    }
+}
+
+impl From<ws::Error> for Error {
+    fn from(e: ws::Error) -> Error {
+        Error::WebSocket(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Database(e)
+    }
+}
+
+// Things an operator might want to know about, none of which stop the
+// server. They're handed to the ServerBuilder's reporter as they happen.
+#[derive(Debug)]
+pub enum Report {
+    // a mailbox was closed by all of its sides, or pruned
+    Usage(Usage),
+    // we couldn't do what a client asked (or prune) because of the database
+    Database(rusqlite::Error),
+    // a connection failed, or we couldn't send to it
+    WebSocket(ws::Error),
+    // a client sent something other than text, which we ignored
+    BinaryMessage(usize),
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Report::Usage(ref u) => write!(
+                f,
+                "mailbox {} after {:.1}s",
+                u.result, u.total_time
+            ),
+            Report::Database(ref e) => write!(f, "database error: {}", e),
+            Report::WebSocket(ref e) => write!(f, "websocket error: {}", e),
+            Report::BinaryMessage(c) => {
+                write!(f, "ignoring binary message from connection {}", c)
+            }
+        }
+    }
+}
+
+pub type Reporter = Box<FnMut(Report) + Send>;
+
+struct Shared {
+    rendezvous: Rendezvous,
+    sockets: HashMap<usize, ws::Sender>,
+    reporter: Reporter,
+}
+
+impl Shared {
+    // hand everything the Rendezvous wants to say to its websocket, and
+    // everything it has to report to the reporter
+    fn flush(&mut self) {
+        for (c, text) in self.rendezvous.outbound() {
+            if let Some(socket) = self.sockets.get(&c) {
+                if let Err(e) = socket.send(text) {
+                    (self.reporter)(Report::WebSocket(e));
+                }
+            }
+        }
+        for report in self.rendezvous.reports() {
+            (self.reporter)(report);
+        }
+    }
+}
+
+struct Factory {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl ws::Factory for Factory {
+    type Handler = Handler;
+
+    fn connection_made(&mut self, out: ws::Sender) -> Handler {
+        Handler {
+            shared: Arc::clone(&self.shared),
+            out: out,
+            connection: None,
+        }
+    }
+}
+
+struct Handler {
+    shared: Arc<Mutex<Shared>>,
+    out: ws::Sender,
+    // set once the handshake is done
+    connection: Option<usize>,
+}
+
+impl Handler {
+    fn lost(&mut self) {
+        if let Some(c) = self.connection.take() {
+            let mut shared = self.shared.lock().unwrap();
+            shared.sockets.remove(&c);
+            shared.rendezvous.disconnect(c);
+        }
+    }
+}
+
+impl ws::Handler for Handler {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        let c = shared.rendezvous.connect();
+        shared.sockets.insert(c, self.out.clone());
+        self.connection = Some(c);
+        shared.flush();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let c = match self.connection {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let mut shared = self.shared.lock().unwrap();
+        match msg {
+            ws::Message::Text(text) => shared.rendezvous.receive(c, &text),
+            ws::Message::Binary(_) => {
+                (shared.reporter)(Report::BinaryMessage(c))
+            }
+        }
+        shared.flush();
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        self.lost();
+    }
+
+    fn on_error(&mut self, e: ws::Error) {
+        (self.shared.lock().unwrap().reporter)(Report::WebSocket(e));
+        self.lost();
+    }
+}
+
+pub struct ServerBuilder {
+    addr: String,
+    database: Option<PathBuf>,
+    motd: Option<String>,
+    max_age: f32,
+    prune_interval: f32,
+    reporter: Reporter,
+}
+
+impl ServerBuilder {
+    // "127.0.0.1:0" picks a free port: use local_addr() to find out which
+    pub fn new(addr: &str) -> ServerBuilder {
+        ServerBuilder {
+            addr: addr.to_string(),
+            database: None,
+            motd: None,
+            max_age: 11.0 * 60.0 * 60.0,
+            prune_interval: 60.0 * 60.0,
+            reporter: Box::new(|_| {}),
+        }
+    }
+
+    // keep everything in this file (created if necessary), so a restarted
+    // server still has its mailboxes
+    pub fn database<P: Into<PathBuf>>(mut self, path: P) -> ServerBuilder {
+        self.database = Some(path.into());
+        self
+    }
+
+    // shown to every client when it connects
+    pub fn motd(mut self, motd: &str) -> ServerBuilder {
+        self.motd = Some(motd.to_string());
+        self
+    }
+
+    // mailboxes idle for this long (in seconds) are pruned
+    pub fn max_age(mut self, max_age: f32) -> ServerBuilder {
+        self.max_age = max_age;
+        self
+    }
+
+    // how often (in seconds) to look for them
+    pub fn prune_interval(mut self, interval: f32) -> ServerBuilder {
+        self.prune_interval = interval;
+        self
+    }
+
+    // called (on one of the server's threads) with each Report, which are
+    // dropped otherwise
+    pub fn reporter(mut self, reporter: Reporter) -> ServerBuilder {
+        self.reporter = reporter;
+        self
+    }
+
+    pub fn build(self) -> Result<Server, Error> {
+        let db = match self.database {
+            Some(ref path) => Database::open(path)?,
+            None => Database::in_memory()?,
+        };
+        let shared = Arc::new(Mutex::new(Shared {
+            rendezvous: Rendezvous::new(db, self.motd),
+            sockets: HashMap::new(),
+            reporter: self.reporter,
+        }));
+        let factory = Factory {
+            shared: Arc::clone(&shared),
+        };
+        let socket = ws::WebSocket::new(factory)?.bind(self.addr.as_str())?;
+        Ok(Server {
+            socket: socket,
+            shared: Arc::downgrade(&shared),
+            max_age: self.max_age,
+            prune_interval: self.prune_interval,
+        })
+    }
+}
+
+// Prunes every `interval` seconds, until the server is gone. The Shared is
+// only kept alive by the ws event loop.
+fn pruner(shared: Weak<Mutex<Shared>>, max_age: f32, interval: f32) {
+    let interval = Duration::from_millis((interval * 1000.0) as u64);
+    loop {
+        thread::sleep(interval);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut shared = shared.lock().unwrap();
+        let reports = match shared.rendezvous.prune(max_age as f64) {
+            Ok(usage) => usage.into_iter().map(Report::Usage).collect(),
+            Err(e) => vec![Report::Database(e)],
+        };
+        for report in reports {
+            (shared.reporter)(report);
+        }
+    }
+}
+
+pub struct Server {
+    socket: ws::WebSocket<Factory>,
+    shared: Weak<Mutex<Shared>>,
+    max_age: f32,
+    prune_interval: f32,
+}
+
+impl Server {
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // what clients should use as their relay_url
+    pub fn url(&self) -> io::Result<String> {
+        Ok(format!("ws://{}/v1", self.local_addr()?))
+    }
+
+    // call shutdown() on this (from any thread) to make run() return
+    pub fn stopper(&self) -> ws::Sender {
+        self.socket.broadcaster()
+    }
+
+    // serve until stopped
+    pub fn run(self) -> Result<(), Error> {
+        let shared = self.shared;
+        let (max_age, interval) = (self.max_age, self.prune_interval);
+        thread::spawn(move || pruner(shared, max_age, interval));
+        self.socket.run()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+    use magic_wormhole_io_blocking::Wormhole;
+    use super::ServerBuilder;
+
+    #[test]
+    fn two_clients() {
+        let server = ServerBuilder::new("127.0.0.1:0").build().unwrap();
+        let url = server.url().unwrap();
+        let stopper = server.stopper();
+        let serving = thread::spawn(move || server.run().unwrap());
+        let t = Duration::from_secs(10);
+
+        let a = Wormhole::connect("appid", &url).unwrap();
+        a.allocate_code(2);
+        let code = a.get_code(t).unwrap();
+        let b = Wormhole::connect("appid", &url).unwrap();
+        b.set_code(&code);
+        a.send(b"hello".to_vec());
+        b.send(b"hi".to_vec());
+        assert_eq!(b.receive(t).unwrap(), b"hello".to_vec());
+        assert_eq!(a.receive(t).unwrap(), b"hi".to_vec());
+        assert_eq!(a.verifier(t).unwrap(), b.verifier(t).unwrap());
+        a.close(t).unwrap();
+        b.close(t).unwrap();
+
+        stopper.shutdown().unwrap();
+        serving.join().unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+extern crate magic_wormhole_server;
+
+use std::env;
+use std::process;
+use magic_wormhole_server::ServerBuilder;
+
+const USAGE: &'static str = "usage: magic-wormhole-server [--listen ADDRESS] \
+                             [--database FILE] [--motd TEXT] [--max-age SECS]";
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let mut addr = "127.0.0.1:4000".to_string();
+    let mut options = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(value)) => addr = value,
+            (_, Some(value)) => options.push((arg, value)),
+            (_, None) => usage(),
+        }
+    }
+    let mut builder = ServerBuilder::new(&addr)
+        .reporter(Box::new(|report| println!("{}", report)));
+    for (option, value) in options {
+        builder = match option.as_str() {
+            "--database" => builder.database(value),
+            "--motd" => builder.motd(&value),
+            "--max-age" => match value.parse() {
+                Ok(max_age) => builder.max_age(max_age),
+                Err(_) => usage(),
+            },
+            _ => usage(),
+        };
+    }
+    let server = match builder.build() {
+        Ok(server) => server,
+        Err(e) => {
+            println!("unable to start: {}", e);
+            process::exit(1);
+        }
+    };
+    println!("listening on {}", server.url().unwrap());
+    if let Err(e) = server.run() {
+        println!("server failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,575 @@
+// The part of the Mailbox Server that knows the protocol: who is connected,
+// and what to tell them. Nameplates, mailboxes and messages are kept in the
+// Database. It knows nothing about sockets: connections are numbers, and
+// whatever we want to say to them is queued until the transport collects it
+// with outbound(). Likewise for anything worth reporting, and reports().
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{self, Rng};
+use rusqlite;
+use serde_json::{self, Value};
+use magic_wormhole_core::server_messages::{Message, Nameplate, WelcomeMsg};
+use database::{Database, MessageRecord, Usage};
+use Report;
+
+pub struct Rendezvous {
+    db: Database,
+    motd: Option<String>,
+    connections: HashMap<usize, Connection>,
+    next_connection: usize,
+    outbound: Vec<(usize, String)>,
+    reports: Vec<Report>,
+}
+
+struct Connection {
+    // set by "bind"
+    appid: Option<String>,
+    side: Option<String>,
+    // set by "open", cleared by "close"
+    mailbox: Option<String>,
+}
+
+// why we couldn't do what a client asked
+enum Failure {
+    // its fault: we tell it why
+    Protocol(String),
+    // ours: we only tell it that something went wrong
+    Database(rusqlite::Error),
+}
+
+impl<'a> From<&'a str> for Failure {
+    fn from(e: &'a str) -> Failure {
+        Failure::Protocol(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(e: rusqlite::Error) -> Failure {
+        Failure::Database(e)
+    }
+}
+
+// seconds since the epoch, like the Python server's "server_tx"
+fn now() -> f64 {
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    t.as_secs() as f64 + t.subsec_nanos() as f64 * 1e-9
+}
+
+impl Rendezvous {
+    pub fn new(db: Database, motd: Option<String>) -> Rendezvous {
+        Rendezvous {
+            db: db,
+            motd: motd,
+            connections: HashMap::new(),
+            next_connection: 0,
+            outbound: Vec::new(),
+            reports: Vec::new(),
+        }
+    }
+
+    // a new client connection, which is welcomed right away
+    pub fn connect(&mut self) -> usize {
+        self.next_connection += 1;
+        let c = self.next_connection;
+        self.connections.insert(
+            c,
+            Connection {
+                appid: None,
+                side: None,
+                mailbox: None,
+            },
+        );
+        let welcome = WelcomeMsg {
+            motd: self.motd.clone(),
+            current_cli_version: None,
+            error: None,
+        };
+        self.send(
+            c,
+            &Message::Welcome {
+                server_tx: None,
+                welcome: Some(welcome),
+            },
+        );
+        c
+    }
+
+    // The client's claims and open mailboxes outlive the connection, since
+    // it will probably reconnect. Anything not yet sent to it is dropped.
+    pub fn disconnect(&mut self, c: usize) {
+        self.connections.remove(&c);
+        self.outbound.retain(|&(to, _)| to != c);
+    }
+
+    // everything we want to say, and who to, since last time
+    pub fn outbound(&mut self) -> Vec<(usize, String)> {
+        self.outbound.drain(..).collect()
+    }
+
+    // everything worth reporting since last time
+    pub fn reports(&mut self) -> Vec<Report> {
+        self.reports.drain(..).collect()
+    }
+
+    // every mailbox that has been closed by all of its sides, or pruned
+    pub fn usage(&self) -> rusqlite::Result<Vec<Usage>> {
+        self.db.usage()
+    }
+
+    // Forget mailboxes (and nameplates) that have been idle for more than
+    // max_age seconds. Mailboxes that somebody is connected to are kept,
+    // however quiet they are.
+    pub fn prune(&mut self, max_age: f64) -> rusqlite::Result<Vec<Usage>> {
+        let listening: HashSet<(String, String)> = self.connections
+            .values()
+            .filter_map(|connection| {
+                match (&connection.appid, &connection.mailbox) {
+                    (&Some(ref appid), &Some(ref mailbox)) => {
+                        Some((appid.clone(), mailbox.clone()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        self.db.prune(now(), max_age, &listening)
+    }
+
+    pub fn receive(&mut self, c: usize, text: &str) {
+        let orig: Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => return self.error(c, &e.to_string(), Value::Null),
+        };
+        // we ack everything before looking at it, so the client can tell
+        // how long the round trip took
+        let id = orig["id"].as_str().map(|id| id.to_string());
+        self.send(
+            c,
+            &Message::Ack {
+                id: id,
+                server_tx: None,
+            },
+        );
+        let m: Message = match serde_json::from_value(orig.clone()) {
+            Ok(m) => m,
+            Err(e) => return self.error(c, &e.to_string(), orig),
+        };
+        let result = match m {
+            Message::Bind { appid, side } => self.bind(c, appid, side),
+            Message::Ping { ping } => {
+                self.send(c, &Message::Pong { pong: ping });
+                Ok(())
+            }
+            m => match self.bound(c) {
+                Some((appid, side)) => self.dispatch(c, &appid, &side, m),
+                None => Err("must bind first".into()),
+            },
+        };
+        match result {
+            Ok(()) => {}
+            Err(Failure::Protocol(e)) => self.error(c, &e, orig),
+            Err(Failure::Database(e)) => {
+                self.reports.push(Report::Database(e));
+                self.error(c, "internal error", orig);
+            }
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        m: Message,
+    ) -> Result<(), Failure> {
+        match m {
+            Message::List {} => self.list(c, appid),
+            Message::Allocate {} => self.allocate(c, appid, side),
+            Message::Claim { nameplate } => {
+                self.claim(c, appid, side, &nameplate)
+            }
+            Message::Release { nameplate } => {
+                self.release(c, appid, side, &nameplate)
+            }
+            Message::Open { mailbox } => self.open(c, appid, side, &mailbox),
+            Message::Add { phase, body } => {
+                self.add(c, appid, side, &phase, &body)
+            }
+            Message::Close { mailbox, mood } => {
+                self.close(c, appid, side, &mailbox, &mood)
+            }
+            m => Err(Failure::Protocol(format!("unknown type {}", m.kind()))),
+        }
+    }
+
+    fn bound(&self, c: usize) -> Option<(String, String)> {
+        let connection = &self.connections[&c];
+        match (&connection.appid, &connection.side) {
+            (&Some(ref appid), &Some(ref side)) => {
+                Some((appid.clone(), side.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn bind(
+        &mut self,
+        c: usize,
+        appid: String,
+        side: String,
+    ) -> Result<(), Failure> {
+        let connection = self.connections.get_mut(&c).unwrap();
+        if connection.appid.is_some() {
+            return Err("already bound".into());
+        }
+        connection.appid = Some(appid);
+        connection.side = Some(side);
+        Ok(())
+    }
+
+    fn list(&mut self, c: usize, appid: &str) -> Result<(), Failure> {
+        let ids = self.db.nameplates(appid)?;
+        let nameplates = ids.into_iter().map(|id| Nameplate { id: id });
+        self.send(
+            c,
+            &Message::Nameplates {
+                nameplates: nameplates.collect(),
+            },
+        );
+        Ok(())
+    }
+
+    fn allocate(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+    ) -> Result<(), Failure> {
+        // a random one from the shortest range (1-9, 10-99, ..) that has
+        // any left, so codes stay short while the server is quiet
+        let nameplate = {
+            let taken: HashSet<String> =
+                self.db.nameplates(appid)?.into_iter().collect();
+            let mut rng = rand::thread_rng();
+            let mut low: u32 = 1;
+            loop {
+                let high = low * 10;
+                let free: Vec<String> = (low..high)
+                    .map(|n| n.to_string())
+                    .filter(|n| !taken.contains(n))
+                    .collect();
+                if !free.is_empty() {
+                    break free[rng.gen_range(0, free.len())].clone();
+                }
+                low = high;
+            }
+        };
+        // allocation implies a claim
+        self.db.claim_nameplate(appid, &nameplate, side, now())?;
+        self.send(
+            c,
+            &Message::Allocated {
+                nameplate: nameplate,
+            },
+        );
+        Ok(())
+    }
+
+    fn claim(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        nameplate: &str,
+    ) -> Result<(), Failure> {
+        match self.db.claim_nameplate(appid, nameplate, side, now())? {
+            Some(mailbox) => {
+                self.send(c, &Message::Claimed { mailbox: mailbox });
+                Ok(())
+            }
+            None => Err("crowded".into()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        nameplate: &str,
+    ) -> Result<(), Failure> {
+        self.db.release_nameplate(appid, nameplate, side)?;
+        self.send(c, &Message::Released {});
+        Ok(())
+    }
+
+    fn open(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        mailbox: &str,
+    ) -> Result<(), Failure> {
+        if self.connections[&c].mailbox.is_some() {
+            return Err("only one open per connection".into());
+        }
+        let messages = match self.db.open_mailbox(appid, mailbox, side, now())?
+        {
+            Some(messages) => messages,
+            None => return Err("crowded".into()),
+        };
+        self.connections.get_mut(&c).unwrap().mailbox =
+            Some(mailbox.to_string());
+        // everything that was added before we opened it
+        for m in messages {
+            self.send_message(c, &m);
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        phase: &str,
+        body: &str,
+    ) -> Result<(), Failure> {
+        let mailbox = match self.connections[&c].mailbox {
+            Some(ref mailbox) => mailbox.clone(),
+            None => return Err("must open mailbox before adding".into()),
+        };
+        let m = self.db
+            .add_message(appid, &mailbox, side, phase, body, now())?;
+        // to everybody who has this mailbox open, including the sender
+        let listeners: Vec<usize> = self.connections
+            .iter()
+            .filter(|&(_, connection)| {
+                connection.appid.as_ref().map(|a| a.as_str()) == Some(appid)
+                    && connection.mailbox.as_ref() == Some(&mailbox)
+            })
+            .map(|(&c, _)| c)
+            .collect();
+        for listener in listeners {
+            self.send_message(listener, &m);
+        }
+        Ok(())
+    }
+
+    fn close(
+        &mut self,
+        c: usize,
+        appid: &str,
+        side: &str,
+        mailbox: &str,
+        mood: &str,
+    ) -> Result<(), Failure> {
+        let usage = self.db.close_mailbox(appid, mailbox, side, mood, now())?;
+        if let Some(usage) = usage {
+            self.reports.push(Report::Usage(usage));
+        }
+        self.connections.get_mut(&c).unwrap().mailbox = None;
+        self.send(c, &Message::Closed {});
+        Ok(())
+    }
+
+    fn error(&mut self, c: usize, error: &str, orig: Value) {
+        self.send(
+            c,
+            &Message::Error {
+                error: error.to_string(),
+                orig: orig,
+            },
+        );
+    }
+
+    fn send_message(&mut self, c: usize, m: &MessageRecord) {
+        let mut v = serde_json::to_value(&Message::Message {
+            side: m.side.clone(),
+            phase: m.phase.clone(),
+            body: m.body.clone(),
+        }).unwrap();
+        v["id"] = json!(m.id.to_string());
+        self.send_value(c, v);
+    }
+
+    fn send(&mut self, c: usize, m: &Message) {
+        self.send_value(c, serde_json::to_value(m).unwrap());
+    }
+
+    fn send_value(&mut self, c: usize, mut m: Value) {
+        m["server_tx"] = json!(now());
+        self.outbound.push((c, m.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{self, Value};
+    use database::Database;
+    use super::Rendezvous;
+    use Report;
+
+    // what each connection was sent, in order
+    fn outbound(r: &mut Rendezvous, c: usize) -> Vec<Value> {
+        r.outbound()
+            .into_iter()
+            .filter(|&(to, _)| to == c)
+            .map(|(_, text)| serde_json::from_str(&text).unwrap())
+            .collect()
+    }
+
+    fn types(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|m| m["type"].as_str().unwrap())
+            .collect()
+    }
+
+    fn bound(r: &mut Rendezvous, side: &str) -> usize {
+        let c = r.connect();
+        r.receive(
+            c,
+            &json!({"type": "bind", "appid": "appid", "side": side})
+                .to_string(),
+        );
+        r.outbound();
+        c
+    }
+
+    #[test]
+    fn welcome() {
+        let mut r = Rendezvous::new(
+            Database::in_memory().unwrap(),
+            Some("hello".to_string()),
+        );
+        let c = r.connect();
+        let out = outbound(&mut r, c);
+        assert_eq!(types(&out), vec!["welcome"]);
+        assert_eq!(out[0]["welcome"]["motd"], json!("hello"));
+        assert!(out[0]["server_tx"].is_f64());
+    }
+
+    #[test]
+    fn ack_and_errors() {
+        let mut r = Rendezvous::new(Database::in_memory().unwrap(), None);
+        let c = r.connect();
+        r.outbound();
+        r.receive(c, r#"{"type": "list", "id": "abcd"}"#);
+        let out = outbound(&mut r, c);
+        assert_eq!(types(&out), vec!["ack", "error"]);
+        assert_eq!(out[0]["id"], json!("abcd"));
+        assert_eq!(out[1]["error"], json!("must bind first"));
+        assert_eq!(out[1]["orig"]["type"], json!("list"));
+
+        r.receive(c, r#"{"type": "ping", "ping": 5}"#);
+        let out = outbound(&mut r, c);
+        assert_eq!(types(&out), vec!["ack", "pong"]);
+        assert_eq!(out[1]["pong"], json!(5));
+    }
+
+    #[test]
+    fn conversation() {
+        let mut r = Rendezvous::new(Database::in_memory().unwrap(), None);
+        let a = bound(&mut r, "sideA");
+        let b = bound(&mut r, "sideB");
+
+        r.receive(a, r#"{"type": "allocate"}"#);
+        let out = outbound(&mut r, a);
+        assert_eq!(types(&out), vec!["ack", "allocated"]);
+        let nameplate = out[1]["nameplate"].as_str().unwrap().to_string();
+        assert_eq!(nameplate.len(), 1);
+
+        r.receive(b, r#"{"type": "list"}"#);
+        let out = outbound(&mut r, b);
+        assert_eq!(out[1]["nameplates"], json!([{ "id": nameplate }]));
+
+        let claim = json!({"type": "claim", "nameplate": nameplate});
+        r.receive(a, &claim.to_string());
+        r.receive(b, &claim.to_string());
+        let out = r.outbound();
+        let claimed: Vec<Value> = out.iter()
+            .map(|&(_, ref text)| serde_json::from_str(text).unwrap())
+            .filter(|m: &Value| m["type"] == json!("claimed"))
+            .collect();
+        assert_eq!(claimed.len(), 2);
+        assert_eq!(claimed[0]["mailbox"], claimed[1]["mailbox"]);
+        let mailbox = claimed[0]["mailbox"].clone();
+
+        // a third side is turned away
+        let c = bound(&mut r, "sideC");
+        r.receive(c, &claim.to_string());
+        assert_eq!(outbound(&mut r, c)[1]["error"], json!("crowded"));
+
+        // messages added before B opens are replayed to it
+        let open = json!({"type": "open", "mailbox": mailbox});
+        r.receive(a, &open.to_string());
+        r.receive(a, r#"{"type": "add", "phase": "pake", "body": "0102"}"#);
+        let out = outbound(&mut r, a);
+        assert_eq!(types(&out), vec!["ack", "ack", "message"]);
+        r.receive(b, &open.to_string());
+        let out = outbound(&mut r, b);
+        assert_eq!(types(&out), vec!["ack", "message"]);
+        assert_eq!(out[1]["side"], json!("sideA"));
+        assert_eq!(out[1]["phase"], json!("pake"));
+        assert_eq!(out[1]["body"], json!("0102"));
+
+        // and later ones go to both
+        r.receive(b, r#"{"type": "add", "phase": "pake", "body": "03"}"#);
+        let out = r.outbound();
+        let to: Vec<usize> = out.iter()
+            .filter(|&&(_, ref text)| text.contains("\"message\""))
+            .map(|&(to, _)| to)
+            .collect();
+        assert_eq!(to.len(), 2);
+        assert!(to.contains(&a) && to.contains(&b));
+
+        let release = json!({"type": "release", "nameplate": nameplate});
+        r.receive(a, &release.to_string());
+        r.receive(b, &release.to_string());
+        r.outbound();
+        r.receive(b, r#"{"type": "list"}"#);
+        assert_eq!(outbound(&mut r, b)[1]["nameplates"], json!([]));
+
+        let close = json!({"type": "close", "mailbox": mailbox,
+                           "mood": "happy"});
+        r.receive(a, &close.to_string());
+        assert_eq!(types(&outbound(&mut r, a)), vec!["ack", "closed"]);
+        assert_eq!(r.usage().unwrap().len(), 0);
+        assert_eq!(r.reports().len(), 0);
+        r.receive(b, &close.to_string());
+        assert_eq!(r.usage().unwrap().len(), 1);
+        match r.reports()[..] {
+            [Report::Usage(ref u)] => assert_eq!(*u, r.usage().unwrap()[0]),
+            _ => panic!(),
+        }
+        assert_eq!(r.usage().unwrap()[0].result, "happy");
+        assert!(r.usage().unwrap()[0].waiting_time.is_some());
+    }
+
+    #[test]
+    fn moods() {
+        let mut r = Rendezvous::new(Database::in_memory().unwrap(), None);
+        let a = bound(&mut r, "sideA");
+        let b = bound(&mut r, "sideB");
+        let open = json!({"type": "open", "mailbox": "mailbox1"});
+        let close = json!({"type": "close", "mailbox": "mailbox1",
+                           "mood": "lonely"});
+        r.receive(a, &open.to_string());
+        r.receive(a, &close.to_string());
+        assert_eq!(r.usage().unwrap()[0].result, "lonely");
+        assert_eq!(r.usage().unwrap()[0].waiting_time, None);
+
+        // the worst mood wins
+        let open = json!({"type": "open", "mailbox": "mailbox2"});
+        let happy = json!({"type": "close", "mailbox": "mailbox2",
+                           "mood": "happy"});
+        let scary = json!({"type": "close", "mailbox": "mailbox2",
+                           "mood": "scary"});
+        r.receive(a, &open.to_string());
+        r.receive(b, &open.to_string());
+        r.receive(a, &happy.to_string());
+        r.receive(b, &scary.to_string());
+        assert_eq!(r.usage().unwrap()[1].result, "scary");
+    }
+}
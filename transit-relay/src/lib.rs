@@ -0,0 +1,626 @@
+// A Transit relay server: for when neither side of a Transit connection can
+// reach the other directly. Each side connects to us and says
+//
+//     please relay TOKEN for side SIDE\n
+//
+// and once two connections with the same token (and different sides) have
+// arrived, we say "ok\n" to both and copy bytes between them until either
+// one closes, or they go over our limits. Every connection (or pair of
+// them) leaves a Usage record behind.
+//
+//     let relay = RelayBuilder::new("127.0.0.1:4001").build()?;
+//     relay.run();
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+extern crate magic_wormhole_transit;
+
+// what happened to a connection (or a pair of them)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Usage {
+    // seconds since the epoch
+    pub started: f64,
+    // how long the first side waited for the second, once paired
+    pub waiting_time: Option<f64>,
+    pub total_time: f64,
+    // forwarded, in both directions
+    pub total_bytes: u64,
+    // "happy", "lonely" (nobody showed up), "errory" (bad handshake), or
+    // "limited" (cut off for going over max_bytes or max_duration)
+    pub result: String,
+}
+
+// seconds since the epoch
+fn now() -> f64 {
+    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    t.as_secs() as f64 + t.subsec_nanos() as f64 * 1e-9
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn duration(seconds: f32) -> Duration {
+    Duration::from_millis((seconds * 1000.0) as u64)
+}
+
+// a connection that has said hello, waiting for its partner
+struct Pending {
+    id: usize,
+    side: Option<String>,
+    stream: TcpStream,
+    started: (f64, Instant),
+    // told when the partner has taken over
+    paired: mpsc::Sender<()>,
+}
+
+struct Shared {
+    max_bytes: Option<u64>,
+    max_duration: Option<Duration>,
+    // waiting connections, by token
+    pending: Mutex<HashMap<String, Vec<Pending>>>,
+    next_id: AtomicUsize,
+    usage: Mutex<Vec<Usage>>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn record(&self, usage: Usage) {
+        println!("relay: {:?}", usage);
+        self.usage.lock().unwrap().push(usage);
+    }
+
+    fn remaining(&self, since: Instant) -> Option<Duration> {
+        self.max_duration.map(|max| {
+            let elapsed = since.elapsed();
+            if elapsed < max {
+                max - elapsed
+            } else {
+                // zero would mean "no timeout"
+                Duration::from_millis(1)
+            }
+        })
+    }
+
+    fn handle(&self, mut stream: TcpStream) {
+        let started = (now(), Instant::now());
+        let errory = |total_bytes| Usage {
+            started: started.0,
+            waiting_time: None,
+            total_time: seconds(started.1.elapsed()),
+            total_bytes: total_bytes,
+            result: "errory".to_string(),
+        };
+        let (token, side) = match read_handshake(&stream) {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) | Err(_) => {
+                let _ = stream.write_all(b"bad handshake\n");
+                return self.record(errory(0));
+            }
+        };
+
+        let partner = {
+            let mut pending = self.pending.lock().unwrap();
+            let waiting = pending.entry(token.clone()).or_insert_with(Vec::new);
+            match waiting.iter().position(|p| pairs_with(&p.side, &side)) {
+                Some(i) => Some(waiting.remove(i)),
+                None => {
+                    let (tx, rx) = mpsc::channel();
+                    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                    let copy = match stream.try_clone() {
+                        Ok(copy) => copy,
+                        Err(_) => return self.record(errory(0)),
+                    };
+                    waiting.push(Pending {
+                        id: id,
+                        side: side,
+                        stream: copy,
+                        started: started,
+                        paired: tx,
+                    });
+                    drop(pending);
+                    return self.wait(&token, id, stream, started, rx);
+                }
+            }
+        };
+        if let Some(partner) = partner {
+            let _ = partner.paired.send(());
+            let waiting_time = seconds(started.1 - partner.started.1);
+            self.pipe(partner.stream, stream, partner.started, waiting_time);
+        }
+    }
+
+    // until our partner shows up (and takes over), or we give up
+    fn wait(
+        &self,
+        token: &str,
+        id: usize,
+        stream: TcpStream,
+        started: (f64, Instant),
+        paired: mpsc::Receiver<()>,
+    ) {
+        loop {
+            // every so often, see if our client has hung up
+            let step = match self.remaining(started.1) {
+                Some(remaining) => remaining.min(Duration::from_secs(1)),
+                None => Duration::from_secs(1),
+            };
+            if paired.recv_timeout(step).is_ok() {
+                return;
+            }
+            let expired = self.max_duration
+                .map_or(false, |max| started.1.elapsed() >= max);
+            // While our entry is pending, nobody else touches the stream.
+            // Once it's gone, the partner has taken over.
+            let mut pending = self.pending.lock().unwrap();
+            let i = match pending
+                .get(token)
+                .and_then(|waiting| waiting.iter().position(|p| p.id == id))
+            {
+                Some(i) => i,
+                None => {
+                    drop(pending);
+                    let _ = paired.recv();
+                    return;
+                }
+            };
+            if !expired && !hung_up(&stream) {
+                continue;
+            }
+            let empty = {
+                let waiting = pending.get_mut(token).unwrap();
+                waiting.remove(i);
+                waiting.is_empty()
+            };
+            if empty {
+                pending.remove(token);
+            }
+            drop(pending);
+            let _ = stream.shutdown(Shutdown::Both);
+            return self.record(Usage {
+                started: started.0,
+                waiting_time: None,
+                total_time: seconds(started.1.elapsed()),
+                total_bytes: 0,
+                result: "lonely".to_string(),
+            });
+        }
+    }
+
+    fn pipe(
+        &self,
+        a: TcpStream,
+        b: TcpStream,
+        started: (f64, Instant),
+        waiting_time: f64,
+    ) {
+        let ok = (&a)
+            .write_all(b"ok\n")
+            .and_then(|_| (&b).write_all(b"ok\n"));
+        let copies = ok.and_then(|_| {
+            Ok((a.try_clone()?, b.try_clone()?, b.try_clone()?, a.try_clone()?))
+        });
+        let (a_in, b_out, b_in, a_out) = match copies {
+            Ok(copies) => copies,
+            Err(_) => {
+                let _ = a.shutdown(Shutdown::Both);
+                let _ = b.shutdown(Shutdown::Both);
+                return self.record(Usage {
+                    started: started.0,
+                    waiting_time: Some(waiting_time),
+                    total_time: seconds(started.1.elapsed()),
+                    total_bytes: 0,
+                    result: "errory".to_string(),
+                });
+            }
+        };
+        let counter = Arc::new(Counter {
+            bytes: AtomicUsize::new(0),
+            limited: AtomicBool::new(false),
+        });
+        let limits = (self.max_bytes, self.remaining(started.1));
+        let other = {
+            let counter = Arc::clone(&counter);
+            thread::spawn(move || copy(b_in, a_out, &counter, limits))
+        };
+        copy(a_in, b_out, &counter, limits);
+        let _ = other.join();
+        let limited = counter.limited.load(Ordering::SeqCst);
+        self.record(Usage {
+            started: started.0,
+            waiting_time: Some(waiting_time),
+            total_time: seconds(started.1.elapsed()),
+            total_bytes: counter.bytes.load(Ordering::SeqCst) as u64,
+            result: if limited { "limited" } else { "happy" }.to_string(),
+        });
+    }
+}
+
+struct Counter {
+    bytes: AtomicUsize,
+    limited: AtomicBool,
+}
+
+// true if the other end has closed (or broken) the connection. Anything it
+// has sent is left for later.
+fn hung_up(stream: &TcpStream) -> bool {
+    if stream.set_read_timeout(Some(Duration::from_millis(1))).is_err() {
+        return true;
+    }
+    let mut buf = [0u8; 1];
+    let closed = match stream.peek(&mut buf) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(ref e) => {
+            e.kind() != io::ErrorKind::WouldBlock
+                && e.kind() != io::ErrorKind::TimedOut
+        }
+    };
+    stream.set_read_timeout(None).is_err() || closed
+}
+
+// Copies until `from` closes or we hit a limit, then closes both (which
+// stops the copy going the other way too).
+fn copy(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    counter: &Counter,
+    limits: (Option<u64>, Option<Duration>),
+) {
+    let (max_bytes, max_duration) = limits;
+    let deadline = max_duration.map(|d| Instant::now() + d);
+    let mut buf = [0u8; 16384];
+    if from.set_read_timeout(None).is_err() {
+        return;
+    }
+    loop {
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                counter.limited.store(true, Ordering::SeqCst);
+                break;
+            }
+            if from.set_read_timeout(Some(deadline - now)).is_err() {
+                break;
+            }
+        }
+        let n = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                counter.limited.store(true, Ordering::SeqCst);
+                break;
+            }
+            Err(_) => break,
+        };
+        let allowed = match max_bytes {
+            Some(max) => take(&counter.bytes, n, max as usize),
+            None => {
+                counter.bytes.fetch_add(n, Ordering::SeqCst);
+                n
+            }
+        };
+        if to.write_all(&buf[..allowed]).is_err() {
+            counter.bytes.fetch_sub(allowed, Ordering::SeqCst);
+            break;
+        }
+        if allowed < n {
+            counter.limited.store(true, Ordering::SeqCst);
+            break;
+        }
+    }
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+// Two connections with the same token are paired unless they're from the
+// same side. Older clients don't say which side they are, so they pair with
+// anybody.
+fn pairs_with(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (&Some(ref a), &Some(ref b)) => a != b,
+        _ => true,
+    }
+}
+
+// Counts up to `n` more bytes (shared by both directions), without going
+// over `max`. Returns how many that was.
+fn take(bytes: &AtomicUsize, n: usize, max: usize) -> usize {
+    let mut total = bytes.load(Ordering::SeqCst);
+    loop {
+        let allowed = n.min(max.saturating_sub(total));
+        match bytes.compare_exchange(
+            total,
+            total + allowed,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => return allowed,
+            Err(current) => total = current,
+        }
+    }
+}
+
+// "please relay TOKEN for side SIDE\n", or the older "please relay TOKEN\n"
+// (without a side). None if it's something else, or if the connection
+// closes before the newline.
+fn read_handshake(
+    stream: &TcpStream,
+) -> io::Result<Option<(String, Option<String>)>> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut line = Vec::new();
+    let mut complete = false;
+    for byte in stream.bytes() {
+        let byte = byte?;
+        if byte == b'\n' {
+            complete = true;
+            break;
+        }
+        line.push(byte);
+        // a token and side are 64 + 16 hex digits
+        if line.len() > 200 {
+            return Ok(None);
+        }
+    }
+    if !complete {
+        return Ok(None);
+    }
+    stream.set_read_timeout(None)?;
+    let line = match String::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Ok(None),
+    };
+    let words: Vec<&str> = line.split(' ').collect();
+    let handshake = match words.as_slice() {
+        ["please", "relay", token, "for", "side", side] => {
+            (token.to_string(), Some(side.to_string()))
+        }
+        ["please", "relay", token] => (token.to_string(), None),
+        _ => return Ok(None),
+    };
+    Ok(Some(handshake))
+}
+
+pub struct RelayBuilder {
+    addr: String,
+    max_bytes: Option<u64>,
+    max_duration: Option<f32>,
+}
+
+impl RelayBuilder {
+    // "127.0.0.1:0" picks a free port: use local_addr() to find out which
+    pub fn new(addr: &str) -> RelayBuilder {
+        RelayBuilder {
+            addr: addr.to_string(),
+            max_bytes: None,
+            max_duration: None,
+        }
+    }
+
+    // the most a pair of connections may send (in both directions)
+    pub fn max_bytes(mut self, max_bytes: Option<u64>) -> RelayBuilder {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    // the longest (in seconds) a connection may last, waiting included
+    pub fn max_duration(mut self, seconds: Option<f32>) -> RelayBuilder {
+        self.max_duration = seconds;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Relay> {
+        Ok(Relay {
+            listener: TcpListener::bind(self.addr.as_str())?,
+            shared: Arc::new(Shared {
+                max_bytes: self.max_bytes,
+                max_duration: self.max_duration.map(duration),
+                pending: Mutex::new(HashMap::new()),
+                next_id: AtomicUsize::new(0),
+                usage: Mutex::new(Vec::new()),
+                stopped: AtomicBool::new(false),
+            }),
+        })
+    }
+}
+
+// Can be shared between threads: one calls run(), and others can look at
+// usage() or stop() it.
+pub struct Relay {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Relay {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // every connection (or pair) that has finished so far
+    pub fn usage(&self) -> Vec<Usage> {
+        self.shared.usage.lock().unwrap().clone()
+    }
+
+    // each connection gets a thread of its own
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            if self.shared.stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            match stream {
+                Ok(stream) => {
+                    let shared = Arc::clone(&self.shared);
+                    thread::spawn(move || shared.handle(stream));
+                }
+                Err(e) => println!("relay: unable to accept: {}", e),
+            }
+        }
+    }
+
+    // makes run() return, but leaves connections already paired alone
+    pub fn stop(&self) -> io::Result<()> {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // wake it up
+        TcpStream::connect(self.local_addr()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use magic_wormhole_transit::{Role, Transit};
+    use super::{Relay, RelayBuilder, Usage};
+
+    fn start(builder: RelayBuilder) -> Arc<Relay> {
+        let relay = Arc::new(builder.build().unwrap());
+        let running = Arc::clone(&relay);
+        thread::spawn(move || running.run());
+        relay
+    }
+
+    // waits (a little) for the relay to finish with a connection
+    fn usage(relay: &Relay, n: usize) -> Vec<Usage> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while relay.usage().len() < n && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        relay.usage()
+    }
+
+    fn hello(relay: &Relay, side: &str) -> TcpStream {
+        let mut s = TcpStream::connect(relay.local_addr().unwrap()).unwrap();
+        let line = format!("please relay {} for side {}\n", "ab12", side);
+        s.write_all(line.as_bytes()).unwrap();
+        s
+    }
+
+    fn read(s: &mut TcpStream, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        s.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn transit() {
+        let relay = start(RelayBuilder::new("127.0.0.1:0"));
+        let port = relay.local_addr().unwrap().port();
+        let key = [7; 32];
+        // only the relay: neither side tells the other where it is
+        let nothing = br#"{"transit": {"abilities-v1": [], "hints-v1": []}}"#;
+        let mut sender = Transit::new(Role::Sender, &key).unwrap();
+        let mut receiver = Transit::new(Role::Receiver, &key).unwrap();
+        for t in vec![&mut sender, &mut receiver] {
+            t.add_relay("127.0.0.1", port);
+            t.set_relay_delay(0.0);
+            t.add_their_hints(nothing).unwrap();
+        }
+        let timeout = Duration::from_secs(10);
+        let receiving = thread::spawn(move || {
+            let mut c = receiver.connect(timeout).unwrap();
+            c.receive_record().unwrap()
+        });
+        let mut c = sender.connect(timeout).unwrap();
+        c.send_record(b"hello").unwrap();
+        assert_eq!(receiving.join().unwrap(), b"hello".to_vec());
+        drop(c);
+
+        let usage = usage(&relay, 1);
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].result, "happy");
+        assert!(usage[0].total_bytes > 0);
+        assert!(usage[0].waiting_time.is_some());
+        relay.stop().unwrap();
+    }
+
+    #[test]
+    fn bad_handshake() {
+        let relay = start(RelayBuilder::new("127.0.0.1:0"));
+        let mut s = TcpStream::connect(relay.local_addr().unwrap()).unwrap();
+        s.write_all(b"please send me everything\n").unwrap();
+        assert_eq!(read(&mut s, 14), b"bad handshake\n".to_vec());
+        assert_eq!(usage(&relay, 1)[0].result, "errory");
+    }
+
+    #[test]
+    fn unfinished_handshake() {
+        let relay = start(RelayBuilder::new("127.0.0.1:0"));
+        let mut s = TcpStream::connect(relay.local_addr().unwrap()).unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        // a handshake without its newline isn't one
+        s.write_all(b"please relay ab12").unwrap();
+        s.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(read(&mut s, 14), b"bad handshake\n".to_vec());
+        assert_eq!(usage(&relay, 1)[0].result, "errory");
+    }
+
+    #[test]
+    fn same_side() {
+        let relay = start(
+            RelayBuilder::new("127.0.0.1:0").max_duration(Some(0.5)),
+        );
+        // two connections from the same side aren't paired
+        let mut a = hello(&relay, "aaaa");
+        let _b = hello(&relay, "aaaa");
+        let mut buf = [0u8; 1];
+        assert_eq!(a.read(&mut buf).unwrap(), 0);
+        let usage = usage(&relay, 2);
+        assert_eq!(usage[0].result, "lonely");
+        assert_eq!(usage[1].result, "lonely");
+    }
+
+    #[test]
+    fn legacy_handshakes() {
+        let relay = start(RelayBuilder::new("127.0.0.1:0"));
+        let legacy = || {
+            let addr = relay.local_addr().unwrap();
+            let mut s = TcpStream::connect(addr).unwrap();
+            s.write_all(b"please relay ab12\n").unwrap();
+            s
+        };
+        // neither says which side it is, so they're paired with each other
+        let mut a = legacy();
+        let mut b = legacy();
+        assert_eq!(read(&mut a, 3), b"ok\n".to_vec());
+        assert_eq!(read(&mut b, 3), b"ok\n".to_vec());
+        a.write_all(b"hello").unwrap();
+        assert_eq!(read(&mut b, 5), b"hello".to_vec());
+        drop(a);
+        drop(b);
+        assert_eq!(usage(&relay, 1)[0].result, "happy");
+    }
+
+    #[test]
+    fn byte_limit() {
+        let relay = start(RelayBuilder::new("127.0.0.1:0").max_bytes(Some(10)));
+        let mut a = hello(&relay, "aaaa");
+        let mut b = hello(&relay, "bbbb");
+        assert_eq!(read(&mut a, 3), b"ok\n".to_vec());
+        assert_eq!(read(&mut b, 3), b"ok\n".to_vec());
+        a.write_all(b"12345").unwrap();
+        assert_eq!(read(&mut b, 5), b"12345".to_vec());
+        // only as much as fits under the limit gets through
+        a.write_all(b"abcdefghijkl").unwrap();
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"abcde".to_vec());
+        let usage = usage(&relay, 1);
+        assert_eq!(usage[0].result, "limited");
+        assert_eq!(usage[0].total_bytes, 10);
+    }
+}
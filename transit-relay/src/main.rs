@@ -0,0 +1,50 @@
+extern crate magic_wormhole_transit_relay;
+
+use std::env;
+use std::process;
+use magic_wormhole_transit_relay::RelayBuilder;
+
+const USAGE: &'static str = "usage: magic-wormhole-transit-relay \
+                             [--listen ADDRESS] [--max-bytes BYTES] \
+                             [--max-duration SECS]";
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let mut addr = "127.0.0.1:4001".to_string();
+    let mut options = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(value)) => addr = value,
+            (_, Some(value)) => options.push((arg, value)),
+            (_, None) => usage(),
+        }
+    }
+    let mut builder = RelayBuilder::new(&addr);
+    for (option, value) in options {
+        builder = match option.as_str() {
+            "--max-bytes" => match value.parse() {
+                Ok(max_bytes) => builder.max_bytes(Some(max_bytes)),
+                Err(_) => usage(),
+            },
+            "--max-duration" => match value.parse() {
+                Ok(max_duration) => builder.max_duration(Some(max_duration)),
+                Err(_) => usage(),
+            },
+            _ => usage(),
+        };
+    }
+    let relay = match builder.build() {
+        Ok(relay) => relay,
+        Err(e) => {
+            println!("unable to listen on {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    println!("relaying on {}", relay.local_addr().unwrap());
+    relay.run();
+}
@@ -0,0 +1,174 @@
+// The "transit" message each side sends the other (over the wormhole) to
+// say how it can be reached:
+//
+//     {"transit": {"abilities-v1": [{"type": "direct-tcp-v1"},
+//                                   {"type": "relay-v1"}],
+//                  "hints-v1": [{"type": "direct-tcp-v1",
+//                                "hostname": "192.168.1.5", "port": 4321,
+//                                "priority": 0.0},
+//                               {"type": "relay-v1",
+//                                "hints": [{"type": "direct-tcp-v1", ..}]}]}}
+//
+// Abilities and hints of types we don't know (e.g. "tor-tcp-v1") are
+// ignored, including inside a relay hint.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DirectHint {
+    pub hostname: String,
+    pub port: u16,
+    #[serde(default)]
+    pub priority: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type")]
+pub enum Ability {
+    #[serde(rename = "direct-tcp-v1")]
+    DirectTcpV1,
+    #[serde(rename = "relay-v1")]
+    RelayV1,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type")]
+pub enum Hint {
+    #[serde(rename = "direct-tcp-v1")]
+    DirectTcpV1(DirectHint),
+    #[serde(rename = "relay-v1")]
+    RelayV1 {
+        #[serde(serialize_with = "serialize_relay_hints",
+                deserialize_with = "deserialize_relay_hints")]
+        hints: Vec<DirectHint>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// the hints inside a relay hint carry their own "type", just like the
+// top-level ones
+fn serialize_relay_hints<S>(
+    hints: &[DirectHint],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let tagged: Vec<Hint> =
+        hints.iter().cloned().map(Hint::DirectTcpV1).collect();
+    tagged.serialize(serializer)
+}
+
+fn deserialize_relay_hints<'de, D>(
+    deserializer: D,
+) -> Result<Vec<DirectHint>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tagged = Vec::<Hint>::deserialize(deserializer)?;
+    Ok(tagged
+        .into_iter()
+        .filter_map(|hint| match hint {
+            Hint::DirectTcpV1(hint) => Some(hint),
+            _ => None,
+        })
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TransitMsg {
+    #[serde(rename = "abilities-v1")]
+    pub abilities: Vec<Ability>,
+    #[serde(rename = "hints-v1")]
+    pub hints: Vec<Hint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TransitMessage {
+    pub transit: TransitMsg,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use super::*;
+
+    #[test]
+    fn test_python_hints() {
+        let s = r#"{"transit": {
+            "abilities-v1": [{"type": "direct-tcp-v1"}, {"type": "relay-v1"},
+                             {"type": "tor-tcp-v1"}],
+            "hints-v1": [
+                {"type": "direct-tcp-v1", "hostname": "10.0.0.1",
+                 "port": 4321, "priority": 0.0},
+                {"type": "tor-tcp-v1", "hostname": "abc.onion", "port": 80},
+                {"type": "relay-v1", "hints": [
+                    {"type": "direct-tcp-v1", "hostname": "relay.example",
+                     "port": 4001, "priority": 2.0},
+                    {"type": "tor-tcp-v1", "hostname": "relay.onion",
+                     "port": 4001}]}]}}"#;
+        let m: TransitMessage = serde_json::from_str(s).unwrap();
+        assert_eq!(
+            m.transit.abilities,
+            vec![Ability::DirectTcpV1, Ability::RelayV1, Ability::Unknown]
+        );
+        assert_eq!(
+            m.transit.hints,
+            vec![
+                Hint::DirectTcpV1(DirectHint {
+                    hostname: "10.0.0.1".to_string(),
+                    port: 4321,
+                    priority: 0.0,
+                }),
+                Hint::Unknown,
+                Hint::RelayV1 {
+                    hints: vec![DirectHint {
+                        hostname: "relay.example".to_string(),
+                        port: 4001,
+                        priority: 2.0,
+                    }],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let m = TransitMessage {
+            transit: TransitMsg {
+                abilities: vec![Ability::DirectTcpV1, Ability::RelayV1],
+                hints: vec![
+                    Hint::DirectTcpV1(DirectHint {
+                        hostname: "10.0.0.1".to_string(),
+                        port: 4321,
+                        priority: 0.0,
+                    }),
+                    Hint::RelayV1 {
+                        hints: vec![DirectHint {
+                            hostname: "relay.example".to_string(),
+                            port: 4001,
+                            priority: 2.0,
+                        }],
+                    },
+                ],
+            },
+        };
+        let v = serde_json::to_value(&m).unwrap();
+        assert_eq!(
+            v,
+            json!({"transit": {
+                "abilities-v1": [{"type": "direct-tcp-v1"},
+                                 {"type": "relay-v1"}],
+                "hints-v1": [
+                    {"type": "direct-tcp-v1", "hostname": "10.0.0.1",
+                     "port": 4321, "priority": 0.0},
+                    {"type": "relay-v1", "hints": [
+                        {"type": "direct-tcp-v1", "hostname": "relay.example",
+                         "port": 4001, "priority": 2.0}]}]}})
+        );
+        assert_eq!(serde_json::from_value::<TransitMessage>(v).unwrap(), m);
+    }
+}
@@ -0,0 +1,589 @@
+// Transit: a TCP connection between the two sides of a wormhole, for data
+// too big to go through the mailbox. Each side listens on a random port and
+// tells the other (in a "transit" message, sent over the wormhole) which
+// addresses it might be reachable at, and which relay servers it knows of.
+// Then both sides try every route at once, and the first connection to
+// finish the handshake wins. Records sent over it are encrypted with keys
+// derived from the wormhole's.
+//
+//     let purpose = transit_key_purpose(APPID);
+//     let key = w.derive_key(&purpose, 32, timeout)?;
+//     let mut transit = Transit::new(Role::Sender, &key)?;
+//     transit.add_relay("transit.magic-wormhole.io", 4001);
+//     w.send(transit.our_hints());
+//     transit.add_their_hints(&w.receive(timeout)?)?;
+//     let mut connection = transit.connect(Duration::from_secs(30))?;
+//     connection.send_record(b"hello")?;
+
+extern crate get_if_addrs;
+extern crate hex;
+extern crate hkdf;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+extern crate sha2;
+extern crate sodiumoxide;
+
+mod hints;
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes;
+pub use hints::{Ability, DirectHint, Hint, TransitMessage, TransitMsg};
+
+// the sender is the side that picks which connection to use
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match *self {
+            Role::Sender => "sender",
+            Role::Receiver => "receiver",
+        }
+    }
+
+    fn other(&self) -> Role {
+        match *self {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // their "transit" message didn't make sense
+    BadHints(String),
+    // no route worked before the timeout
+    ConnectionFailed,
+    // a record that didn't decrypt, or arrived out of order
+    BadRecord,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::BadHints(ref e) => write!(f, "bad transit hints: {}", e),
+            Error::ConnectionFailed => write!(f, "unable to connect"),
+            Error::BadRecord => write!(f, "corrupt or out-of-order record"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::BadHints(_) => "bad transit hints",
+            Error::ConnectionFailed => "unable to connect",
+            Error::BadRecord => "corrupt or out-of-order record",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+// what to pass to the wormhole's derive_key() to get the transit key
+pub fn transit_key_purpose(appid: &str) -> String {
+    format!("{}/transit-key", appid)
+}
+
+fn derive_key(key: &[u8], purpose: &[u8], length: usize) -> Vec<u8> {
+    let salt = vec![0; length];
+    let hk = Hkdf::<Sha256>::extract(&salt, key);
+    hk.expand(purpose, length)
+}
+
+// what each side says as soon as it's connected
+fn handshake(key: &[u8], role: Role) -> Vec<u8> {
+    let purpose = format!("transit_{}", role.name());
+    let token = derive_key(key, purpose.as_bytes(), 32);
+    format!("transit {} {} ready\n\n", role.name(), hex::encode(token))
+        .into_bytes()
+}
+
+// what we say to a relay server, before the handshake. It connects us to
+// whoever presents the same token from a different side.
+fn relay_handshake(key: &[u8], side: &str) -> Vec<u8> {
+    let token = derive_key(key, b"transit_relay_token", 32);
+    format!("please relay {} for side {}\n", hex::encode(token), side)
+        .into_bytes()
+}
+
+fn record_key(key: &[u8], role: Role) -> secretbox::Key {
+    let purpose = format!("transit_record_{}_key", role.name());
+    let k = derive_key(key, purpose.as_bytes(), secretbox::KEYBYTES);
+    secretbox::Key::from_slice(&k).unwrap()
+}
+
+// Like the Python client, we only mention 127.0.0.1 if there's nothing
+// else (so both sides can be on the same machine).
+fn local_addresses() -> Vec<IpAddr> {
+    let addrs: Vec<IpAddr> = match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .iter()
+            .map(|interface| interface.ip())
+            .filter(|ip| ip.is_ipv4())
+            .collect(),
+        Err(_) => vec![],
+    };
+    let others: Vec<IpAddr> = addrs
+        .iter()
+        .cloned()
+        .filter(|ip| !ip.is_loopback())
+        .collect();
+    if others.is_empty() {
+        vec!["127.0.0.1".parse().unwrap()]
+    } else {
+        others
+    }
+}
+
+pub struct Transit {
+    role: Role,
+    key: Vec<u8>,
+    // tells the relay server which end of the connection we are
+    side: String,
+    listener: TcpListener,
+    our_relays: Vec<DirectHint>,
+    their_direct: Vec<DirectHint>,
+    their_relays: Vec<DirectHint>,
+    relay_delay: Duration,
+}
+
+impl Transit {
+    // `key` comes from the wormhole (see transit_key_purpose()). We start
+    // listening (on every interface, on a random port) right away.
+    pub fn new(role: Role, key: &[u8]) -> io::Result<Transit> {
+        Ok(Transit {
+            role: role,
+            key: key.to_vec(),
+            side: hex::encode(randombytes::randombytes(8)),
+            listener: TcpListener::bind("0.0.0.0:0")?,
+            our_relays: Vec::new(),
+            their_direct: Vec::new(),
+            their_relays: Vec::new(),
+            relay_delay: Duration::from_secs(2),
+        })
+    }
+
+    // a relay server we can use, and will tell the other side about
+    pub fn add_relay(&mut self, hostname: &str, port: u16) {
+        self.our_relays.push(DirectHint {
+            hostname: hostname.to_string(),
+            port: port,
+            priority: 0.0,
+        });
+    }
+
+    // how long (in seconds) direct connections get before we try relays
+    pub fn set_relay_delay(&mut self, seconds: f32) {
+        self.relay_delay = Duration::from_millis((seconds * 1000.0) as u64);
+    }
+
+    // the "transit" message to send to the other side
+    pub fn our_hints(&self) -> Vec<u8> {
+        let port = self.listener.local_addr().unwrap().port();
+        let mut hints: Vec<Hint> = local_addresses()
+            .into_iter()
+            .map(|ip| {
+                Hint::DirectTcpV1(DirectHint {
+                    hostname: ip.to_string(),
+                    port: port,
+                    priority: 0.0,
+                })
+            })
+            .collect();
+        for relay in &self.our_relays {
+            hints.push(Hint::RelayV1 {
+                hints: vec![relay.clone()],
+            });
+        }
+        let m = TransitMessage {
+            transit: TransitMsg {
+                abilities: vec![Ability::DirectTcpV1, Ability::RelayV1],
+                hints: hints,
+            },
+        };
+        serde_json::to_vec(&m).unwrap()
+    }
+
+    // the "transit" message the other side sent us
+    pub fn add_their_hints(&mut self, message: &[u8]) -> Result<(), Error> {
+        let m: TransitMessage = serde_json::from_slice(message)
+            .map_err(|e| Error::BadHints(e.to_string()))?;
+        for hint in m.transit.hints {
+            match hint {
+                Hint::DirectTcpV1(hint) => self.their_direct.push(hint),
+                Hint::RelayV1 { hints } => self.their_relays.extend(hints),
+                Hint::Unknown => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Tries every route until one of them works, or the timeout passes.
+    // Both sides must call this at about the same time.
+    pub fn connect(self, timeout: Duration) -> Result<Connection, Error> {
+        let race = Arc::new(Race {
+            role: self.role,
+            key: self.key.clone(),
+            deadline: Instant::now() + timeout,
+            over: Mutex::new(false),
+        });
+        let (tx, rx) = mpsc::channel();
+
+        self.listener.set_nonblocking(true)?;
+        {
+            let (race, tx) = (Arc::clone(&race), tx.clone());
+            let listener = self.listener;
+            thread::spawn(move || Race::accept(&race, listener, tx));
+        }
+        for hint in self.their_direct {
+            let (race, tx) = (Arc::clone(&race), tx.clone());
+            thread::spawn(move || race.direct(hint, tx));
+        }
+        // we use our own relays as well as theirs
+        let mut relays = self.our_relays;
+        for relay in self.their_relays {
+            if !relays.contains(&relay) {
+                relays.push(relay);
+            }
+        }
+        for hint in relays {
+            let (race, tx) = (Arc::clone(&race), tx.clone());
+            let (side, delay) = (self.side.clone(), self.relay_delay);
+            thread::spawn(move || race.relay(hint, &side, delay, tx));
+        }
+
+        match rx.recv_timeout(timeout) {
+            Ok(stream) => Ok(Connection::new(stream, self.role, &self.key)),
+            Err(_) => {
+                *race.over.lock().unwrap() = true;
+                Err(Error::ConnectionFailed)
+            }
+        }
+    }
+}
+
+// everything the threads racing to connect need to know
+struct Race {
+    role: Role,
+    key: Vec<u8>,
+    deadline: Instant,
+    // set once a connection has won (or we've given up)
+    over: Mutex<bool>,
+}
+
+impl Race {
+    fn remaining(&self) -> Option<Duration> {
+        let now = Instant::now();
+        if now < self.deadline {
+            Some(self.deadline - now)
+        } else {
+            None
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        *self.over.lock().unwrap() || self.remaining().is_none()
+    }
+
+    fn accept(
+        race: &Arc<Race>,
+        listener: TcpListener,
+        tx: mpsc::Sender<TcpStream>,
+    ) {
+        while !race.is_over() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let (race, tx) = (Arc::clone(race), tx.clone());
+                    thread::spawn(move || {
+                        if stream.set_nonblocking(false).is_ok() {
+                            race.negotiate(stream, tx);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                // the outbound routes may still work, and if none do,
+                // connect() gives up with ConnectionFailed
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn direct(&self, hint: DirectHint, tx: mpsc::Sender<TcpStream>) {
+        if let Ok(stream) = self.open(&hint) {
+            self.negotiate(stream, tx);
+        }
+    }
+
+    fn relay(
+        &self,
+        hint: DirectHint,
+        side: &str,
+        delay: Duration,
+        tx: mpsc::Sender<TcpStream>,
+    ) {
+        thread::sleep(delay);
+        if self.is_over() {
+            return;
+        }
+        let stream = match self.open(&hint) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let relayed = (&stream)
+            .write_all(&relay_handshake(&self.key, side))
+            .and_then(|_| expect(&stream, b"ok\n"));
+        if let Ok(true) = relayed {
+            self.negotiate(stream, tx);
+        }
+    }
+
+    fn open(&self, hint: &DirectHint) -> io::Result<TcpStream> {
+        let addrs = (hint.hostname.as_str(), hint.port).to_socket_addrs()?;
+        let mut last = io::Error::new(io::ErrorKind::Other, "no addresses");
+        for addr in addrs {
+            let timeout = match self.remaining() {
+                Some(timeout) => timeout,
+                None => break,
+            };
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    // Both sides send their handshake. Then the sender says "go" on the
+    // first connection to get this far, and "nevermind" on the others.
+    fn negotiate(&self, stream: TcpStream, tx: mpsc::Sender<TcpStream>) {
+        match self.handshake(&stream) {
+            Ok(true) => {
+                if stream.set_read_timeout(None).is_ok() {
+                    // nobody's listening if we've given up
+                    let _ = tx.send(stream);
+                }
+            }
+            Ok(false) | Err(_) => {}
+        }
+    }
+
+    // true if this connection won
+    fn handshake(&self, mut stream: &TcpStream) -> io::Result<bool> {
+        if let Some(timeout) = self.remaining() {
+            stream.set_read_timeout(Some(timeout))?;
+        }
+        stream.write_all(&handshake(&self.key, self.role))?;
+        if !expect(stream, &handshake(&self.key, self.role.other()))? {
+            // someone else's connection (or a port scanner)
+            return Ok(false);
+        }
+        match self.role {
+            Role::Sender => {
+                let mut over = self.over.lock().unwrap();
+                if *over {
+                    stream.write_all(b"nevermind\n")?;
+                    return Ok(false);
+                }
+                stream.write_all(b"go\n")?;
+                *over = true;
+                Ok(true)
+            }
+            Role::Receiver => {
+                if !expect(stream, b"go\n")? {
+                    return Ok(false);
+                }
+                let mut over = self.over.lock().unwrap();
+                if *over {
+                    return Ok(false);
+                }
+                *over = true;
+                Ok(true)
+            }
+        }
+    }
+}
+
+// reads exactly as many bytes as `expected` has, and compares them
+fn expect(mut stream: &TcpStream, expected: &[u8]) -> io::Result<bool> {
+    let mut got = vec![0; expected.len()];
+    stream.read_exact(&mut got)?;
+    Ok(got == expected)
+}
+
+// The winning connection. Each record is sent as a 4-byte big-endian
+// length, then the nonce and the SecretBox. Nonces count up from zero, so
+// records can't be dropped, replayed or reordered without us noticing.
+pub struct Connection {
+    stream: TcpStream,
+    send_key: secretbox::Key,
+    receive_key: secretbox::Key,
+    next_send_nonce: u64,
+    next_receive_nonce: u64,
+}
+
+fn nonce(n: u64) -> secretbox::Nonce {
+    let mut bytes = [0u8; secretbox::NONCEBYTES];
+    for i in 0..8 {
+        bytes[secretbox::NONCEBYTES - 1 - i] = (n >> (8 * i)) as u8;
+    }
+    secretbox::Nonce::from_slice(&bytes).unwrap()
+}
+
+impl Connection {
+    fn new(stream: TcpStream, role: Role, key: &[u8]) -> Connection {
+        Connection {
+            stream: stream,
+            send_key: record_key(key, role),
+            receive_key: record_key(key, role.other()),
+            next_send_nonce: 0,
+            next_receive_nonce: 0,
+        }
+    }
+
+    pub fn send_record(&mut self, plaintext: &[u8]) -> Result<(), Error> {
+        let nonce = nonce(self.next_send_nonce);
+        self.next_send_nonce += 1;
+        let mut record = nonce.as_ref().to_vec();
+        record.extend(secretbox::seal(plaintext, &nonce, &self.send_key));
+        let length = record.len() as u32;
+        let header = [
+            (length >> 24) as u8,
+            (length >> 16) as u8,
+            (length >> 8) as u8,
+            length as u8,
+        ];
+        self.stream.write_all(&header)?;
+        self.stream.write_all(&record)?;
+        Ok(())
+    }
+
+    pub fn receive_record(&mut self) -> Result<Vec<u8>, Error> {
+        let mut header = [0u8; 4];
+        self.stream.read_exact(&mut header)?;
+        let length = header
+            .iter()
+            .fold(0usize, |length, &b| (length << 8) | b as usize);
+        if length < secretbox::NONCEBYTES {
+            return Err(Error::BadRecord);
+        }
+        let mut record = vec![0; length];
+        self.stream.read_exact(&mut record)?;
+        let (n, ciphertext) = record.split_at(secretbox::NONCEBYTES);
+        let expected = nonce(self.next_receive_nonce);
+        if n != expected.as_ref() {
+            return Err(Error::BadRecord);
+        }
+        self.next_receive_nonce += 1;
+        secretbox::open(ciphertext, &expected, &self.receive_key)
+            .map_err(|_| Error::BadRecord)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    // two sides that know where to find each other
+    fn pair(sender_key: &[u8], receiver_key: &[u8]) -> (Transit, Transit) {
+        let mut s = Transit::new(Role::Sender, sender_key).unwrap();
+        let mut r = Transit::new(Role::Receiver, receiver_key).unwrap();
+        let (s_hints, r_hints) = (s.our_hints(), r.our_hints());
+        s.add_their_hints(&r_hints).unwrap();
+        r.add_their_hints(&s_hints).unwrap();
+        (s, r)
+    }
+
+    #[test]
+    fn direct() {
+        let (s, r) = pair(&[7; 32], &[7; 32]);
+        let t = Duration::from_secs(10);
+        let receiving = thread::spawn(move || {
+            let mut c = r.connect(t).unwrap();
+            let got = c.receive_record().unwrap();
+            c.send_record(b"ok").unwrap();
+            got
+        });
+        let mut c = s.connect(t).unwrap();
+        c.send_record(b"hello").unwrap();
+        assert_eq!(c.receive_record().unwrap(), b"ok".to_vec());
+        assert_eq!(receiving.join().unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let (s, r) = pair(&[7; 32], &[8; 32]);
+        let t = Duration::from_millis(500);
+        let receiving = thread::spawn(move || r.connect(t));
+        match s.connect(t) {
+            Err(Error::ConnectionFailed) => {}
+            _ => panic!("connected with the wrong key"),
+        }
+        match receiving.join().unwrap() {
+            Err(Error::ConnectionFailed) => {}
+            _ => panic!("connected with the wrong key"),
+        }
+    }
+
+    #[test]
+    fn records() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+        let key = [7; 32];
+        let raw = a.try_clone().unwrap();
+        let mut sender = Connection::new(a, Role::Sender, &key);
+        let mut receiver = Connection::new(b, Role::Receiver, &key);
+        sender.send_record(b"one").unwrap();
+        sender.send_record(b"two").unwrap();
+        assert_eq!(receiver.receive_record().unwrap(), b"one".to_vec());
+        assert_eq!(receiver.receive_record().unwrap(), b"two".to_vec());
+
+        // a replay of the first record is refused
+        let mut replay = nonce(0).as_ref().to_vec();
+        replay.extend(secretbox::seal(
+            b"one",
+            &nonce(0),
+            &record_key(&key, Role::Sender),
+        ));
+        (&raw).write_all(&[0, 0, 0, replay.len() as u8]).unwrap();
+        (&raw).write_all(&replay).unwrap();
+        match receiver.receive_record() {
+            Err(Error::BadRecord) => {}
+            r => panic!("accepted a replay: {:?}", r),
+        }
+    }
+}